@@ -23,7 +23,7 @@
     defaultPackage.x86_64-linux = import ./hello.nix nixpkgs;
 
     deploy.nodes.example = {
-      sshOpts = [ "-p" "2221" ];
+      sshPort = 2221;
       hostname = "localhost";
       fastConnection = true;
       profiles = {
@@ -8,12 +8,16 @@ use signal_hook::{consts::signal::SIGHUP, iterator::Signals};
 use clap::Clap;
 
 use tokio::fs;
+use tokio::fs::os::unix::{DirBuilderExt, OpenOptionsExt};
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
 use std::time::Duration;
 
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
@@ -37,6 +41,18 @@ struct Opts {
     #[clap(long)]
     temp_path: String,
 
+    /// Octal umask applied to the temporary directory and files created in it
+    #[clap(long, parse(try_from_str = parse_umask))]
+    umask: Option<u32>,
+
+    /// Name of the confirmation canary file inside the temporary path
+    #[clap(long)]
+    lock_file_name: Option<String>,
+
+    /// Run the activation script in the current directory, instead of the profile path
+    #[clap(long)]
+    keep_working_dir: bool,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -45,6 +61,11 @@ struct Opts {
 enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
+    Rollback(RollbackOpts),
+}
+
+fn parse_umask(s: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s, 8)
 }
 
 /// Activate a profile
@@ -66,6 +87,61 @@ struct ActivateOpts {
     /// Auto rollback if failure
     #[clap(long)]
     auto_rollback: bool,
+
+    /// Shell command taking a snapshot of the node's state before activating
+    #[clap(long)]
+    pre_activate_snapshot: Option<String>,
+    /// Shell command restoring that snapshot, run last when rolling back
+    #[clap(long)]
+    rollback_to_snapshot: Option<String>,
+    /// Shell command discarding that snapshot, once activation succeeded (and was confirmed)
+    #[clap(long)]
+    release_snapshot: Option<String>,
+
+    /// Shell command checking the activation, which confirms it once it succeeds (with magic rollback)
+    #[clap(long)]
+    self_confirm_command: Option<String>,
+}
+
+/// Commands for snapshotting state which Nix generations don't cover, like a ZFS dataset
+#[derive(Debug, Default)]
+pub struct SnapshotCommands {
+    pub create: Option<String>,
+    pub rollback: Option<String>,
+    pub release: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Failed to execute the command to {0} the snapshot: {1}")]
+    Command(&'static str, std::io::Error),
+    #[error("The command to {0} the snapshot resulted in a bad exit code: {1:?}")]
+    CommandExit(&'static str, Option<i32>),
+}
+
+async fn run_snapshot_command(action: &'static str, command: &str) -> Result<(), SnapshotError> {
+    info!("Running command to {} the snapshot: {}", action, command);
+
+    let exit_status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+        .map_err(|err| SnapshotError::Command(action, err))?;
+
+    match exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(SnapshotError::CommandExit(action, a)),
+    }
+}
+
+/// Discarding the snapshot is only cleanup, so failing to do so is not an activation failure
+async fn release_snapshot(snapshot: &SnapshotCommands) {
+    if let Some(release) = &snapshot.release {
+        if let Err(err) = run_snapshot_command("release", release).await {
+            warn!("{}", err);
+        }
+    }
 }
 
 /// Activate a profile
@@ -75,6 +151,13 @@ struct WaitOpts {
     closure: String,
 }
 
+/// Roll a profile back to its previous generation
+#[derive(Clap, Debug)]
+struct RollbackOpts {
+    /// The profile path to roll back
+    profile_path: String,
+}
+
 #[derive(Error, Debug)]
 pub enum DeactivateError {
     #[error("Failed to execute the rollback command: {0}")]
@@ -95,14 +178,32 @@ pub enum DeactivateError {
     ReactivateError(std::io::Error),
     #[error("Command for re-activating the last generation resulted in a bad exit code: {0:?}")]
     ReactivateExitError(Option<i32>),
+    #[error("Failed to roll back to the snapshot: {0}")]
+    SnapshotRollbackError(#[from] SnapshotError),
 }
 
-pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
+/// Runs the activation script of the profile, from the profile path unless `keep_working_dir` is set
+fn activate_script_command(profile_path: &str, keep_working_dir: bool) -> Command {
+    let mut command = Command::new(format!("{}/deploy-rs-activate", profile_path));
+    command.env("PROFILE", profile_path);
+
+    if !keep_working_dir {
+        command.current_dir(profile_path);
+    }
+
+    command
+}
+
+pub async fn deactivate(
+    profile_path: &str,
+    keep_working_dir: bool,
+    snapshot_rollback: Option<&str>,
+) -> Result<(), DeactivateError> {
     warn!("De-activating due to error");
 
     let nix_env_rollback_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--rollback")
         .status()
         .await
@@ -117,7 +218,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     let nix_env_list_generations_out = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--list-generations")
         .output()
         .await
@@ -145,7 +246,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     let nix_env_delete_generation_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--delete-generations")
         .arg(last_generation_id)
         .status()
@@ -159,9 +260,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     info!("Attempting to re-activate the last generation");
 
-    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
-        .env("PROFILE", &profile_path)
-        .current_dir(&profile_path)
+    let re_activate_exit_status = activate_script_command(profile_path, keep_working_dir)
         .status()
         .await
         .map_err(DeactivateError::ReactivateError)?;
@@ -171,6 +270,11 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         a => return Err(DeactivateError::ReactivateExitError(a)),
     };
 
+    // Last, as the snapshot may also contain the profile, which was just rolled back to the same generation
+    if let Some(snapshot_rollback) = snapshot_rollback {
+        run_snapshot_command("roll back to", snapshot_rollback).await?;
+    }
+
     Ok(())
 }
 
@@ -180,6 +284,10 @@ pub enum ActivationConfirmationError {
     CreateConfirmDirError(std::io::Error),
     #[error("Failed to create activation confirmation file: {0}")]
     CreateConfirmFileError(std::io::Error),
+    #[error("Failed to set permissions of activation confirmation directory: {0}")]
+    SetConfirmDirPermissionsError(std::io::Error),
+    #[error("Failed to set permissions of activation confirmation file: {0}")]
+    SetConfirmFilePermissionsError(std::io::Error),
     #[error("Failed to create file system watcher instance: {0}")]
     CreateWatcherError(notify::Error),
     #[error("Error forking process: {0}")]
@@ -188,10 +296,54 @@ pub enum ActivationConfirmationError {
     WatcherError(#[from] notify::Error),
 }
 
+/// What the watcher saw happen to the canary file
+#[derive(Debug)]
+pub enum CanaryEvent {
+    /// Created while waiting, or removed while confirming
+    Done,
+    /// Written to by `deploy --cancel`, asking for an immediate rollback
+    Cancelled,
+}
+
+/// How long to wait before running the self confirm command again after it failed
+const SELF_CONFIRM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs `command` until it succeeds, then confirms the activation by removing the canary file
+async fn self_confirm(command: &str, lock_path: &str) {
+    info!("Running self confirm command: {}", command);
+
+    loop {
+        match Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .kill_on_drop(true)
+            .status()
+            .await
+        {
+            Ok(status) if status.success() => break,
+            Ok(status) => debug!(
+                "Self confirm command resulted in a bad exit code: {:?}",
+                status.code()
+            ),
+            Err(err) => warn!("Failed to execute the self confirm command: {}", err),
+        }
+
+        tokio::time::sleep(SELF_CONFIRM_INTERVAL).await;
+    }
+
+    info!("Self confirm command succeeded, confirming");
+
+    if let Err(err) = fs::remove_file(lock_path).await {
+        error!("Failed to remove the canary file to confirm: {}", err);
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum DangerZoneError {
     #[error("Timeout elapsed for confirmation")]
     TimesUp,
+    #[error("Activation was cancelled")]
+    Cancelled,
     #[error("inotify stream ended without activation confirmation")]
     NoConfirmation,
     #[error("inotify encountered an error: {0}")]
@@ -199,41 +351,96 @@ pub enum DangerZoneError {
 }
 
 async fn danger_zone(
-    mut events: mpsc::Receiver<Result<(), notify::Error>>,
+    mut events: mpsc::Receiver<Result<CanaryEvent, notify::Error>>,
     confirm_timeout: u16,
 ) -> Result<(), DangerZoneError> {
     info!("Waiting for confirmation event...");
 
     match timeout(Duration::from_secs(confirm_timeout as u64), events.recv()).await {
-        Ok(Some(Ok(()))) => Ok(()),
+        Ok(Some(Ok(CanaryEvent::Done))) => Ok(()),
+        Ok(Some(Ok(CanaryEvent::Cancelled))) => Err(DangerZoneError::Cancelled),
         Ok(Some(Err(e))) => Err(DangerZoneError::WatchError(e)),
         Ok(None) => Err(DangerZoneError::NoConfirmation),
         Err(_) => Err(DangerZoneError::TimesUp),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activation_confirmation(
     profile_path: String,
     temp_path: String,
     confirm_timeout: u16,
     closure: String,
+    umask: Option<u32>,
+    lock_file_name: Option<String>,
+    keep_working_dir: bool,
+    snapshot: &SnapshotCommands,
+    self_confirm_command: Option<&str>,
 ) -> Result<(), ActivationConfirmationError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, lock_file_name.as_deref());
 
     debug!("Ensuring parent directory exists for canary file");
 
     if let Some(parent) = Path::new(&lock_path).parent() {
-        fs::create_dir_all(parent)
+        // Only touch the permissions of a directory we created ourselves, pre-existing ones
+        // (such as `/tmp`) are left alone
+        let parent_existed = fs::metadata(parent).await.is_ok();
+
+        // Created with the mode so that it is never more open than that, the mode is still set
+        // afterwards as the umask of the process may have narrowed it
+        let mut dir_builder = fs::DirBuilder::new();
+        dir_builder.recursive(true);
+        if let Some(umask) = umask {
+            dir_builder.mode(0o777 & !umask);
+        }
+
+        dir_builder
+            .create(parent)
             .await
             .map_err(ActivationConfirmationError::CreateConfirmDirError)?;
+
+        if let (Some(umask), false) = (umask, parent_existed) {
+            debug!("Setting canary directory mode to {:o}", 0o777 & !umask);
+
+            fs::set_permissions(parent, Permissions::from_mode(0o777 & !umask))
+                .await
+                .map_err(ActivationConfirmationError::SetConfirmDirPermissionsError)?;
+        }
     }
 
     debug!("Creating canary file");
 
-    fs::File::create(&lock_path)
+    // Like the directory, the file is created with the mode, which is set again afterwards. The
+    // closure lets confirmation check that it confirms this activation, and not another one.
+    let mut open_options = fs::OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    if let Some(umask) = umask {
+        open_options.mode(0o666 & !umask);
+    }
+
+    let mut lock_file = open_options
+        .open(&lock_path)
+        .await
+        .map_err(ActivationConfirmationError::CreateConfirmFileError)?;
+
+    // Flushed so that the write is done before watching starts, when it would count as cancelling
+    lock_file
+        .write_all(closure.as_bytes())
+        .await
+        .map_err(ActivationConfirmationError::CreateConfirmFileError)?;
+    lock_file
+        .flush()
         .await
         .map_err(ActivationConfirmationError::CreateConfirmFileError)?;
 
+    if let Some(umask) = umask {
+        debug!("Setting canary file mode to {:o}", 0o666 & !umask);
+
+        fs::set_permissions(&lock_path, Permissions::from_mode(0o666 & !umask))
+            .await
+            .map_err(ActivationConfirmationError::SetConfirmFilePermissionsError)?;
+    }
+
     debug!("Creating notify watcher");
 
     let (deleted, done) = mpsc::channel(1);
@@ -243,13 +450,20 @@ pub async fn activation_confirmation(
             let send_result = match res {
                 Ok(e) if e.kind == notify::EventKind::Remove(notify::event::RemoveKind::File) => {
                     debug!("Got worthy removal event, sending on channel");
-                    deleted.try_send(Ok(()))
+                    deleted.try_send(Ok(CanaryEvent::Done))
+                }
+                Ok(notify::event::Event {
+                    kind: notify::EventKind::Modify(notify::event::ModifyKind::Data(_)),
+                    ..
+                }) => {
+                    debug!("Got write to canary file, sending cancellation on channel");
+                    deleted.try_send(Ok(CanaryEvent::Cancelled))
                 }
                 Err(e) => {
                     debug!("Got error waiting for removal event, sending on channel");
                     deleted.try_send(Err(e))
                 }
-                Ok(_) => Ok(()), // ignore non-removal events
+                Ok(_) => Ok(()), // ignore other events
             };
 
             if let Err(e) = send_result {
@@ -259,14 +473,35 @@ pub async fn activation_confirmation(
 
     watcher.watch(&lock_path, RecursiveMode::NonRecursive)?;
 
-    if let Err(err) = danger_zone(done, confirm_timeout).await {
-        error!("Error waiting for confirmation event: {}", err);
+    let confirmation = danger_zone(done, confirm_timeout);
+    tokio::pin!(confirmation);
 
-        if let Err(err) = deactivate(&profile_path).await {
-            error!(
-                "Error de-activating due to another error waiting for confirmation, oh no...: {}",
-                err
-            );
+    let confirmed = match self_confirm_command {
+        Some(command) => tokio::select! {
+            x = &mut confirmation => x,
+            // Removing the canary file ends waiting for confirmation as usual
+            _ = self_confirm(command, &lock_path) => confirmation.await,
+        },
+        None => confirmation.await,
+    };
+
+    match confirmed {
+        Ok(()) => release_snapshot(snapshot).await,
+        Err(err) => {
+            error!("Error waiting for confirmation event: {}", err);
+
+            if let Err(err) = deactivate(
+                &profile_path,
+                keep_working_dir,
+                snapshot.rollback.as_deref(),
+            )
+            .await
+            {
+                error!(
+                    "Error de-activating due to another error waiting for confirmation, oh no...: {}",
+                    err
+                );
+            }
         }
     }
 
@@ -280,8 +515,12 @@ pub enum WaitError {
     #[error("Error waiting for activation: {0}")]
     Waiting(#[from] DangerZoneError),
 }
-pub async fn wait(temp_path: String, closure: String) -> Result<(), WaitError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+pub async fn wait(
+    temp_path: String,
+    closure: String,
+    lock_file_name: Option<String>,
+) -> Result<(), WaitError> {
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, lock_file_name.as_deref());
 
     let (created, done) = mpsc::channel(1);
 
@@ -293,7 +532,9 @@ pub async fn wait(temp_path: String, closure: String) -> Result<(), WaitError> {
             let send_result = match res {
                 Ok(e) if e.kind == notify::EventKind::Create(notify::event::CreateKind::File) => {
                     match &e.paths[..] {
-                        [x] if x == Path::new(&lock_path) => created.try_send(Ok(())),
+                        [x] if x == Path::new(&lock_path) => {
+                            created.try_send(Ok(CanaryEvent::Done))
+                        }
                         _ => Ok(()),
                     }
                 }
@@ -339,8 +580,12 @@ pub enum ActivateError {
 
     #[error("Failed to get activation confirmation: {0}")]
     ActivationConfirmationError(#[from] ActivationConfirmationError),
+
+    #[error("Failed to take a snapshot before activating: {0}")]
+    SnapshotError(#[from] SnapshotError),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
@@ -348,7 +593,16 @@ pub async fn activate(
     temp_path: String,
     confirm_timeout: u16,
     magic_rollback: bool,
+    umask: Option<u32>,
+    lock_file_name: Option<String>,
+    keep_working_dir: bool,
+    snapshot: SnapshotCommands,
+    self_confirm_command: Option<String>,
 ) -> Result<(), ActivateError> {
+    if let Some(create) = &snapshot.create {
+        run_snapshot_command("take", create).await?;
+    }
+
     info!("Activating profile");
 
     let nix_env_set_exit_status = Command::new("nix-env")
@@ -364,7 +618,12 @@ pub async fn activate(
         Some(0) => (),
         a => {
             if auto_rollback {
-                deactivate(&profile_path).await?;
+                deactivate(
+                    &profile_path,
+                    keep_working_dir,
+                    snapshot.rollback.as_deref(),
+                )
+                .await?;
             }
             return Err(ActivateError::SetProfileExitError(a));
         }
@@ -372,9 +631,7 @@ pub async fn activate(
 
     debug!("Running activation script");
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", profile_path))
-        .env("PROFILE", &profile_path)
-        .current_dir(&profile_path)
+    let activate_status = match activate_script_command(&profile_path, keep_working_dir)
         .status()
         .await
         .map_err(ActivateError::RunActivateError)
@@ -382,7 +639,12 @@ pub async fn activate(
         Ok(x) => x,
         Err(e) => {
             if auto_rollback {
-                deactivate(&profile_path).await?;
+                deactivate(
+                    &profile_path,
+                    keep_working_dir,
+                    snapshot.rollback.as_deref(),
+                )
+                .await?;
             }
             return Err(e);
         }
@@ -392,7 +654,12 @@ pub async fn activate(
         Some(0) => (),
         a => {
             if auto_rollback {
-                deactivate(&profile_path).await?;
+                deactivate(
+                    &profile_path,
+                    keep_working_dir,
+                    snapshot.rollback.as_deref(),
+                )
+                .await?;
             }
             return Err(ActivateError::RunActivateExitError(a));
         }
@@ -403,15 +670,32 @@ pub async fn activate(
     if magic_rollback {
         info!("Magic rollback is enabled, setting up confirmation hook...");
 
-        match activation_confirmation(profile_path.clone(), temp_path, confirm_timeout, closure)
-            .await
+        match activation_confirmation(
+            profile_path.clone(),
+            temp_path,
+            confirm_timeout,
+            closure,
+            umask,
+            lock_file_name,
+            keep_working_dir,
+            &snapshot,
+            self_confirm_command.as_deref(),
+        )
+        .await
         {
             Ok(()) => {}
             Err(err) => {
-                deactivate(&profile_path).await?;
+                deactivate(
+                    &profile_path,
+                    keep_working_dir,
+                    snapshot.rollback.as_deref(),
+                )
+                .await?;
                 return Err(ActivateError::ActivationConfirmationError(err));
             }
         };
+    } else {
+        release_snapshot(&snapshot).await;
     }
 
     Ok(())
@@ -420,7 +704,7 @@ pub async fn activate(
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Ensure that this process stays alive after the SSH connection dies
-    let mut signals = Signals::new(&[SIGHUP])?;
+    let mut signals = Signals::new([SIGHUP])?;
     std::thread::spawn(move || {
         for _ in signals.forever() {
             println!("Received NOHUP - ignoring...");
@@ -435,6 +719,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         match opts.subcmd {
             SubCommand::Activate(_) => deploy::LoggerType::Activate,
             SubCommand::Wait(_) => deploy::LoggerType::Wait,
+            SubCommand::Rollback(_) => deploy::LoggerType::Activate,
         },
     )?;
 
@@ -446,13 +731,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             opts.temp_path,
             activate_opts.confirm_timeout,
             activate_opts.magic_rollback,
+            opts.umask,
+            opts.lock_file_name,
+            opts.keep_working_dir,
+            SnapshotCommands {
+                create: activate_opts.pre_activate_snapshot,
+                rollback: activate_opts.rollback_to_snapshot,
+                release: activate_opts.release_snapshot,
+            },
+            activate_opts.self_confirm_command,
         )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Wait(wait_opts) => wait(opts.temp_path, wait_opts.closure)
+        SubCommand::Wait(wait_opts) => wait(opts.temp_path, wait_opts.closure, opts.lock_file_name)
             .await
             .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Rollback(rollback_opts) => {
+            deactivate(&rollback_opts.profile_path, opts.keep_working_dir, None)
+                .await
+                .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
     };
 
     match r {
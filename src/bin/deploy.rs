@@ -8,13 +8,13 @@ use std::io::{stdin, stdout, Write};
 use clap::Clap;
 
 use log::{debug, error, info, warn};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::process::Stdio;
 use thiserror::Error;
 use tokio::process::Command;
 
 /// Simple Rust rewrite of a simple Nix Flake deployment tool
-#[derive(Clap, Debug)]
+#[derive(Clap, Serialize, Deserialize, Debug, Clone)]
 #[clap(version = "1.0", author = "Serokell <https://serokell.io/>")]
 struct Opts {
     /// The flake to deploy
@@ -26,15 +26,32 @@ struct Opts {
     /// Use the interactive prompt before deployment
     #[clap(short, long)]
     interactive: bool,
+    /// Deploy without asking, skipping the interactive prompt and `requireConfirmation`
+    #[clap(short, long)]
+    yes: bool,
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
 
     /// Print debug logs to output
     #[clap(short, long)]
     debug_logs: bool,
+    /// Narrate every decision made while deploying, such as why and how long it waits for confirmation
+    #[clap(long)]
+    explain: bool,
     /// Directory to print logs to (including the background activation process)
     #[clap(long)]
     log_dir: Option<String>,
+    /// Also print lifecycle events of deploying each profile to standard output, `text` (the default) or `json`
+    #[clap(long, default_value = "text")]
+    log_format: deploy::LogFormat,
+    /// Don't color the nodes apart in the log, even on a terminal
+    #[clap(long)]
+    no_color: bool,
+
+    /// Deploy the store path read from this file (or standard input, given `-`) instead of building the profile, which has to be the only one selected
+    #[clap(long)]
+    #[serde(skip)]
+    closure_from: Option<deploy::ClosureSource>,
 
     /// Keep the build outputs of each built profile
     #[clap(short, long)]
@@ -47,15 +64,79 @@ struct Opts {
     #[clap(short, long)]
     skip_checks: bool,
 
+    /// Refuse to deploy unless the git working tree in the current directory is clean
+    #[clap(long)]
+    require_clean_git: bool,
+    /// Refuse to deploy unless the current git branch is the given one (implies --require-clean-git)
+    #[clap(long)]
+    require_git_branch: Option<String>,
+    /// Deploy anyway if the git checks fail, for emergencies
+    #[clap(long)]
+    allow_dirty: bool,
+
+    /// Make the selected profiles, which are waiting for confirmation from another deployment, roll back now
+    #[clap(long, requires = "run-id")]
+    cancel: bool,
+
+    /// Connect to every node and run read-only checks, without copying or activating anything
+    #[clap(long)]
+    dry_connect: bool,
+    /// Print the SSH commands that would deploy the selected profiles, without copying or running anything
+    #[clap(long)]
+    dry_run: bool,
+    /// Print a hash of the selected nodes, profiles and closures to deploy, then exit without deploying
+    #[clap(long)]
+    plan_hash: bool,
+    /// Print every selected profile as JSON after merging its settings (hostname, users, paths, rollback and so on), then exit without deploying
+    #[clap(long)]
+    print_deployment: bool,
+    /// How many nodes to deploy at the same time at most, defaults to the number of nodes up to 10
+    #[clap(long)]
+    max_parallel: Option<usize>,
+    /// How many profiles of a node to deploy at the same time at most, only waiting for those they depend on with `dependsOn`, instead of one after another
+    #[clap(long)]
+    max_parallel_profiles: Option<usize>,
+    /// Keep deploying the nodes which don't depend on one which failed, instead of stopping after the first failure
+    #[clap(long)]
+    keep_going: bool,
+    /// Check that every node can be reached over SSH before deploying to any of them
+    #[clap(long)]
+    check_connectivity: bool,
+
+    /// Exit with a distinct code (2) when a profile was rolled back, even cleanly
+    #[clap(long, conflicts_with = "soft-rollback")]
+    fail_if_rolled_back: bool,
+    /// Only warn when a profile was rolled back cleanly, instead of failing
+    #[clap(long)]
+    soft_rollback: bool,
+
+    /// Identifies this run, to keep its lock files apart from those of concurrent runs (defaults to a unique one)
+    #[clap(long)]
+    #[serde(skip)]
+    run_id: Option<String>,
+
+    /// Re-run the last invocation (as stored in `.deploy-last.toml`), ignoring all other arguments
+    #[clap(long)]
+    #[serde(skip)]
+    repeat_last: bool,
+
+    /// OTLP/HTTP endpoint to send OpenTelemetry traces of the deployment to
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    #[serde(skip)]
+    otlp_endpoint: Option<String>,
+
     /// Override the SSH user with the given value
     #[clap(long)]
     ssh_user: Option<String>,
     /// Override the profile user with the given value
     #[clap(long)]
     profile_user: Option<String>,
-    /// Override the SSH options used
+    /// Override the SSH options used, split like a shell would (so quoted options may contain spaces)
     #[clap(long)]
     ssh_opts: Option<String>,
+    /// Override the SSH binary used for running commands on the node
+    #[clap(long)]
+    ssh_command: Option<String>,
     /// Override if the connecting to the target node should be considered fast
     #[clap(long)]
     fast_connection: Option<bool>,
@@ -65,6 +146,9 @@ struct Opts {
     /// Override hostname used for the node
     #[clap(long)]
     hostname: Option<String>,
+    /// Override the SSH port of the node
+    #[clap(long)]
+    ssh_port: Option<u16>,
     /// Make activation wait for confirmation, or roll back after a period of time
     #[clap(long)]
     magic_rollback: Option<bool>,
@@ -74,6 +158,55 @@ struct Opts {
     /// Where to store temporary files (only used by magic-rollback)
     #[clap(long)]
     temp_path: Option<String>,
+    /// Also deploy nodes which are disabled with `enabled = false`
+    #[clap(long)]
+    include_disabled: bool,
+    /// Fail instead of only warning when no profiles are selected to deploy, or a `--set` selector matches none of them
+    #[clap(long)]
+    error_on_no_targets: bool,
+    /// Deploy the given variant of every profile which has variants, instead of its `path`
+    #[clap(long)]
+    attr: Option<String>,
+    /// Override a setting for matching targets only, e.g. `--set node:web01 auto_rollback=false`
+    /// (selectors are `node:<name>` and `profile:<name>`, can be given multiple times)
+    #[clap(long, number_of_values = 2, value_names = &["SELECTOR", "SETTING=VALUE"])]
+    #[serde(default)]
+    set: Vec<String>,
+}
+
+/// Exit code with `--fail-if-rolled-back` when a profile was rolled back
+const EXIT_ROLLED_BACK: i32 = 2;
+
+/// Where the options of the last invocation are stored, for `--repeat-last`
+const LAST_DEPLOY_PATH: &str = "./.deploy-last.toml";
+
+#[derive(Error, Debug)]
+enum LastDeployError {
+    #[error("Failed to read the last deployment from {}: {0}", LAST_DEPLOY_PATH)]
+    Read(std::io::Error),
+    #[error("Failed to parse the last deployment: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Failed to make printable TOML of the last deployment: {0}")]
+    TomlFormat(#[from] toml::ser::Error),
+    #[error("Failed to write the last deployment to {}: {0}", LAST_DEPLOY_PATH)]
+    Write(std::io::Error),
+}
+
+fn read_last_deploy() -> Result<Opts, LastDeployError> {
+    let last = std::fs::read_to_string(LAST_DEPLOY_PATH).map_err(LastDeployError::Read)?;
+
+    Ok(toml::from_str(&last)?)
+}
+
+fn save_last_deploy(opts: &Opts) -> Result<(), LastDeployError> {
+    let toml = format!(
+        "# The last deploy-rs invocation, this can be edited and re-run with `deploy --repeat-last`\n{}",
+        toml::to_string(opts)?
+    );
+
+    std::fs::write(LAST_DEPLOY_PATH, toml).map_err(LastDeployError::Write)?;
+
+    Ok(())
 }
 
 /// Returns if the available Nix installation supports flakes
@@ -92,6 +225,58 @@ async fn test_flake_support() -> Result<bool, std::io::Error> {
         .success())
 }
 
+#[derive(Error, Debug)]
+enum GitCheckError {
+    #[error("Failed to run git: {0}")]
+    Git(std::io::Error),
+    #[error("`git {0}` resulted in a bad exit code: {1:?}")]
+    GitExit(&'static str, Option<i32>),
+    #[error("Error converting git output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+    #[error(
+        "The git working tree has uncommitted changes (use --allow-dirty to deploy anyway):\n{0}"
+    )]
+    Dirty(String),
+    #[error("Deploying from git branch `{0}`, but `{1}` is required (use --allow-dirty to deploy anyway)")]
+    WrongBranch(String, String),
+}
+
+async fn run_git(args: &[&str], name: &'static str) -> Result<String, GitCheckError> {
+    let output = Command::new("git")
+        .args(args)
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(GitCheckError::Git)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(GitCheckError::GitExit(name, a)),
+    };
+
+    Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+}
+
+/// Checks that the working tree is clean (and on `branch`), returning the checked out commit
+async fn check_git(branch: Option<&str>) -> Result<String, GitCheckError> {
+    debug!("Checking the git working tree");
+
+    // Flakes only see tracked files, so untracked ones (like `.deploy-last.toml`) don't matter
+    let status = run_git(&["status", "--porcelain", "--untracked-files=no"], "status").await?;
+    if !status.is_empty() {
+        return Err(GitCheckError::Dirty(status));
+    }
+
+    if let Some(branch) = branch {
+        let current = run_git(&["rev-parse", "--abbrev-ref", "HEAD"], "rev-parse").await?;
+        if current != branch {
+            return Err(GitCheckError::WrongBranch(current, branch.to_string()));
+        }
+    }
+
+    run_git(&["rev-parse", "HEAD"], "rev-parse").await
+}
+
 #[derive(Error, Debug)]
 enum CheckDeploymentError {
     #[error("Failed to execute Nix checking command: {0}")]
@@ -104,6 +289,7 @@ async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
     extra_build_args: &[String],
+    log_format: deploy::LogFormat,
 ) -> Result<(), CheckDeploymentError> {
     info!("Running checks for flake in {}", repo);
 
@@ -127,7 +313,10 @@ async fn check_deployment(
         check_command.arg(extra_arg);
     }
 
-    let check_status = check_command.status().await?;
+    let check_status = check_command
+        .stdout(log_format.child_stdout())
+        .status()
+        .await?;
 
     match check_status.code() {
         Some(0) => (),
@@ -263,7 +452,7 @@ fn print_deployment(
     for (data, defs) in parts {
         part_map
             .entry(data.node_name.to_string())
-            .or_insert_with(HashMap::new)
+            .or_default()
             .insert(
                 data.profile_name.to_string(),
                 PromptPart {
@@ -360,6 +549,34 @@ enum RunDeployError {
     TomlFormat(#[from] toml::ser::Error),
     #[error("{0}")]
     PromptDeployment(#[from] PromptDeploymentError),
+    #[error("Invalid node dependencies: {0}")]
+    NodeDependencies(#[from] deploy::graph::DependencyError),
+    #[error("{0}")]
+    NodeProfiles(#[from] deploy::NodeProfilesError),
+    #[error("{0}")]
+    DeployFleet(#[from] deploy::deploy::DeployFleetError),
+    #[error("Failed to cancel activation: {0}")]
+    CancelProfile(#[from] deploy::deploy::CancelProfileError),
+    #[error("Preflight check failed: {0}")]
+    Preflight(#[from] deploy::preflight::PreflightError),
+    #[error("{0}")]
+    IncoherentRollback(#[from] deploy::IncoherentRollbackError),
+    #[error("Failed to make printable JSON of deployment: {0}")]
+    JsonFormat(#[from] serde_json::Error),
+    #[error("Nothing to deploy: {0} was selected, but {1}")]
+    NoTargets(String, &'static str),
+    #[error("`--set {0}` matches none of the selected profiles")]
+    UnmatchedOverride(String),
+}
+
+impl RunDeployError {
+    fn rolled_back(&self) -> bool {
+        match self {
+            RunDeployError::DeployProfile(err) => err.rolled_back(),
+            RunDeployError::DeployFleet(err) => err.rolled_back(),
+            _ => false,
+        }
+    }
 }
 
 type ToDeploy<'a> = Vec<(
@@ -367,6 +584,7 @@ type ToDeploy<'a> = Vec<(
     (&'a str, &'a deploy::data::Profile),
 )>;
 
+#[allow(clippy::too_many_arguments)]
 async fn run_deploy(
     deploy_flake: deploy::DeployFlake<'_>,
     data: deploy::data::Data,
@@ -378,7 +596,13 @@ async fn run_deploy(
     result_path: Option<&str>,
     extra_build_args: &[String],
     debug_logs: bool,
+    explain: bool,
     log_dir: Option<String>,
+    dry_connect: bool,
+    plan_hash: bool,
+    cancel: bool,
+    include_disabled: bool,
+    error_on_no_targets: bool,
 ) -> Result<(), RunDeployError> {
     let to_deploy: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
         (Some(node_name), Some(profile_name)) => {
@@ -391,6 +615,9 @@ async fn run_deploy(
                 None => return Err(RunDeployError::ProfileNotFound(profile_name.to_owned())),
             };
 
+            // Only the selected profile is deployed, but its node's dependencies still have to be valid
+            deploy::node_profiles(node_name, node)?;
+
             vec![((node_name, node), (profile_name, profile))]
         }
         (Some(node_name), None) => {
@@ -399,25 +626,7 @@ async fn run_deploy(
                 None => return Err(RunDeployError::NodeNotFound(node_name.to_owned())),
             };
 
-            let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-            for profile_name in [
-                node.node_settings.profiles_order.iter().collect(),
-                node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-            ]
-            .concat()
-            {
-                let profile = match node.node_settings.profiles.get(profile_name) {
-                    Some(x) => x,
-                    None => return Err(RunDeployError::ProfileNotFound(profile_name.to_owned())),
-                };
-
-                if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                    profiles_list.push((&profile_name, profile));
-                }
-            }
-
-            profiles_list
+            deploy::node_profiles(node_name, node)?
                 .into_iter()
                 .map(|x| ((node_name.as_str(), node), x))
                 .collect()
@@ -426,27 +635,7 @@ async fn run_deploy(
             let mut l = Vec::new();
 
             for (node_name, node) in &data.nodes {
-                let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                for profile_name in [
-                    node.node_settings.profiles_order.iter().collect(),
-                    node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                ]
-                .concat()
-                {
-                    let profile = match node.node_settings.profiles.get(profile_name) {
-                        Some(x) => x,
-                        None => {
-                            return Err(RunDeployError::ProfileNotFound(profile_name.to_owned()))
-                        }
-                    };
-
-                    if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                        profiles_list.push((&profile_name, profile));
-                    }
-                }
-
-                let ll: ToDeploy = profiles_list
+                let ll: ToDeploy = deploy::node_profiles(node_name, node)?
                     .into_iter()
                     .map(|x| ((node_name.as_str(), node), x))
                     .collect();
@@ -459,6 +648,67 @@ async fn run_deploy(
         (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
     };
 
+    let mut disabled: Vec<&str> = Vec::new();
+    let to_deploy: ToDeploy = to_deploy
+        .into_iter()
+        .filter(|((node_name, node), _)| {
+            if include_disabled || node.node_settings.enabled != Some(false) {
+                return true;
+            }
+
+            if !disabled.contains(node_name) {
+                disabled.push(node_name);
+            }
+
+            false
+        })
+        .collect();
+
+    for node_name in &disabled {
+        warn!(
+            "Skipping node `{}`, which is disabled (use --include-disabled to deploy it anyway)",
+            node_name
+        );
+    }
+
+    if to_deploy.is_empty() {
+        let selection = match (&deploy_flake.node, &deploy_flake.profile) {
+            (Some(node), Some(profile)) => format!("profile `{}` of node `{}`", profile, node),
+            (Some(node), None) => format!("node `{}`", node),
+            _ => format!("every node of `{}`", deploy_flake.repo),
+        };
+        let reason = match disabled.is_empty() {
+            true => "there are no profiles",
+            false => "all of them are disabled",
+        };
+
+        if error_on_no_targets {
+            return Err(RunDeployError::NoTargets(selection, reason));
+        }
+
+        warn!(
+            "Nothing to deploy: {} was selected, but {}",
+            selection, reason
+        );
+    }
+
+    let targets: Vec<(&str, &str)> = to_deploy
+        .iter()
+        .map(|((node_name, _), (profile_name, _))| (*node_name, *profile_name))
+        .collect();
+    for targeted in deploy::unmatched_overrides(&cmd_overrides.targeted, &targets) {
+        if error_on_no_targets {
+            return Err(RunDeployError::UnmatchedOverride(
+                targeted.selector.to_string(),
+            ));
+        }
+
+        warn!(
+            "`--set {}` matches none of the selected profiles, so it is ignored",
+            targeted.selector
+        );
+    }
+
     let mut parts: Vec<(deploy::DeployData, deploy::DeployDefs)> = Vec::new();
 
     for ((node_name, node), (profile_name, profile)) in to_deploy {
@@ -470,6 +720,7 @@ async fn run_deploy(
             profile_name,
             &cmd_overrides,
             debug_logs,
+            explain,
             log_dir.as_deref(),
         );
 
@@ -478,19 +729,96 @@ async fn run_deploy(
         parts.push((deploy_data, deploy_defs));
     }
 
-    if interactive {
+    let mut warned_ssh_opts = std::collections::HashSet::new();
+    for (deploy_data, _) in &parts {
+        for (opt, setting) in
+            deploy::deploy::find_managed_ssh_opts(&deploy_data.configured_ssh_opts)
+        {
+            if warned_ssh_opts.insert((deploy_data.node_name, opt.clone())) {
+                warn!(
+                    "`sshOpts` of node `{}` contain `{}`, which is managed by the `{}` setting, consider using that instead",
+                    deploy_data.node_name, opt, setting
+                );
+            }
+        }
+    }
+
+    if plan_hash {
+        let targets: Vec<_> = parts
+            .iter()
+            .map(|(data, _)| {
+                (
+                    data.node_name,
+                    data.profile_name,
+                    data.profile.profile_settings.path.as_str(),
+                )
+            })
+            .collect();
+
+        // Printed on its own, for scripts comparing it with the hash of the last applied plan
+        println!("{}", deploy::make_plan_hash(&targets));
+
+        return Ok(());
+    }
+
+    if cmd_overrides.print_plan {
+        let plans = parts
+            .iter()
+            .map(|(data, defs)| data.plan(defs))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        println!("{}", serde_json::to_string_pretty(&plans)?);
+
+        return Ok(());
+    }
+
+    if interactive && !cmd_overrides.yes {
         prompt_deployment(&parts[..])?;
     } else {
         print_deployment(&parts[..])?;
     }
 
+    if cmd_overrides.dry_run {
+        for (deploy_data, deploy_defs) in &parts {
+            deploy::deploy::deploy_profile(
+                deploy_data,
+                deploy_defs,
+                &deploy::runner::SshRunner,
+                &Default::default(),
+            )
+            .await?;
+        }
+
+        return Ok(());
+    }
+
+    // Held until the end of the run, so that its SSH connections are closed even on errors
+    let ssh_masters = deploy::deploy::SshMaster::for_profiles(parts.iter().map(|(a, b)| (a, b)));
+    futures_util::future::join_all(ssh_masters.iter().map(|x| x.open())).await;
+
+    if cancel {
+        for (deploy_data, deploy_defs) in &parts {
+            deploy::deploy::cancel_profile(deploy_data, deploy_defs).await?;
+        }
+
+        return Ok(());
+    }
+
+    if dry_connect {
+        for (deploy_data, deploy_defs) in &parts {
+            deploy::preflight::preflight_profile(deploy_data, deploy_defs).await?;
+        }
+
+        return Ok(());
+    }
+
     for (deploy_data, deploy_defs) in &parts {
         deploy::push::push_profile(deploy::push::PushProfileData {
             supports_flakes,
             check_sigs,
             repo: deploy_flake.repo,
-            deploy_data: &deploy_data,
-            deploy_defs: &deploy_defs,
+            deploy_data,
+            deploy_defs,
             keep_result,
             result_path,
             extra_build_args,
@@ -498,13 +826,57 @@ async fn run_deploy(
         .await?;
     }
 
-    for (deploy_data, deploy_defs) in &parts {
-        deploy::deploy::deploy_profile(&deploy_data, &deploy_defs).await?;
+    // Only a full evaluation contains every node, otherwise dependencies outside of it are ignored
+    if deploy_flake.node.is_none() {
+        deploy::graph::check_dependencies(
+            &data
+                .nodes
+                .iter()
+                .map(|(name, node)| (name.as_str(), &node.node_settings.depends_on[..]))
+                .collect(),
+        )?;
     }
 
+    let targets: Vec<_> = parts.iter().map(|(a, b)| (a, b)).collect();
+
+    let cancel = deploy::deploy::CancellationToken::new();
+    cancel_on_ctrl_c(&cancel);
+
+    deploy::deploy::deploy_fleet(
+        &targets,
+        &deploy::runner::SshRunner,
+        &deploy::deploy::FleetOptions {
+            max_parallel: cmd_overrides.max_parallel,
+            max_parallel_profiles: cmd_overrides.max_parallel_profiles,
+            keep_going: cmd_overrides.keep_going,
+            check_connectivity: cmd_overrides.check_connectivity,
+        },
+        &cancel,
+    )
+    .await?;
+
     Ok(())
 }
 
+/// Cancels `cancel` on the first Ctrl-C, which leaves the activations that are not confirmed yet to
+/// roll back, and exits on the second one
+fn cancel_on_ctrl_c(cancel: &deploy::deploy::CancellationToken) {
+    let cancel = cancel.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+
+        warn!("Interrupted, stopping the deployments which are not confirmed yet, press Ctrl-C again to exit");
+        cancel.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
 #[derive(Error, Debug)]
 enum RunError {
     #[error("Failed to deploy profile: {0}")]
@@ -523,10 +895,46 @@ enum RunError {
     Logger(#[from] flexi_logger::FlexiLoggerError),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
+    #[error("{0}")]
+    LastDeploy(#[from] LastDeployError),
+    #[error("{0}")]
+    GitCheck(#[from] GitCheckError),
+    #[error("{0}")]
+    SelectVariant(#[from] deploy::SelectVariantError),
+    #[error("{0}")]
+    ReadClosure(#[from] deploy::ReadClosureError),
+    #[error("{0}")]
+    ResolveHostname(#[from] deploy::ResolveHostnameError),
+    #[error("{0}")]
+    RunId(#[from] deploy::RunIdError),
+    #[error("Invalid `--ssh-opts`: {0}")]
+    SplitSshOpts(#[from] deploy::SplitSshOptsError),
+    #[error("Invalid `--set` override: {0}")]
+    ParseOverride(#[from] deploy::ParseOverrideError),
+    #[error("{0}")]
+    RolledBack(RunDeployError),
 }
 
 async fn run() -> Result<(), RunError> {
-    let opts: Opts = Opts::parse();
+    let mut opts: Opts = Opts::parse();
+
+    let repeat_last = opts.repeat_last;
+
+    // The last invocation is read before starting the logger, so that its logging options apply
+    let mut last_deploy_err = None;
+
+    if repeat_last {
+        match read_last_deploy() {
+            // Where traces go is up to the current environment, not the last one
+            Ok(last_opts) => {
+                opts = Opts {
+                    otlp_endpoint: opts.otlp_endpoint.take(),
+                    ..last_opts
+                }
+            }
+            Err(err) => last_deploy_err = Some(err),
+        }
+    }
 
     deploy::init_logger(
         opts.debug_logs,
@@ -534,18 +942,80 @@ async fn run() -> Result<(), RunError> {
         deploy::LoggerType::Deploy,
     )?;
 
+    if let Some(err) = last_deploy_err {
+        return Err(err.into());
+    }
+
+    deploy::telemetry::init(opts.otlp_endpoint.as_deref());
+
+    if repeat_last {
+        info!("Repeating the last deployment of {}", opts.flake);
+    }
+
+    // Only invocations which deploy are stored, not those which only print, check or cancel
+    let last_deploy = if repeat_last
+        || opts.cancel
+        || opts.dry_connect
+        || opts.dry_run
+        || opts.plan_hash
+        || opts.print_deployment
+    {
+        None
+    } else {
+        Some(opts.clone())
+    };
+
     let deploy_flake = deploy::parse_flake(opts.flake.as_str())?;
 
+    if opts.require_clean_git || opts.require_git_branch.is_some() {
+        match check_git(opts.require_git_branch.as_deref()).await {
+            Ok(commit) => info!("Deploying from clean git commit {}", commit),
+            Err(err) if opts.allow_dirty => warn!("Deploying anyway: {}", err),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let targeted = opts
+        .set
+        .chunks(2)
+        .map(|x| deploy::TargetedOverride::parse(&x[0], &x[1]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let run_id = match opts.run_id.take() {
+        Some(x) => x,
+        None => deploy::make_run_id(),
+    };
+    deploy::check_run_id(&run_id)?;
+    info!("Deploying as run `{}`", run_id);
+
     let cmd_overrides = deploy::CmdOverrides {
         ssh_user: opts.ssh_user,
         profile_user: opts.profile_user,
-        ssh_opts: opts.ssh_opts,
+        ssh_opts: opts
+            .ssh_opts
+            .as_deref()
+            .map(deploy::split_ssh_opts)
+            .transpose()?,
+        ssh_command: opts.ssh_command,
         fast_connection: opts.fast_connection,
         auto_rollback: opts.auto_rollback,
         hostname: opts.hostname,
+        ssh_port: opts.ssh_port,
         magic_rollback: opts.magic_rollback,
         temp_path: opts.temp_path,
         confirm_timeout: opts.confirm_timeout,
+        targeted,
+        run_id: Some(run_id),
+        dry_run: opts.dry_run,
+        log_format: opts.log_format,
+        yes: opts.yes,
+        no_color: opts.no_color,
+        closure_from: opts.closure_from.clone(),
+        print_plan: opts.print_deployment,
+        max_parallel: opts.max_parallel,
+        max_parallel_profiles: opts.max_parallel_profiles,
+        keep_going: opts.keep_going,
+        check_connectivity: opts.check_connectivity,
     };
 
     let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
@@ -555,14 +1025,29 @@ async fn run() -> Result<(), RunError> {
     }
 
     if !opts.skip_checks {
-        check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args).await?;
+        check_deployment(
+            supports_flakes,
+            deploy_flake.repo,
+            &opts.extra_build_args,
+            cmd_overrides.log_format,
+        )
+        .await?;
     }
 
-    let data = get_deployment_data(supports_flakes, &deploy_flake, &opts.extra_build_args).await?;
+    let mut data =
+        get_deployment_data(supports_flakes, &deploy_flake, &opts.extra_build_args).await?;
+
+    deploy::resolve_hostnames(&mut data)?;
+    deploy::select_variants(&mut data, opts.attr.as_deref())?;
+
+    if let Some(ref source) = cmd_overrides.closure_from {
+        let closure = deploy::read_closure(source, std::io::stdin().lock())?;
+        deploy::override_closure(&mut data, &deploy_flake, closure)?;
+    }
 
     let result_path = opts.result_path.as_deref();
 
-    run_deploy(
+    let result = run_deploy(
         deploy_flake,
         data,
         supports_flakes,
@@ -573,9 +1058,48 @@ async fn run() -> Result<(), RunError> {
         result_path,
         &opts.extra_build_args,
         opts.debug_logs,
+        opts.explain,
         opts.log_dir,
+        opts.dry_connect,
+        opts.plan_hash,
+        opts.cancel,
+        opts.include_disabled,
+        opts.error_on_no_targets,
     )
-    .await?;
+    .await;
+
+    // Every shared connection was closed at the end of the run
+    deploy::remove_ssh_control_dir();
+
+    if let Some(last_deploy) = last_deploy {
+        if let Err(err) = save_last_deploy(&last_deploy) {
+            warn!(
+                "Could not store this deployment for `--repeat-last`: {}",
+                err
+            );
+        }
+    }
+
+    let (outcome, status) = match result {
+        Ok(()) => ("success", deploy::telemetry::SpanStatus::Ok),
+        Err(ref err) if err.rolled_back() => ("rolled_back", deploy::telemetry::SpanStatus::Error),
+        Err(_) => ("failure", deploy::telemetry::SpanStatus::Error),
+    };
+
+    if let Err(err) = deploy::telemetry::finish(outcome, status).await {
+        warn!("Failed to export OpenTelemetry traces: {}", err);
+    }
+
+    match result {
+        Err(err) if err.rolled_back() && opts.fail_if_rolled_back => {
+            return Err(RunError::RolledBack(err))
+        }
+        Err(err) if err.rolled_back() && opts.soft_rollback => {
+            warn!("{}", err);
+            warn!("The deployment was rolled back, which is not treated as a failure because of --soft-rollback");
+        }
+        x => x?,
+    }
 
     Ok(())
 }
@@ -584,6 +1108,11 @@ async fn run() -> Result<(), RunError> {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match run().await {
         Ok(()) => (),
+        Err(RunError::RolledBack(err)) => {
+            error!("{}", err);
+            error!("The deployment was rolled back, failing because of --fail-if-rolled-back");
+            std::process::exit(EXIT_ROLLED_BACK);
+        }
         Err(err) => {
             error!("{}", err);
             std::process::exit(1);
@@ -0,0 +1,238 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Evaluation of `confirmChecks`, the checks which have to pass before an activation is confirmed.
+
+use crate::data::ConfirmCheck;
+use crate::runner::{CommandRunner, RunOptions};
+use futures_util::future::join_all;
+use log::debug;
+use std::future::Future;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+/// How long a single HTTP check may take
+const HTTP_CHECK_TIMEOUT_SECS: u16 = 10;
+
+/// How long to wait between rounds of checks, with a minimum healthy duration
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks for how long checks have passed continuously
+pub struct HealthyWindow {
+    min: Duration,
+    since: Option<Instant>,
+}
+
+impl HealthyWindow {
+    pub fn new(min: Duration) -> HealthyWindow {
+        HealthyWindow { min, since: None }
+    }
+
+    /// Records the result of a round of checks finished at `now`, returning if the checks
+    /// have now passed for long enough. A failure starts the window over.
+    pub fn record(&mut self, passed: bool, now: Instant) -> bool {
+        if !passed {
+            self.since = None;
+            return false;
+        }
+
+        let since = *self.since.get_or_insert(now);
+
+        now.saturating_duration_since(since) >= self.min
+    }
+
+    /// If the last round of checks passed
+    pub fn healthy(&self) -> bool {
+        self.since.is_some()
+    }
+}
+
+#[test]
+fn test_healthy_window() {
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    let mut window = HealthyWindow::new(Duration::from_secs(0));
+    assert!(window.record(true, at(0)));
+
+    let mut window = HealthyWindow::new(Duration::from_secs(15));
+    assert!(!window.record(true, at(0)));
+    assert!(!window.record(true, at(10)));
+    // Flapping starts the window over
+    assert!(!window.record(false, at(11)));
+    assert!(!window.healthy());
+    assert!(!window.record(true, at(12)));
+    assert!(!window.record(true, at(26)));
+    assert!(window.record(true, at(27)));
+}
+
+/// The checks which actually run something, in the order `combine` expects their results
+fn leaves(check: &ConfirmCheck) -> Vec<&ConfirmCheck> {
+    match check {
+        ConfirmCheck::AllOf(checks) | ConfirmCheck::AnyOf(checks) => {
+            checks.iter().flat_map(leaves).collect()
+        }
+        x => vec![x],
+    }
+}
+
+/// The commands of the `command` checks in `check`, which run on the node
+pub fn check_commands(check: &ConfirmCheck) -> Vec<&str> {
+    leaves(check)
+        .into_iter()
+        .filter_map(|x| match x {
+            ConfirmCheck::Command(command) => Some(command.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Combines the results of the leaves of `check`, returning every failure which made it fail
+fn combine(
+    check: &ConfirmCheck,
+    results: &mut impl Iterator<Item = Result<(), String>>,
+) -> Result<(), Vec<String>> {
+    match check {
+        ConfirmCheck::AllOf(checks) => {
+            let failures: Vec<String> = checks
+                .iter()
+                .filter_map(|x| combine(x, results).err())
+                .flatten()
+                .collect();
+
+            match failures.is_empty() {
+                true => Ok(()),
+                false => Err(failures),
+            }
+        }
+        ConfirmCheck::AnyOf(checks) => {
+            // Every check is combined, so that the results of the following ones are consumed
+            let combined: Vec<_> = checks.iter().map(|x| combine(x, results)).collect();
+
+            if checks.is_empty() {
+                return Err(vec!["`anyOf` without any checks".to_string()]);
+            }
+
+            match combined.iter().any(|x| x.is_ok()) {
+                true => Ok(()),
+                false => Err(combined
+                    .into_iter()
+                    .filter_map(|x| x.err())
+                    .flatten()
+                    .collect()),
+            }
+        }
+        _ => match results.next() {
+            Some(Ok(())) => Ok(()),
+            Some(Err(x)) => Err(vec![x]),
+            None => Err(vec!["check was not run".to_string()]),
+        },
+    }
+}
+
+#[test]
+fn test_combine() {
+    let http = |x: &str| ConfirmCheck::Http(x.to_string());
+    let command = |x: &str| ConfirmCheck::Command(x.to_string());
+
+    let check = ConfirmCheck::AllOf(vec![
+        command("true"),
+        ConfirmCheck::AnyOf(vec![http("http://a"), http("http://b")]),
+    ]);
+
+    assert_eq!(leaves(&check).len(), 3);
+    assert_eq!(check_commands(&check), vec!["true"]);
+
+    let run = |results: Vec<Result<(), String>>| combine(&check, &mut results.into_iter());
+
+    assert_eq!(run(vec![Ok(()), Err("a".to_string()), Ok(())]), Ok(()));
+    assert_eq!(
+        run(vec![Err("true".to_string()), Ok(()), Ok(())]),
+        Err(vec!["true".to_string()])
+    );
+    assert_eq!(
+        run(vec![Ok(()), Err("a".to_string()), Err("b".to_string())]),
+        Err(vec!["a".to_string(), "b".to_string()])
+    );
+    assert!(combine(&ConfirmCheck::AnyOf(vec![]), &mut std::iter::empty()).is_err());
+}
+
+/// Runs a check which `status` carries out, described for its failures as `description`
+async fn run_leaf(
+    description: String,
+    status: impl Future<Output = std::io::Result<ExitStatus>>,
+) -> Result<(), String> {
+    debug!("Running confirm check {}", description);
+
+    match status.await {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} (exit code {:?})", description, status.code())),
+        Err(err) => Err(format!("{} ({})", description, err)),
+    }
+}
+
+/// Runs every check concurrently, returning the descriptions of the ones which made `check` fail.
+/// HTTP checks are made from the deploying machine, commands run on the node through `runner`
+/// with `node_argv`, like every other command on the node.
+pub async fn run_confirm_checks(
+    check: &ConfirmCheck,
+    runner: &dyn CommandRunner,
+    node_argv: &[String],
+    options: &RunOptions,
+) -> Result<(), Vec<String>> {
+    let leaves = leaves(check);
+
+    let argvs: Vec<Vec<String>> = leaves
+        .iter()
+        .map(|x| match x {
+            ConfirmCheck::Command(command) => [node_argv, std::slice::from_ref(command)].concat(),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    let results = join_all(
+        leaves
+            .into_iter()
+            .zip(&argvs)
+            .map(|(leaf, argv)| async move {
+                match leaf {
+                    ConfirmCheck::Http(url) => {
+                        let mut curl = Command::new("curl");
+                        curl.arg("--silent")
+                            .arg("--fail")
+                            .arg("--max-time")
+                            .arg(HTTP_CHECK_TIMEOUT_SECS.to_string())
+                            .arg("--output")
+                            .arg("/dev/null")
+                            .arg(url)
+                            .stdin(Stdio::null())
+                            .stdout(Stdio::null())
+                            .kill_on_drop(true);
+
+                        run_leaf(format!("http `{}`", url), curl.status()).await
+                    }
+                    ConfirmCheck::Command(command) => {
+                        // Standard output is captured to be dropped, as only the exit code matters
+                        let output = runner.output(
+                            argv,
+                            RunOptions {
+                                kill_on_drop: true,
+                                ..options.clone()
+                            },
+                        );
+
+                        run_leaf(format!("command `{}`", command), async {
+                            output.await.map(|x| x.status)
+                        })
+                        .await
+                    }
+                    ConfirmCheck::AllOf(_) | ConfirmCheck::AnyOf(_) => unreachable!(),
+                }
+            }),
+    )
+    .await;
+
+    combine(check, &mut results.into_iter())
+}
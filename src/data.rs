@@ -3,14 +3,18 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use merge::Merge;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Clone, Merge)]
+#[derive(Deserialize, Serialize, Debug, Clone, Merge, Default, PartialEq)]
 pub struct GenericSettings {
     #[serde(rename(deserialize = "sshUser"))]
     pub ssh_user: Option<String>,
     pub user: Option<String>,
+    #[serde(rename(deserialize = "privilegeEscalationCommand"))]
+    pub privilege_escalation_command: Option<String>,
+    #[serde(rename(deserialize = "sudoPassword"))]
+    pub sudo_password: Option<SecretSource>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
         default,
@@ -18,6 +22,12 @@ pub struct GenericSettings {
     )]
     #[merge(strategy = merge::vec::append)]
     pub ssh_opts: Vec<String>,
+    #[serde(rename(deserialize = "sshCommand"))]
+    pub ssh_command: Option<String>,
+    #[serde(rename(deserialize = "sshAgentSock"))]
+    pub ssh_agent_sock: Option<String>,
+    #[serde(rename(deserialize = "identityFile"))]
+    pub identity_file: Option<String>,
     #[serde(rename(deserialize = "fastConnection"))]
     pub fast_connection: Option<bool>,
     #[serde(rename(deserialize = "autoRollback"))]
@@ -28,11 +38,153 @@ pub struct GenericSettings {
     pub temp_path: Option<String>,
     #[serde(rename(deserialize = "magicRollback"))]
     pub magic_rollback: Option<bool>,
+    pub umask: Option<String>,
+    #[serde(rename(deserialize = "lockFileName"))]
+    pub lock_file_name: Option<String>,
+    #[serde(rename(deserialize = "clearStaleLock"))]
+    pub clear_stale_lock: Option<bool>,
+    #[serde(rename(deserialize = "verifyClosureOnRemote"))]
+    pub verify_closure_on_remote: Option<bool>,
+    #[serde(rename(deserialize = "autoConfirmTimeout"))]
+    pub auto_confirm_timeout: Option<bool>,
+    #[serde(rename(deserialize = "activationTimeout"))]
+    pub activation_timeout: Option<u16>,
+    #[serde(rename(deserialize = "waitTimeout"))]
+    pub wait_timeout: Option<u16>,
+    #[serde(rename(deserialize = "streamLogs"))]
+    pub stream_logs: Option<bool>,
+    #[serde(rename(deserialize = "copyRetries"))]
+    pub copy_retries: Option<u8>,
+    #[serde(rename(deserialize = "copyOpts"))]
+    pub copy_opts: Option<Vec<String>>,
+    #[serde(rename(deserialize = "sshConnectRetries"))]
+    pub ssh_connect_retries: Option<u8>,
+    #[serde(rename(deserialize = "confirmRetries"))]
+    pub confirm_retries: Option<u8>,
+    #[serde(rename(deserialize = "workingDir"))]
+    pub working_dir: Option<String>,
+    #[serde(rename(deserialize = "workingDirAfterSudo"))]
+    pub working_dir_after_sudo: Option<bool>,
+    #[serde(rename(deserialize = "activationEnv"))]
+    pub activation_env: Option<HashMap<String, String>>,
+    #[serde(rename(deserialize = "preActivateSnapshot"))]
+    pub pre_activate_snapshot: Option<String>,
+    #[serde(rename(deserialize = "rollbackToSnapshot"))]
+    pub rollback_to_snapshot: Option<String>,
+    #[serde(rename(deserialize = "releaseSnapshot"))]
+    pub release_snapshot: Option<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "verifyUnits")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub verify_units: Vec<String>,
+    #[serde(rename(deserialize = "verifyUnitsRestarted"))]
+    pub verify_units_restarted: Option<bool>,
+    #[serde(rename(deserialize = "healthCheckCommand"))]
+    pub health_check_command: Option<String>,
+    #[serde(rename(deserialize = "healthCheckTimeout"))]
+    pub health_check_timeout: Option<u16>,
+    #[serde(rename(deserialize = "socksProxy"))]
+    pub socks_proxy: Option<String>,
+    #[serde(rename(deserialize = "sshMultiplexing"))]
+    pub ssh_multiplexing: Option<bool>,
+    pub local: Option<bool>,
+    #[serde(rename(deserialize = "onConfirmCommand"))]
+    pub on_confirm_command: Option<String>,
+    #[serde(rename(deserialize = "preDeployHook"))]
+    pub pre_deploy_hook: Option<String>,
+    #[serde(rename(deserialize = "postDeployHook"))]
+    pub post_deploy_hook: Option<String>,
+    #[serde(rename(deserialize = "fetchLogsTo"))]
+    pub fetch_logs_to: Option<std::path::PathBuf>,
+    #[serde(rename(deserialize = "confirmChecks"))]
+    pub confirm_checks: Option<ConfirmCheck>,
+    #[serde(rename(deserialize = "minHealthyDuration"))]
+    pub min_healthy_duration: Option<u16>,
+    pub stabilization: Option<Stabilization>,
+    #[serde(rename(deserialize = "selfConfirmCommand"))]
+    pub self_confirm_command: Option<String>,
+    #[serde(rename(deserialize = "externalConfirm"))]
+    pub external_confirm: Option<bool>,
+    #[serde(rename(deserialize = "confirmCommand"))]
+    pub confirm_command: Option<String>,
+    #[serde(rename(deserialize = "currentClosureCommand"))]
+    pub current_closure_command: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// A check which has to pass before an activation is confirmed, or a combination of them
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfirmCheck {
+    /// A request from the deploying machine to the URL has to succeed
+    Http(String),
+    /// The command has to exit successfully on the node
+    Command(String),
+    AllOf(Vec<ConfirmCheck>),
+    AnyOf(Vec<ConfirmCheck>),
+}
+
+/// Where a secret like `sudoPassword` is read from on the deploying machine
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SecretSource {
+    /// The name of an environment variable
+    Env(String),
+    /// A command run locally, whose output without the final newline is the secret
+    Command(String),
+}
+
+/// A command which has to keep succeeding on the node for a while before an activation is confirmed
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Stabilization {
+    pub command: String,
+    /// Seconds between runs of `command`
+    pub interval: u16,
+    /// Seconds `command` has to keep succeeding for
+    pub window: u16,
+}
+
+/// How SSH treats host keys of the node it doesn't know, its `StrictHostKeyChecking`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrictHostKey {
+    /// Unknown host keys are refused
+    Yes,
+    No,
+    /// Unknown host keys are added to `known_hosts`, but changed ones are still refused
+    AcceptNew,
+}
+
+/// A file copied from the deploying machine to the node before activating
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FileUpload {
+    pub local: std::path::PathBuf,
+    pub remote: String,
+    /// Octal permissions of the file on the node, like `0600`
+    pub mode: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct NodeSettings {
+    /// Empty when the node uses `hostnameTemplate` instead, until it is resolved
+    #[serde(default)]
     pub hostname: String,
+    #[serde(rename(deserialize = "hostnameTemplate"))]
+    pub hostname_template: Option<String>,
+    #[serde(default, rename(deserialize = "templateVars"))]
+    pub template_vars: HashMap<String, String>,
+    #[serde(rename(deserialize = "sshPort"))]
+    pub ssh_port: Option<u16>,
+    #[serde(rename(deserialize = "sshJumpHost"))]
+    pub ssh_jump_host: Option<String>,
+    #[serde(rename(deserialize = "strictHostKeyChecking"))]
+    pub strict_host_key_checking: Option<StrictHostKey>,
+    #[serde(rename(deserialize = "requireConfirmation"))]
+    pub require_confirmation: Option<bool>,
+    #[serde(rename(deserialize = "preActivationFiles"))]
+    pub pre_activation_files: Option<Vec<FileUpload>>,
     pub profiles: HashMap<String, Profile>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
@@ -40,13 +192,39 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "dependsOn")
+    )]
+    pub depends_on: Vec<String>,
+    #[serde(rename(deserialize = "confirmGroup"))]
+    pub confirm_group: Option<String>,
+    #[serde(rename(deserialize = "activationQuorum"))]
+    pub activation_quorum: Option<usize>,
+    pub attribute: Option<String>,
+    pub enabled: Option<bool>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    /// Who activates the profile instead of `user`, which it is still installed for
+    #[serde(rename(deserialize = "activateUser"))]
+    pub activate_user: Option<String>,
+    /// Profiles of the same node which are deployed before this one
+    #[serde(rename(deserialize = "dependsOn"))]
+    pub depends_on: Option<Vec<String>>,
+    #[serde(default)]
+    pub variants: HashMap<String, String>,
+    /// Which of `variants` replaced `path`, chosen after evaluation
+    #[serde(skip)]
+    pub variant: Option<String>,
+    /// If `path` was given (`--closure-from`) instead of evaluated, so it is not built
+    #[serde(skip)]
+    pub prebuilt: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
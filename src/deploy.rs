@@ -3,337 +3,6603 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use futures_util::future::join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::{debug, error, info, warn};
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::runner::{CommandRunner, RunOptions, SshRunner};
+use crate::telemetry::{Span, SpanStatus};
+use crate::RollbackStrategy;
 use thiserror::Error;
 use tokio::process::Command;
 
 struct ActivateCommandData<'a> {
-    sudo: &'a Option<String>,
     profile_path: &'a str,
     closure: &'a str,
-    auto_rollback: bool,
+    rollback: RollbackStrategy,
     temp_path: &'a str,
     confirm_timeout: u16,
-    magic_rollback: bool,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    umask: Option<&'a str>,
+    lock_file_name: Option<&'a str>,
+    working_dir: Option<&'a str>,
+    snapshot: Option<&'a SnapshotCommands>,
+    self_confirm_command: Option<&'a str>,
+    activation_env: Option<&'a HashMap<String, String>>,
+}
+
+/// Snapshot command templates of a deployment, with `{snapshot}` replaced by its name
+struct SnapshotCommands {
+    create: String,
+    rollback: Option<String>,
+    release: Option<String>,
+}
+
+fn make_snapshot_commands(
+    settings: &crate::data::GenericSettings,
+    name: &str,
+) -> Option<SnapshotCommands> {
+    let fill = |template: &String| template.replace("{snapshot}", name);
+
+    Some(SnapshotCommands {
+        create: fill(settings.pre_activate_snapshot.as_ref()?),
+        rollback: settings.rollback_to_snapshot.as_ref().map(fill),
+        release: settings.release_snapshot.as_ref().map(fill),
+    })
+}
+
+/// The `{snapshot}` of a deployment, which tells snapshots of different nodes, profiles and runs
+/// apart. Characters other than letters, digits, `.`, `_` and `-` become `-`, as snapshot names
+/// (like those of ZFS) are restricted.
+fn make_snapshot_name(node_name: &str, profile_name: &str, run_id: &str) -> String {
+    format!("deploy-rs-{}-{}-{}", node_name, profile_name, run_id)
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// The `{snapshot}` of deploying `deploy_data`
+fn deploy_snapshot_name(deploy_data: &super::DeployData<'_>) -> String {
+    let run_id = match &deploy_data.cmd_overrides.run_id {
+        Some(x) => x.clone(),
+        None => super::make_run_id(),
+    };
+
+    make_snapshot_name(deploy_data.node_name, deploy_data.profile_name, &run_id)
+}
+
+#[test]
+fn test_make_snapshot_name() {
+    assert_eq!(
+        make_snapshot_name("web01", "system", "1700000000-42"),
+        "deploy-rs-web01-system-1700000000-42"
+    );
+    assert_eq!(
+        make_snapshot_name("web 01", "my@app", "ci"),
+        "deploy-rs-web-01-my-app-ci"
+    );
+}
+
+#[test]
+fn test_make_snapshot_commands() {
+    let mut settings = crate::data::GenericSettings::default();
+    assert!(make_snapshot_commands(&settings, "deploy-rs-1").is_none());
+
+    settings.pre_activate_snapshot = Some("zfs snapshot rpool/var@{snapshot}".to_string());
+    settings.rollback_to_snapshot = Some("zfs rollback -r rpool/var@{snapshot}".to_string());
+
+    let commands = make_snapshot_commands(&settings, "deploy-rs-1").unwrap();
+    assert_eq!(commands.create, "zfs snapshot rpool/var@deploy-rs-1");
+    assert_eq!(
+        commands.rollback.as_deref(),
+        Some("zfs rollback -r rpool/var@deploy-rs-1")
+    );
+    assert_eq!(commands.release, None);
+}
+
+/// Quotes `s` for a POSIX shell
+pub(crate) fn shell_escape(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[test]
+fn test_shell_escape() {
+    assert_eq!(shell_escape("/srv/app"), "'/srv/app'");
+    assert_eq!(shell_escape("it's"), "'it'\\''s'");
+    assert_eq!(shell_escape("my dir"), "'my dir'");
+    assert_eq!(shell_escape("$(reboot)"), "'$(reboot)'");
+    assert_eq!(shell_escape(""), "''");
 }
 
-fn build_activate_command(data: ActivateCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+/// ANSI colors which nodes are told apart by in the log
+const NODE_COLORS: &[u8] = &[31, 32, 33, 34, 35, 36];
+
+fn node_color(node_name: &str) -> u8 {
+    // FNV-1a, unlike the hasher of std it stays the same between Rust versions
+    let hash = node_name.bytes().fold(0x811c_9dc5_u32, |hash, x| {
+        (hash ^ x as u32).wrapping_mul(0x0100_0193)
+    });
+
+    NODE_COLORS[hash as usize % NODE_COLORS.len()]
+}
+
+fn make_log_prefix(node_name: &str, profile_name: &str, color: bool) -> String {
+    let prefix = format!("[{}/{}]", node_name, profile_name);
+
+    match color {
+        true => format!("\x1b[{}m{}\x1b[0m", node_color(node_name), prefix),
+        false => prefix,
+    }
+}
+
+/// Tells the log lines of a profile apart from those of others deployed at the same time, colored
+/// by node when the log goes to a terminal
+fn log_prefix(deploy_data: &super::DeployData<'_>) -> String {
+    use std::io::IsTerminal;
+
+    // The log file would get the colors as well
+    let color = !deploy_data.cmd_overrides.no_color
+        && deploy_data.log_dir.is_none()
+        && std::io::stderr().is_terminal();
+
+    make_log_prefix(deploy_data.node_name, deploy_data.profile_name, color)
+}
+
+#[test]
+fn test_log_prefix() {
+    assert_eq!(make_log_prefix("web-1", "system", false), "[web-1/system]");
+    assert_eq!(
+        make_log_prefix("web-1", "system", true),
+        format!("\x1b[{}m[web-1/system]\x1b[0m", node_color("web-1"))
+    );
+    // Profiles of a node share its color
+    assert_eq!(
+        make_log_prefix("web-1", "app", true),
+        format!("\x1b[{}m[web-1/app]\x1b[0m", node_color("web-1"))
+    );
+    assert_ne!(node_color("web-1"), node_color("web-2"));
+
+    let node = crate::mock_node(serde_json::json!({}));
+
+    let cmd_overrides = crate::CmdOverrides {
+        no_color: true,
+        ..Default::default()
+    };
+
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+
+    assert_eq!(log_prefix(&deploy_data), "[example/system]");
+}
+
+/// Logs like the macros of `log`, prefixed by the node and profile of `deploy_data`
+macro_rules! node_log {
+    ($level:ident, $deploy_data:expr, $($arg:tt)+) => {
+        log::$level!("{} {}", log_prefix($deploy_data), format_args!($($arg)+))
+    };
+}
+
+/// The command line of `activate-rs` activating the profile, which `remote_command` turns into
+/// the command SSH runs on the node
+fn build_activate_command(data: ActivateCommandData) -> Vec<String> {
+    let mut argv = Vec::new();
+
+    // Set by `env` rather than by sudo, which may refuse to or reset the environment
+    if let Some(activation_env) = data.activation_env {
+        let mut vars: Vec<String> = activation_env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        vars.sort();
+
+        argv.push("env".to_string());
+        argv.extend(vars);
+    }
+
+    argv.push(format!("{}/activate-rs", data.closure));
 
     if data.debug_logs {
-        self_activate_command = format!("{} --debug-logs", self_activate_command);
+        argv.push("--debug-logs".to_string());
     }
 
     if let Some(log_dir) = data.log_dir {
-        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+        argv.extend(["--log-dir".to_string(), log_dir.to_string()]);
     }
 
-    self_activate_command = format!(
-        "{} --temp-path '{}' activate '{}' '{}'",
-        self_activate_command, data.temp_path, data.closure, data.profile_path
-    );
+    if let Some(umask) = data.umask {
+        argv.extend(["--umask".to_string(), umask.to_string()]);
+    }
 
-    self_activate_command = format!(
-        "{} --confirm-timeout {}",
-        self_activate_command, data.confirm_timeout
-    );
+    if let Some(lock_file_name) = data.lock_file_name {
+        argv.extend(["--lock-file-name".to_string(), lock_file_name.to_string()]);
+    }
 
-    if data.magic_rollback {
-        self_activate_command = format!("{} --magic-rollback", self_activate_command);
+    if data.working_dir.is_some() {
+        argv.push("--keep-working-dir".to_string());
     }
 
-    if data.auto_rollback {
-        self_activate_command = format!("{} --auto-rollback", self_activate_command);
+    argv.extend([
+        "--temp-path".to_string(),
+        data.temp_path.to_string(),
+        "activate".to_string(),
+        data.closure.to_string(),
+        data.profile_path.to_string(),
+        "--confirm-timeout".to_string(),
+        data.confirm_timeout.to_string(),
+    ]);
+
+    match data.rollback {
+        RollbackStrategy::Magic => argv.extend([
+            "--magic-rollback".to_string(),
+            "--auto-rollback".to_string(),
+        ]),
+        RollbackStrategy::Auto => argv.push("--auto-rollback".to_string()),
+        RollbackStrategy::None => (),
     }
 
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if let Some(snapshot) = data.snapshot {
+        argv.extend([
+            "--pre-activate-snapshot".to_string(),
+            snapshot.create.clone(),
+        ]);
+
+        if let Some(rollback) = &snapshot.rollback {
+            argv.extend(["--rollback-to-snapshot".to_string(), rollback.clone()]);
+        }
+
+        if let Some(release) = &snapshot.release {
+            argv.extend(["--release-snapshot".to_string(), release.clone()]);
+        }
     }
 
-    self_activate_command
+    if let Some(self_confirm_command) = data.self_confirm_command {
+        argv.extend([
+            "--self-confirm-command".to_string(),
+            self_confirm_command.to_string(),
+        ]);
+    }
+
+    argv
+}
+
+#[cfg(test)]
+fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|x| x.to_string()).collect()
 }
 
 #[test]
 fn test_activation_command_builder() {
-    let sudo = Some("sudo -u test".to_string());
-    let profile_path = "/blah/profiles/test";
-    let closure = "/nix/store/blah/etc";
-    let auto_rollback = true;
-    let temp_path = "/tmp";
-    let confirm_timeout = 30;
-    let magic_rollback = true;
-    let debug_logs = true;
-    let log_dir = Some("/tmp/something.txt");
-
     assert_eq!(
         build_activate_command(ActivateCommandData {
-            sudo: &sudo,
-            profile_path,
-            closure,
-            auto_rollback,
-            temp_path,
-            confirm_timeout,
-            magic_rollback,
-            debug_logs,
-            log_dir
+            profile_path: "/blah/profiles/test",
+            closure: "/nix/store/blah/etc",
+            rollback: RollbackStrategy::Magic,
+            temp_path: "/tmp",
+            confirm_timeout: 30,
+            debug_logs: true,
+            log_dir: Some("/tmp/something.txt"),
+            umask: Some("0002"),
+            lock_file_name: None,
+            working_dir: None,
+            snapshot: None,
+            self_confirm_command: None,
+            activation_env: None,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt --temp-path '/tmp' activate '/nix/store/blah/etc' '/blah/profiles/test' --confirm-timeout 30 --magic-rollback --auto-rollback"
-            .to_string(),
+        argv(&[
+            "/nix/store/blah/etc/activate-rs",
+            "--debug-logs",
+            "--log-dir",
+            "/tmp/something.txt",
+            "--umask",
+            "0002",
+            "--temp-path",
+            "/tmp",
+            "activate",
+            "/nix/store/blah/etc",
+            "/blah/profiles/test",
+            "--confirm-timeout",
+            "30",
+            "--magic-rollback",
+            "--auto-rollback",
+        ]),
+    );
+}
+
+#[test]
+fn test_activation_command_env() {
+    let activation_env: HashMap<String, String> = vec![
+        ("FEATURES".to_string(), "a b's".to_string()),
+        ("DEPLOY_STAGE".to_string(), "production".to_string()),
+    ]
+    .into_iter()
+    .collect();
+
+    let activate = build_activate_command(ActivateCommandData {
+        profile_path: "/blah/profiles/test",
+        closure: "/nix/store/blah/etc",
+        rollback: RollbackStrategy::None,
+        temp_path: "/tmp",
+        confirm_timeout: 30,
+        debug_logs: false,
+        log_dir: None,
+        umask: None,
+        lock_file_name: None,
+        working_dir: Some("/srv/app"),
+        snapshot: None,
+        self_confirm_command: None,
+        activation_env: Some(&activation_env),
+    });
+
+    assert_eq!(
+        activate[..5],
+        argv(&[
+            "env",
+            "DEPLOY_STAGE=production",
+            "FEATURES=a b's",
+            "/nix/store/blah/etc/activate-rs",
+            "--keep-working-dir",
+        ])[..]
     );
 }
 
+#[test]
+fn test_activation_command_self_confirm() {
+    let command = build_activate_command(ActivateCommandData {
+        profile_path: "/blah/profiles/test",
+        closure: "/nix/store/blah/etc",
+        rollback: RollbackStrategy::Magic,
+        temp_path: "/tmp",
+        confirm_timeout: 30,
+        debug_logs: false,
+        log_dir: None,
+        umask: None,
+        lock_file_name: None,
+        working_dir: None,
+        snapshot: None,
+        self_confirm_command: Some("curl -f http://localhost/health"),
+        activation_env: None,
+    });
+
+    assert!(command.ends_with(&argv(&[
+        "--magic-rollback",
+        "--auto-rollback",
+        "--self-confirm-command",
+        "curl -f http://localhost/health",
+    ])));
+}
+
 struct WaitCommandData<'a> {
-    sudo: &'a Option<String>,
     closure: &'a str,
     temp_path: &'a str,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    lock_file_name: Option<&'a str>,
 }
 
-fn build_wait_command(data: WaitCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+/// The command line of `activate-rs` waiting for the activation, see `build_activate_command`
+fn build_wait_command(data: WaitCommandData) -> Vec<String> {
+    let mut argv = vec![format!("{}/activate-rs", data.closure)];
 
     if data.debug_logs {
-        self_activate_command = format!("{} --debug-logs", self_activate_command);
+        argv.push("--debug-logs".to_string());
     }
 
     if let Some(log_dir) = data.log_dir {
-        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+        argv.extend(["--log-dir".to_string(), log_dir.to_string()]);
     }
 
-    self_activate_command = format!(
-        "{} --temp-path '{}' wait '{}'",
-        self_activate_command, data.temp_path, data.closure
-    );
-
-    if let Some(sudo_cmd) = &data.sudo {
-        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    if let Some(lock_file_name) = data.lock_file_name {
+        argv.extend(["--lock-file-name".to_string(), lock_file_name.to_string()]);
     }
 
-    self_activate_command
+    argv.extend([
+        "--temp-path".to_string(),
+        data.temp_path.to_string(),
+        "wait".to_string(),
+        data.closure.to_string(),
+    ]);
+
+    argv
 }
 
 #[test]
 fn test_wait_command_builder() {
-    let sudo = Some("sudo -u test".to_string());
-    let closure = "/nix/store/blah/etc";
-    let temp_path = "/tmp";
-    let debug_logs = true;
-    let log_dir = Some("/tmp/something.txt");
-
     assert_eq!(
         build_wait_command(WaitCommandData {
-            sudo: &sudo,
-            closure,
-            temp_path,
-            debug_logs,
-            log_dir
+            closure: "/nix/store/blah/etc",
+            temp_path: "/tmp",
+            debug_logs: true,
+            log_dir: Some("/tmp/something.txt"),
+            lock_file_name: None,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt --temp-path '/tmp' wait '/nix/store/blah/etc'"
-            .to_string(),
+        argv(&[
+            "/nix/store/blah/etc/activate-rs",
+            "--debug-logs",
+            "--log-dir",
+            "/tmp/something.txt",
+            "--temp-path",
+            "/tmp",
+            "wait",
+            "/nix/store/blah/etc",
+        ]),
     );
 }
 
-#[derive(Error, Debug)]
-pub enum ConfirmProfileError {
-    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
-    SSHConfirmError(std::io::Error),
-    #[error(
-        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
-    )]
-    SSHConfirmExitError(Option<i32>),
+/// Joins `argv` into one command line for a shell, quoting the arguments which need it
+fn shell_join(argv: &[String]) -> String {
+    argv.iter()
+        .map(|x| quote_arg(x))
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-pub async fn confirm_profile(
-    deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-    temp_path: Cow<'_, str>,
-    ssh_addr: &str,
-) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command.arg(ssh_addr);
+/// The command SSH runs on the node for `argv`, which the shell of the node splits into `argv`
+/// again. It is run as `user` through `sudo`, and in `working_dir` (entered as `sshUser`, unless
+/// `working_dir_after_sudo`).
+fn remote_command(
+    argv: &[String],
+    sudo: &Option<String>,
+    working_dir: Option<&str>,
+    working_dir_after_sudo: bool,
+) -> String {
+    let mut command = shell_join(argv);
+
+    match (working_dir, sudo) {
+        (Some(working_dir), Some(sudo_cmd)) if working_dir_after_sudo => {
+            // Changing directory requires a shell, which sudo doesn't provide by itself
+            command = format!(
+                "{} sh -c {}",
+                sudo_cmd,
+                shell_escape(&format!("cd {} && {}", shell_escape(working_dir), command))
+            );
+        }
+        (working_dir, sudo) => {
+            if let Some(sudo_cmd) = sudo {
+                command = format!("{} {}", sudo_cmd, command);
+            }
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
+            if let Some(working_dir) = working_dir {
+                command = format!("cd {} && {}", shell_escape(working_dir), command);
+            }
+        }
     }
 
-    let lock_path = super::make_lock_path(&temp_path, &deploy_data.profile.profile_settings.path);
+    command
+}
 
-    let mut confirm_command = format!("rm {}", lock_path);
-    if let Some(sudo_cmd) = &deploy_defs.sudo {
-        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
-    }
+#[test]
+fn test_remote_command() {
+    let sudo = Some("sudo -u test".to_string());
+    let activate = argv(&[
+        "/nix/store/blah/etc/activate-rs",
+        "--keep-working-dir",
+        "--temp-path",
+        "/tmp",
+        "activate",
+        "/nix/store/blah/etc",
+        "/blah/profiles/test",
+    ]);
+    let joined = "/nix/store/blah/etc/activate-rs --keep-working-dir --temp-path /tmp activate /nix/store/blah/etc /blah/profiles/test";
 
-    debug!(
-        "Attempting to run command to confirm deployment: {}",
-        confirm_command
+    assert_eq!(remote_command(&activate, &None, None, false), joined);
+    assert_eq!(
+        remote_command(&activate, &sudo, None, false),
+        format!("sudo -u test {}", joined)
+    );
+    assert_eq!(
+        remote_command(&activate, &None, Some("/srv/my app"), false),
+        format!("cd '/srv/my app' && {}", joined)
+    );
+    assert_eq!(
+        remote_command(&activate, &sudo, Some("/srv/my app"), false),
+        format!("cd '/srv/my app' && sudo -u test {}", joined)
+    );
+    assert_eq!(
+        remote_command(
+            &activate,
+            &Some("doas -u 'test'".to_string()),
+            Some("/srv/my app"),
+            true
+        ),
+        format!(
+            "doas -u 'test' sh -c 'cd '\\''/srv/my app'\\'' && {}'",
+            joined
+        )
     );
+}
 
-    let ssh_confirm_exit_status = ssh_confirm_command
-        .arg(confirm_command)
-        .status()
-        .await
-        .map_err(ConfirmProfileError::SSHConfirmError)?;
+#[test]
+fn test_commands_escape_paths() {
+    let sudo = Some("sudo -u test".to_string());
 
-    match ssh_confirm_exit_status.code() {
-        Some(0) => (),
-        a => return Err(ConfirmProfileError::SSHConfirmExitError(a)),
-    };
+    let activate = build_activate_command(ActivateCommandData {
+        profile_path: "/blah/profiles/it's mine",
+        closure: "/nix/store/blah etc",
+        rollback: RollbackStrategy::None,
+        temp_path: "/tmp/$(reboot)",
+        confirm_timeout: 30,
+        debug_logs: false,
+        log_dir: Some("/var/log/`id`"),
+        umask: None,
+        lock_file_name: None,
+        working_dir: None,
+        snapshot: None,
+        self_confirm_command: None,
+        activation_env: None,
+    });
 
-    info!("Deployment confirmed.");
+    assert_eq!(
+        remote_command(&activate, &sudo, None, false),
+        "sudo -u test '/nix/store/blah etc/activate-rs' --log-dir '/var/log/`id`' --temp-path '/tmp/$(reboot)' activate '/nix/store/blah etc' '/blah/profiles/it'\\''s mine' --confirm-timeout 30"
+    );
 
-    Ok(())
-}
+    let wait = build_wait_command(WaitCommandData {
+        closure: "/nix/store/blah etc",
+        temp_path: "/tmp/$(reboot)",
+        debug_logs: false,
+        log_dir: Some("/var/log/it's"),
+        lock_file_name: Some("deploy-rs; rm -rf /"),
+    });
 
-#[derive(Error, Debug)]
-pub enum DeployProfileError {
-    #[error("Failed to spawn activation command over SSH: {0}")]
-    SSHSpawnActivateError(std::io::Error),
+    assert_eq!(
+        remote_command(&wait, &sudo, None, false),
+        "sudo -u test '/nix/store/blah etc/activate-rs' --log-dir '/var/log/it'\\''s' --lock-file-name 'deploy-rs; rm -rf /' --temp-path '/tmp/$(reboot)' wait '/nix/store/blah etc'"
+    );
 
-    #[error("Failed to run activation command over SSH: {0}")]
-    SSHActivateError(std::io::Error),
-    #[error("Activating over SSH resulted in a bad exit code: {0:?}")]
-    SSHActivateExitError(Option<i32>),
+    // Values of the activation environment stay one argument each
+    let activation_env: HashMap<String, String> =
+        vec![("FEATURES".to_string(), "a b's $(id)".to_string())]
+            .into_iter()
+            .collect();
+    let activate = build_activate_command(ActivateCommandData {
+        profile_path: "/blah/profiles/test",
+        closure: "/nix/store/blah-etc",
+        rollback: RollbackStrategy::None,
+        temp_path: "/tmp",
+        confirm_timeout: 30,
+        debug_logs: false,
+        log_dir: None,
+        umask: None,
+        lock_file_name: None,
+        working_dir: None,
+        snapshot: None,
+        self_confirm_command: None,
+        activation_env: Some(&activation_env),
+    });
 
-    #[error("Failed to run wait command over SSH: {0}")]
-    SSHWaitError(std::io::Error),
-    #[error("Waiting over SSH resulted in a bad exit code: {0:?}")]
-    SSHWaitExitError(Option<i32>),
+    assert!(remote_command(&activate, &None, None, false)
+        .starts_with("env 'FEATURES=a b'\\''s $(id)' /nix/store/blah-etc/activate-rs "));
 
-    #[error("Error confirming deployment: {0}")]
-    ConfirmError(#[from] ConfirmProfileError),
+    assert_eq!(
+        build_verify_command("/nix/store/it's etc"),
+        "nix store verify --recursive --no-trust '/nix/store/it'\\''s etc'"
+    );
 }
 
-pub async fn deploy_profile(
-    deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-) -> Result<(), DeployProfileError> {
-    info!(
-        "Activating profile `{}` for node `{}`",
-        deploy_data.profile_name, deploy_data.node_name
-    );
+struct RollbackCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    profile_path: &'a str,
+    temp_path: &'a str,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+}
 
-    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
-        Some(x) => x.into(),
-        None => "/tmp".into(),
-    };
+fn build_rollback_command(data: RollbackCommandData) -> String {
+    let mut self_activate_command = shell_escape(&format!("{}/activate-rs", data.closure));
 
-    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
 
-    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!(
+            "{} --log-dir {}",
+            self_activate_command,
+            shell_escape(log_dir)
+        );
+    }
 
-    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+    self_activate_command = format!(
+        "{} --temp-path {} rollback {}",
+        self_activate_command,
+        shell_escape(data.temp_path),
+        shell_escape(data.profile_path)
+    );
 
-    let self_activate_command = build_activate_command(ActivateCommandData {
-        sudo: &deploy_defs.sudo,
-        profile_path: &deploy_defs.profile_path,
-        closure: &deploy_data.profile.profile_settings.path,
-        auto_rollback,
-        temp_path: &temp_path,
-        confirm_timeout,
-        magic_rollback,
-        debug_logs: deploy_data.debug_logs,
-        log_dir: deploy_data.log_dir,
-    });
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
 
-    debug!("Constructed activation command: {}", self_activate_command);
+    self_activate_command
+}
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+#[test]
+fn test_rollback_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+
+    assert_eq!(
+        build_rollback_command(RollbackCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah/etc",
+            profile_path: "/blah/profiles/test",
+            temp_path: "/tmp",
+            debug_logs: true,
+            log_dir: Some("/tmp/something.txt"),
+        }),
+        "sudo -u test '/nix/store/blah/etc/activate-rs' --debug-logs --log-dir '/tmp/something.txt' --temp-path '/tmp' rollback '/blah/profiles/test'"
+            .to_string(),
+    );
+}
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+/// Writes standard input to `remote` on the node. It is written next to `remote` and moved in place
+/// once complete, only readable by its owner until `mode` is set.
+fn build_upload_command(sudo: &Option<String>, remote: &str, mode: Option<&str>) -> String {
+    let temp = shell_escape(&format!("{}.deploy-rs-upload", remote));
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command.arg(&ssh_addr);
+    let mut script = format!("umask 077 && cat > {}", temp);
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+    if let Some(mode) = mode {
+        script = format!("{} && chmod {} {}", script, shell_escape(mode), temp);
     }
 
-    if !magic_rollback {
-        let ssh_activate_exit_status = ssh_activate_command
-            .arg(self_activate_command)
-            .status()
-            .await
-            .map_err(DeployProfileError::SSHActivateError)?;
-
-        match ssh_activate_exit_status.code() {
-            Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExitError(a)),
-        };
+    script = format!("{} && mv {} {}", script, temp, shell_escape(remote));
 
-        info!("Success activating, done!");
-    } else {
-        let self_wait_command = build_wait_command(WaitCommandData {
-            sudo: &deploy_defs.sudo,
-            closure: &deploy_data.profile.profile_settings.path,
-            temp_path: &temp_path,
-            debug_logs: deploy_data.debug_logs,
-            log_dir: deploy_data.log_dir,
-        });
+    let mut upload_command = format!("sh -c {}", shell_escape(&script));
 
-        debug!("Constructed wait command: {}", self_wait_command);
+    if let Some(sudo_cmd) = &sudo {
+        upload_command = format!("{} {}", sudo_cmd, upload_command);
+    }
 
-        let ssh_activate = ssh_activate_command
-            .arg(self_activate_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHSpawnActivateError)?;
+    upload_command
+}
 
-        info!("Creating activation waiter");
+#[test]
+fn test_upload_command_builder() {
+    assert_eq!(
+        build_upload_command(
+            &Some("sudo -u root".to_string()),
+            "/run/keys/app.env",
+            Some("0400")
+        ),
+        r#"sudo -u root sh -c 'umask 077 && cat > '\''/run/keys/app.env.deploy-rs-upload'\'' && chmod '\''0400'\'' '\''/run/keys/app.env.deploy-rs-upload'\'' && mv '\''/run/keys/app.env.deploy-rs-upload'\'' '\''/run/keys/app.env'\'''"#
+    );
+    assert_eq!(
+        build_upload_command(&None, "/etc/app.conf", None),
+        r#"sh -c 'umask 077 && cat > '\''/etc/app.conf.deploy-rs-upload'\'' && mv '\''/etc/app.conf.deploy-rs-upload'\'' '\''/etc/app.conf'\'''"#
+    );
+}
 
-        let mut ssh_wait_command = Command::new("ssh");
-        ssh_wait_command.arg(&ssh_addr);
+fn is_octal_mode(mode: &str) -> bool {
+    (3..=4).contains(&mode.len()) && mode.chars().all(|c| ('0'..='7').contains(&c))
+}
 
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+#[test]
+fn test_is_octal_mode() {
+    assert!(is_octal_mode("0600"));
+    assert!(is_octal_mode("644"));
+    assert!(!is_octal_mode("u+rw"));
+    assert!(!is_octal_mode("0800"));
+    assert!(!is_octal_mode(""));
+}
 
-        let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
+/// Copies `preActivationFiles` to the node in order, through the same `ssh` as every other command
+async fn upload_files(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    files: &[crate::data::FileUpload],
+) -> Result<(), DeployProfileError> {
+    for file in files {
+        let local = file.local.display().to_string();
+
+        if let Some(mode) = &file.mode {
+            if !is_octal_mode(mode) {
+                return Err(DeployProfileError::InvalidUploadMode(local, mode.clone()));
+            }
+        }
+
+        let upload_command =
+            build_upload_command(&deploy_defs.sudo, &file.remote, file.mode.as_deref());
+
+        node_log!(
+            debug,
+            deploy_data,
+            "Uploading `{}` to the node: {}",
+            local,
+            upload_command
+        );
+
+        let mut argv = node_argv(deploy_data, ssh_addr);
+        argv.push(upload_command);
+
+        let ssh_upload_exit_status = runner
+            .status(
+                &argv,
+                RunOptions {
+                    stdin: Some(file.local.clone()),
+                    ..sudo_options(deploy_data, deploy_defs)
+                },
+            )
+            .await
+            .map_err(|err| DeployProfileError::UploadError(local.clone(), err))?;
+
+        match ssh_upload_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::UploadExitError(local, a)),
+        };
+    }
+
+    Ok(())
+}
+
+struct ConfirmCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    temp_path: &'a str,
+    lock_file_name: Option<&'a str>,
+    /// Removes the canary file, with `{lock_path}` replaced by its quoted path
+    confirm_command: Option<&'a str>,
+}
+
+/// How the canary file is removed to confirm, unless `confirmCommand` is set
+const DEFAULT_CONFIRM_COMMAND: &str = "rm {lock_path}";
+
+/// Exit code of the confirmation command when there is no canary file, it was already confirmed (or rolled back)
+const CONFIRM_NOT_PENDING_EXIT: i32 = 3;
+/// Exit code of the confirmation command when the canary file belongs to another closure
+const CONFIRM_MISMATCH_EXIT: i32 = 4;
+
+/// The canary file contains the closure waiting for confirmation, it is only removed if that is
+/// the expected one. Empty files, created by older versions of `activate-rs`, are always removed.
+fn build_confirm_command(data: ConfirmCommandData) -> String {
+    let lock_path = super::make_lock_path(data.temp_path, data.closure, data.lock_file_name);
+
+    let remove_command = data
+        .confirm_command
+        .unwrap_or(DEFAULT_CONFIRM_COMMAND)
+        .replace("{lock_path}", &shell_escape(&lock_path));
+
+    let script = format!(
+        "test -f {0} || exit {2}; c=$(cat {0}); test -z \"$c\" -o \"$c\" = {1} || exit {3}; {4}",
+        shell_escape(&lock_path),
+        shell_escape(data.closure),
+        CONFIRM_NOT_PENDING_EXIT,
+        CONFIRM_MISMATCH_EXIT,
+        remove_command
+    );
+
+    let mut confirm_command = format!("sh -c {}", shell_escape(&script));
+
+    if let Some(sudo_cmd) = &data.sudo {
+        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
+    }
+
+    confirm_command
+}
+
+#[test]
+fn test_confirm_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let closure = "/nix/store/blah-etc";
+    let temp_path = "/tmp";
+
+    assert_eq!(
+        build_confirm_command(ConfirmCommandData {
+            sudo: &sudo,
+            closure,
+            temp_path,
+            lock_file_name: None,
+            confirm_command: None,
+        }),
+        "sudo -u test sh -c 'test -f '\\''/tmp/deploy-rs-canary-blah'\\'' || exit 3; c=$(cat '\\''/tmp/deploy-rs-canary-blah'\\''); test -z \"$c\" -o \"$c\" = '\\''/nix/store/blah-etc'\\'' || exit 4; rm '\\''/tmp/deploy-rs-canary-blah'\\'''"
+            .to_string(),
+    );
+}
+
+#[test]
+fn test_confirm_command_builder_custom() {
+    let sudo = Some("sudo -u test".to_string());
+
+    let confirm_command = build_confirm_command(ConfirmCommandData {
+        sudo: &sudo,
+        closure: "/nix/store/blah-etc",
+        temp_path: "/tmp",
+        lock_file_name: Some("deploy-rs-ready"),
+        confirm_command: Some("unlink {lock_path} && touch /run/confirmed"),
+    });
+
+    assert!(
+        confirm_command.starts_with("sudo -u test sh -c 'test -f '\\''/tmp/deploy-rs-ready'\\''")
+    );
+    assert!(confirm_command
+        .ends_with("|| exit 4; unlink '\\''/tmp/deploy-rs-ready'\\'' && touch /run/confirmed'"));
+}
+
+/// Writing to the canary file makes the waiting activation roll back immediately
+fn build_cancel_command(data: ConfirmCommandData) -> String {
+    let lock_path = super::make_lock_path(data.temp_path, data.closure, data.lock_file_name);
+
+    // Fail instead of creating the file if nothing is waiting for confirmation
+    let script = format!("test -f {0} && echo cancel > {0}", shell_escape(&lock_path));
+    let mut cancel_command = format!("sh -c {}", shell_escape(&script));
+
+    if let Some(sudo_cmd) = &data.sudo {
+        cancel_command = format!("{} {}", sudo_cmd, cancel_command);
+    }
+
+    cancel_command
+}
+
+#[test]
+fn test_cancel_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+
+    assert_eq!(
+        build_cancel_command(ConfirmCommandData {
+            sudo: &sudo,
+            closure: "/nix/store/blah-etc",
+            temp_path: "/tmp",
+            lock_file_name: None,
+            confirm_command: None,
+        }),
+        "sudo -u test sh -c 'test -f '\\''/tmp/deploy-rs-canary-blah'\\'' && echo cancel > '\\''/tmp/deploy-rs-canary-blah'\\'''"
+            .to_string(),
+    );
+}
+
+#[test]
+fn test_confirm_commands_hostile_temp_path() {
+    let temp_dir = std::env::temp_dir().join(format!(
+        "deploy-rs-hostile-{}/it's \"$(touch pwned)\" `touch pwned`",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+    let temp_path = temp_dir.to_str().unwrap();
+    let closure = "/nix/store/blah-etc";
+
+    let lock_path = crate::make_lock_path(temp_path, closure, None);
+    let run = |command: String| {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&temp_dir)
+            .status()
+            .unwrap()
+            .code()
+    };
+    let data = || ConfirmCommandData {
+        sudo: &None,
+        closure,
+        temp_path,
+        lock_file_name: None,
+        confirm_command: None,
+    };
+
+    std::fs::write(&lock_path, closure).unwrap();
+    assert_eq!(run(build_cancel_command(data())), Some(0));
+    assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "cancel\n");
+
+    std::fs::write(&lock_path, closure).unwrap();
+    assert_eq!(run(build_confirm_command(data())), Some(0));
+    assert!(!std::path::Path::new(&lock_path).exists());
+    assert!(!temp_dir.join("pwned").exists());
+
+    std::fs::remove_dir_all(temp_dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_lock_file_name_propagation() {
+    let sudo = None;
+    let closure = "/nix/store/blah-etc";
+    let temp_path = "/tmp";
+    let lock_file_name = Some("custom-ready");
+
+    let activate_command = build_activate_command(ActivateCommandData {
+        profile_path: "/blah/profiles/test",
+        closure,
+        rollback: RollbackStrategy::Magic,
+        temp_path,
+        confirm_timeout: 30,
+        debug_logs: false,
+        log_dir: None,
+        umask: None,
+        lock_file_name,
+        working_dir: None,
+        snapshot: None,
+        self_confirm_command: None,
+        activation_env: None,
+    });
+    let wait_command = build_wait_command(WaitCommandData {
+        closure,
+        temp_path,
+        debug_logs: false,
+        log_dir: None,
+        lock_file_name,
+    });
+    let confirm_command = build_confirm_command(ConfirmCommandData {
+        sudo: &sudo,
+        closure,
+        temp_path,
+        lock_file_name,
+        confirm_command: None,
+    });
+
+    // activate-rs derives the lock path from these two flags in both subcommands
+    let lock_flags = argv(&["--lock-file-name", "custom-ready", "--temp-path", "/tmp"]);
+    assert!(activate_command.windows(4).any(|x| x == &lock_flags[..]));
+    assert!(wait_command.windows(4).any(|x| x == &lock_flags[..]));
+
+    assert_eq!(
+        super::make_lock_path(temp_path, closure, lock_file_name),
+        "/tmp/custom-ready"
+    );
+    assert!(confirm_command.contains("rm '\\''/tmp/custom-ready'\\''"));
+}
+
+/// Outputs `exists` if `lock_path` exists on the node, and nothing otherwise
+fn build_check_lock_command(lock_path: &str) -> String {
+    format!(
+        "if test -e {}; then echo exists; fi",
+        shell_escape(lock_path)
+    )
+}
+
+fn build_clear_lock_command(sudo: &Option<String>, lock_path: &str) -> String {
+    let clear_lock_command = format!("rm -f {}", shell_escape(lock_path));
+
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, clear_lock_command),
+        None => clear_lock_command,
+    }
+}
+
+#[test]
+fn test_lock_command_builders() {
+    assert_eq!(
+        build_check_lock_command("/tmp/deploy-rs-canary-blah-42"),
+        "if test -e '/tmp/deploy-rs-canary-blah-42'; then echo exists; fi"
+    );
+    assert_eq!(
+        build_clear_lock_command(
+            &Some("sudo -u 'root'".to_string()),
+            "/tmp/deploy-rs-canary-blah-42"
+        ),
+        "sudo -u 'root' rm -f '/tmp/deploy-rs-canary-blah-42'"
+    );
+}
+
+/// Whether a lock found before activating has to be removed first, or deploying aborted
+fn stale_lock_action(
+    lock_path: &str,
+    exists: bool,
+    clear_stale_lock: Option<bool>,
+) -> Result<bool, DeployProfileError> {
+    match (exists, clear_stale_lock) {
+        (false, _) => Ok(false),
+        (true, Some(true)) => Ok(true),
+        (true, _) => Err(DeployProfileError::StaleLock(lock_path.to_string())),
+    }
+}
+
+#[test]
+fn test_stale_lock_action() {
+    let lock_path = "/tmp/deploy-rs-canary-blah-42";
+
+    assert!(!stale_lock_action(lock_path, false, None).unwrap());
+    assert!(!stale_lock_action(lock_path, false, Some(true)).unwrap());
+    assert!(stale_lock_action(lock_path, true, Some(true)).unwrap());
+    assert!(matches!(
+        stale_lock_action(lock_path, true, None),
+        Err(DeployProfileError::StaleLock(x)) if x == lock_path
+    ));
+    assert!(matches!(
+        stale_lock_action(lock_path, true, Some(false)),
+        Err(DeployProfileError::StaleLock(_))
+    ));
+}
+
+/// Looks for the lock of this deployment on the node, which only exists when an earlier one of the
+/// same run was killed before it could be confirmed or rolled back
+async fn check_stale_lock(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+) -> Result<(), DeployProfileError> {
+    let lock_path = profile_lock_path(deploy_data);
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(build_check_lock_command(&lock_path));
+
+    let output = runner
+        .output(&argv, node_options(deploy_data))
+        .await
+        .map_err(DeployProfileError::SSHCheckLockError)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(DeployProfileError::SSHCheckLockExitError(a)),
+    };
+
+    let exists = String::from_utf8_lossy(&output.stdout).trim() == "exists";
+
+    if exists {
+        node_log!(
+            warn,
+            deploy_data,
+            "The lock `{}` is already on the node, an earlier deployment of this run was interrupted",
+            lock_path
+        );
+    }
+
+    if stale_lock_action(
+        &lock_path,
+        exists,
+        deploy_data.merged_settings.clear_stale_lock,
+    )? {
+        node_log!(info, deploy_data, "Removing the stale lock `{}`", lock_path);
+
+        let mut argv = node_argv(deploy_data, ssh_addr);
+        argv.push(build_clear_lock_command(&deploy_defs.sudo, &lock_path));
+
+        let status = runner
+            .status(&argv, sudo_options(deploy_data, deploy_defs))
+            .await
+            .map_err(DeployProfileError::SSHCheckLockError)?;
+
+        match status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::SSHCheckLockExitError(a)),
+        };
+    }
+
+    Ok(())
+}
+
+/// Succeeds if `temp_path` is a directory the profile user, who creates the lock in it, can write to
+fn build_check_temp_path_command(sudo: &Option<String>, temp_path: &str) -> String {
+    let check_command = format!("test -d {0} -a -w {0}", shell_escape(temp_path));
+
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, check_command),
+        None => check_command,
+    }
+}
+
+/// `test` fails with 1, any other failure is of running it
+fn temp_path_check_result(temp_path: &str, code: Option<i32>) -> Result<(), DeployProfileError> {
+    match code {
+        Some(0) => Ok(()),
+        Some(1) => Err(DeployProfileError::TempPathUnwritable(
+            temp_path.to_string(),
+        )),
+        a => Err(DeployProfileError::SSHCheckTempPathExitError(a)),
+    }
+}
+
+#[test]
+fn test_temp_path_check_result() {
+    assert!(temp_path_check_result("/tmp", Some(0)).is_ok());
+    assert!(matches!(
+        temp_path_check_result("/tmp", Some(1)),
+        Err(DeployProfileError::TempPathUnwritable(x)) if x == "/tmp"
+    ));
+    assert!(matches!(
+        temp_path_check_result("/tmp", Some(255)),
+        Err(DeployProfileError::SSHCheckTempPathExitError(Some(255)))
+    ));
+    assert!(matches!(
+        temp_path_check_result("/tmp", None),
+        Err(DeployProfileError::SSHCheckTempPathExitError(None))
+    ));
+}
+
+/// Fails early when the lock can't be created in `temp_path`, instead of inside the activation
+async fn check_temp_path(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    temp_path: &str,
+) -> Result<(), DeployProfileError> {
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(build_check_temp_path_command(&deploy_defs.sudo, temp_path));
+
+    let status = runner
+        .status(&argv, sudo_options(deploy_data, deploy_defs))
+        .await
+        .map_err(DeployProfileError::SSHCheckTempPathError)?;
+
+    temp_path_check_result(temp_path, status.code())
+}
+
+fn build_verify_command(closure: &str) -> String {
+    format!(
+        "nix store verify --recursive --no-trust {}",
+        shell_escape(closure)
+    )
+}
+
+/// How the current closure of a profile is read by default, `{profile_path}` is replaced
+const DEFAULT_CURRENT_CLOSURE_COMMAND: &str = "readlink -f {profile_path}";
+
+/// Prints the generation link the profile points to (or an empty line), then the current closure
+/// as printed by `current_closure_command`
+fn build_read_profile_command(profile_path: &str, current_closure_command: Option<&str>) -> String {
+    format!(
+        "echo \"$(readlink {0})\" && {1}",
+        shell_escape(profile_path),
+        current_closure_command
+            .unwrap_or(DEFAULT_CURRENT_CLOSURE_COMMAND)
+            .replace("{profile_path}", &shell_escape(profile_path))
+    )
+}
+
+#[test]
+fn test_read_profile_command_builder() {
+    assert_eq!(
+        build_read_profile_command("/nix/var/nix/profiles/system", None),
+        "echo \"$(readlink '/nix/var/nix/profiles/system')\" && readlink -f '/nix/var/nix/profiles/system'"
+            .to_string(),
+    );
+
+    let settings = crate::data::GenericSettings {
+        current_closure_command: Some("readlink -f /run/booted-system".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        build_read_profile_command(
+            "/nix/var/nix/profiles/system",
+            settings.current_closure_command.as_deref()
+        ),
+        "echo \"$(readlink '/nix/var/nix/profiles/system')\" && readlink -f /run/booted-system"
+            .to_string(),
+    );
+}
+
+/// Parses the number out of a generation link like `system-42-link`
+fn parse_generation(link: &str) -> Option<u64> {
+    link.trim_end()
+        .strip_suffix("-link")?
+        .rsplit('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn test_parse_generation() {
+    assert_eq!(parse_generation("system-42-link\n"), Some(42));
+    assert_eq!(parse_generation("my-profile-7-link"), Some(7));
+    assert_eq!(parse_generation("/nix/store/abc-system"), None);
+}
+
+/// Fills in the placeholders of `onConfirmCommand`
+fn make_on_confirm_command(
+    template: &str,
+    node: &str,
+    profile: &str,
+    closure: &str,
+    generation: Option<u64>,
+) -> String {
+    template
+        .replace("{node}", node)
+        .replace("{profile}", profile)
+        .replace("{closure}", closure)
+        .replace(
+            "{generation}",
+            &generation.map(|x| x.to_string()).unwrap_or_default(),
+        )
+}
+
+#[test]
+fn test_make_on_confirm_command() {
+    assert_eq!(
+        make_on_confirm_command(
+            "mark-deploy --host {node} --profile {profile} --gen {generation} {closure}",
+            "web01",
+            "system",
+            "/nix/store/abc-system",
+            Some(42)
+        ),
+        "mark-deploy --host web01 --profile system --gen 42 /nix/store/abc-system"
+    );
+    assert_eq!(
+        make_on_confirm_command(
+            "echo {generation}",
+            "web01",
+            "system",
+            "/nix/store/abc",
+            None
+        ),
+        "echo "
+    );
+}
+
+/// Runs `onConfirmCommand` locally, failures are only logged since the profile is already live
+async fn run_on_confirm_command(command: &str, deploy_data: &super::DeployData<'_>) {
+    node_log!(
+        debug,
+        deploy_data,
+        "Running on confirm command: {}",
+        command
+    );
+
+    match Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdout(deploy_data.cmd_overrides.log_format.child_stdout())
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => (),
+        Ok(status) => node_log!(
+            warn,
+            deploy_data,
+            "The on confirm command resulted in a bad exit code: {:?}",
+            status.code()
+        ),
+        Err(err) => node_log!(
+            warn,
+            deploy_data,
+            "Failed to run the on confirm command: {}",
+            err
+        ),
+    }
+}
+
+/// Runs `preDeployHook` or `postDeployHook` (the `name`) locally, with the node and profile deployed
+/// in `DEPLOY_NODE` and `DEPLOY_PROFILE`
+async fn run_deploy_hook(
+    deploy_data: &super::DeployData<'_>,
+    name: &'static str,
+    hook: &str,
+) -> Result<(), DeployProfileError> {
+    node_log!(debug, deploy_data, "Running `{}`: {}", name, hook);
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("DEPLOY_NODE", deploy_data.node_name)
+        .env("DEPLOY_PROFILE", deploy_data.profile_name)
+        .stdout(deploy_data.cmd_overrides.log_format.child_stdout())
+        .status()
+        .await
+        .map_err(|err| DeployProfileError::HookError(name, err))?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(DeployProfileError::HookFailed(name, a)),
+    }
+}
+
+/// Options which can be passed to SSH through `sshOpts`, but are managed by deploy-rs settings
+/// instead. Flags are matched as given, `-o` options case insensitively.
+const MANAGED_SSH_OPTS: &[(&str, &str)] = &[
+    ("-l", "sshUser"),
+    ("User", "sshUser"),
+    ("-p", "sshPort"),
+    ("Port", "sshPort"),
+    ("-J", "sshJumpHost"),
+    ("ProxyJump", "sshJumpHost"),
+    ("StrictHostKeyChecking", "strictHostKeyChecking"),
+    ("-i", "identityFile"),
+    ("IdentityFile", "identityFile"),
+];
+
+/// Returns the options in `ssh_opts` which conflict with deploy-rs settings, along with those settings
+pub fn find_managed_ssh_opts(ssh_opts: &[String]) -> Vec<(String, &'static str)> {
+    let mut found = Vec::new();
+    let mut opts = ssh_opts.iter();
+
+    while let Some(opt) = opts.next() {
+        // Both `-o Key=Value` and `-oKey=Value` (or `Key Value`) are valid
+        let option = match opt.strip_prefix("-o") {
+            Some("") => opts.next().map(|x| x.as_str()),
+            Some(x) => Some(x),
+            None => None,
+        };
+
+        let name = match option {
+            Some(x) => x.split(['=', ' ']).next().unwrap_or(x),
+            None => opt.as_str(),
+        };
+
+        for (managed, setting) in MANAGED_SSH_OPTS {
+            let matched = match option {
+                Some(_) => !managed.starts_with('-') && name.eq_ignore_ascii_case(managed),
+                // Flags may have their value attached, like `-lroot`
+                None => managed.starts_with('-') && opt.starts_with(managed),
+            };
+
+            if matched {
+                found.push((name.to_string(), *setting));
+            }
+        }
+    }
+
+    found
+}
+
+#[test]
+fn test_find_managed_ssh_opts() {
+    let opts = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(
+        find_managed_ssh_opts(&opts(&["-o", "Compression=yes", "-v"])),
+        vec![]
+    );
+    assert_eq!(
+        find_managed_ssh_opts(&opts(&[
+            "-p",
+            "2121",
+            "-o",
+            "ProxyJump=bastion",
+            "-i",
+            "key"
+        ])),
+        vec![
+            ("-p".to_string(), "sshPort"),
+            ("ProxyJump".to_string(), "sshJumpHost"),
+            ("-i".to_string(), "identityFile")
+        ]
+    );
+    assert_eq!(
+        find_managed_ssh_opts(&opts(&["-o", "user=admin"])),
+        vec![("user".to_string(), "sshUser")]
+    );
+    assert_eq!(
+        find_managed_ssh_opts(&opts(&["-oUser admin", "-lroot"])),
+        vec![
+            ("User".to_string(), "sshUser"),
+            ("-lroot".to_string(), "sshUser")
+        ]
+    );
+}
+
+/// Prints the uptime of the node, followed by the state of each unit
+fn build_unit_states_command(units: &[String]) -> String {
+    let mut command =
+        "cat /proc/uptime && systemctl show --property=Id,ActiveState,ActiveEnterTimestampMonotonic"
+            .to_string();
+
+    for unit in units {
+        command = format!("{} {}", command, shell_escape(unit));
+    }
+
+    command
+}
+
+#[test]
+fn test_unit_states_command_builder() {
+    assert_eq!(
+        build_unit_states_command(&["nginx.service".to_string(), "my app.service".to_string()]),
+        "cat /proc/uptime && systemctl show --property=Id,ActiveState,ActiveEnterTimestampMonotonic 'nginx.service' 'my app.service'"
+            .to_string(),
+    );
+}
+
+#[derive(Debug, PartialEq)]
+struct UnitState {
+    id: String,
+    active_state: String,
+    /// Microseconds since boot
+    active_enter_monotonic: u64,
+}
+
+/// Parses the output of the unit states command into the uptime of the node in seconds, and the unit states
+fn parse_unit_states(output: &str) -> Option<(f64, Vec<UnitState>)> {
+    let mut lines = output.lines();
+
+    let uptime = lines.next()?.split_whitespace().next()?.parse().ok()?;
+
+    let mut states = Vec::new();
+    let mut state = UnitState {
+        id: String::new(),
+        active_state: String::new(),
+        active_enter_monotonic: 0,
+    };
+
+    // Units are separated by empty lines
+    for line in lines.chain(std::iter::once("")) {
+        match line.split_once('=') {
+            Some(("Id", x)) => state.id = x.to_string(),
+            Some(("ActiveState", x)) => state.active_state = x.to_string(),
+            Some(("ActiveEnterTimestampMonotonic", x)) => {
+                state.active_enter_monotonic = x.parse().ok()?
+            }
+            _ if line.is_empty() && !state.id.is_empty() => {
+                states.push(std::mem::replace(
+                    &mut state,
+                    UnitState {
+                        id: String::new(),
+                        active_state: String::new(),
+                        active_enter_monotonic: 0,
+                    },
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    Some((uptime, states))
+}
+
+#[test]
+fn test_parse_unit_states() {
+    let output = "5000.25 9000.00\n\
+                  Id=nginx.service\n\
+                  ActiveState=active\n\
+                  ActiveEnterTimestampMonotonic=4990000000\n\
+                  \n\
+                  ActiveState=failed\n\
+                  Id=app.service\n\
+                  ActiveEnterTimestampMonotonic=0\n";
+
+    assert_eq!(
+        parse_unit_states(output),
+        Some((
+            5000.25,
+            vec![
+                UnitState {
+                    id: "nginx.service".to_string(),
+                    active_state: "active".to_string(),
+                    active_enter_monotonic: 4990000000,
+                },
+                UnitState {
+                    id: "app.service".to_string(),
+                    active_state: "failed".to_string(),
+                    active_enter_monotonic: 0,
+                },
+            ]
+        ))
+    );
+    assert_eq!(parse_unit_states(""), None);
+}
+
+/// Checks that every unit is active, and if `since` (seconds since boot) is given, that it entered that state afterwards
+fn check_unit_states(
+    units: &[String],
+    states: &[UnitState],
+    since: Option<f64>,
+) -> Result<(), DeployProfileError> {
+    for unit in units {
+        let state = match states.iter().find(|x| &x.id == unit) {
+            Some(x) => x,
+            None => {
+                return Err(DeployProfileError::UnitNotActive(
+                    unit.clone(),
+                    "unknown".to_string(),
+                ))
+            }
+        };
+
+        if state.active_state != "active" {
+            return Err(DeployProfileError::UnitNotActive(
+                unit.clone(),
+                state.active_state.clone(),
+            ));
+        }
+
+        if let Some(since) = since {
+            if (state.active_enter_monotonic as f64) / 1_000_000.0 < since {
+                return Err(DeployProfileError::UnitNotRestarted(unit.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_unit_states() {
+    let units = ["nginx.service".to_string()];
+    let states = [UnitState {
+        id: "nginx.service".to_string(),
+        active_state: "active".to_string(),
+        active_enter_monotonic: 4990000000,
+    }];
+
+    assert!(check_unit_states(&units, &states, None).is_ok());
+    assert!(check_unit_states(&units, &states, Some(4980.0)).is_ok());
+    assert!(matches!(
+        check_unit_states(&units, &states, Some(4995.0)),
+        Err(DeployProfileError::UnitNotRestarted(_))
+    ));
+    assert!(matches!(
+        check_unit_states(&["app.service".to_string()], &states, None),
+        Err(DeployProfileError::UnitNotActive(_, _))
+    ));
+}
+
+/// How many SSH round trips `auto_confirm_timeout` makes sure fit in the confirmation window
+const CONFIRM_TIMEOUT_RTT_MULTIPLIER: u32 = 10;
+
+/// Takes `confirm_timeout` as a floor, raising it to fit enough round trips of the given latency
+fn confirm_timeout_for_rtt(confirm_timeout: u16, rtt: Duration) -> u16 {
+    // Rounded up to whole seconds
+    let minimum = (rtt * CONFIRM_TIMEOUT_RTT_MULTIPLIER)
+        .as_millis()
+        .div_ceil(1000);
+
+    if minimum > confirm_timeout as u128 {
+        minimum.min(u16::MAX as u128) as u16
+    } else {
+        confirm_timeout
+    }
+}
+
+#[test]
+fn test_confirm_timeout_for_rtt() {
+    assert_eq!(confirm_timeout_for_rtt(30, Duration::from_millis(200)), 30);
+    assert_eq!(confirm_timeout_for_rtt(30, Duration::from_millis(3000)), 30);
+    assert_eq!(confirm_timeout_for_rtt(30, Duration::from_millis(3001)), 31);
+    assert_eq!(confirm_timeout_for_rtt(5, Duration::from_secs(2)), 20);
+    assert_eq!(
+        confirm_timeout_for_rtt(30, Duration::from_secs(u32::MAX as u64)),
+        u16::MAX
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmProfileError {
+    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
+    SSHConfirmError(std::io::Error),
+    #[error(
+        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
+    )]
+    SSHConfirmExitError(Option<i32>),
+    #[error(
+        "The node is waiting for confirmation of another closure than `{0}`, which was left alone"
+    )]
+    ClosureMismatch(String),
+    #[error("Confirm checks failed (the server should roll back): {}", .0.join(", "))]
+    ChecksFailed(Vec<String>),
+    #[error(
+        "Confirm checks did not finish before the confirm timeout (the server should roll back)"
+    )]
+    ChecksTimeout,
+}
+
+/// How many times connecting for confirming is retried without `confirmRetries`, as failing to
+/// confirm rolls back a deployment which may have been fine
+const DEFAULT_CONFIRM_RETRIES: u8 = 2;
+
+/// Confirms the activation, once `confirmChecks` (if any) passed before `deadline`. Connecting is
+/// retried as long as that is before `deadline` too.
+pub async fn confirm_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    temp_path: Cow<'_, str>,
+    ssh_addr: &str,
+    deadline: Option<Instant>,
+) -> Result<(), ConfirmProfileError> {
+    if let Some(check) = &deploy_data.merged_settings.confirm_checks {
+        let min_healthy = Duration::from_secs(
+            deploy_data
+                .merged_settings
+                .min_healthy_duration
+                .unwrap_or(0)
+                .into(),
+        );
+        let mut window = crate::checks::HealthyWindow::new(min_healthy);
+        let node_argv = node_argv(deploy_data, ssh_addr);
+        let options = node_options(deploy_data);
+
+        loop {
+            let checks = crate::checks::run_confirm_checks(check, runner, &node_argv, &options);
+
+            let result = match deadline {
+                Some(deadline) => {
+                    tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), checks)
+                        .await
+                        .map_err(|_| ConfirmProfileError::ChecksTimeout)?
+                }
+                None => checks.await,
+            };
+
+            if window.record(result.is_ok(), Instant::now()) {
+                break;
+            }
+
+            match &result {
+                // Without a minimum healthy duration, checks only get one chance
+                Err(failures) if min_healthy.as_secs() == 0 => {
+                    return Err(ConfirmProfileError::ChecksFailed(failures.clone()))
+                }
+                Err(failures) => node_log!(
+                    debug,
+                    deploy_data,
+                    "Confirm checks failed, waiting for them to pass for {:?}: {}",
+                    min_healthy,
+                    failures.join(", ")
+                ),
+                Ok(()) => (),
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() + crate::checks::CHECK_INTERVAL >= deadline {
+                    return Err(match (window.healthy(), result) {
+                        (false, Err(failures)) => ConfirmProfileError::ChecksFailed(failures),
+                        _ => ConfirmProfileError::ChecksTimeout,
+                    });
+                }
+            }
+
+            tokio::time::sleep(crate::checks::CHECK_INTERVAL).await;
+        }
+
+        node_log!(debug, deploy_data, "Confirm checks passed");
+    }
+
+    let confirm_command = build_confirm_command(ConfirmCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path: &temp_path,
+        lock_file_name: deploy_data.merged_settings.lock_file_name.as_deref(),
+        confirm_command: deploy_data.merged_settings.confirm_command.as_deref(),
+    });
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Attempting to run command to confirm deployment: {}",
+        confirm_command
+    );
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(confirm_command);
+
+    let retries = match deploy_data.merged_settings.confirm_retries {
+        Some(x) => x,
+        None => deploy_data
+            .merged_settings
+            .ssh_connect_retries
+            .unwrap_or(0)
+            .max(DEFAULT_CONFIRM_RETRIES),
+    };
+
+    // Confirming is idempotent, so it is safe to run again when connecting failed
+    let ssh_confirm_exit_status =
+        with_connect_retries(deploy_data, "confirming", retries, deadline, || {
+            runner.status(
+                &argv,
+                RunOptions {
+                    // Confirming a group stops the remaining confirmations once one fails
+                    kill_on_drop: true,
+                    ..sudo_options(deploy_data, deploy_defs)
+                },
+            )
+        })
+        .await
+        .map_err(ConfirmProfileError::SSHConfirmError)?;
+
+    match ssh_confirm_exit_status.code() {
+        Some(0) => (),
+        // Confirming is idempotent, if this closure was confirmed already the profile check afterwards still passes
+        Some(CONFIRM_NOT_PENDING_EXIT) => {
+            node_log!(warn, deploy_data, "Nothing is waiting for confirmation on the node, it was already confirmed or rolled back");
+            return Ok(());
+        }
+        Some(CONFIRM_MISMATCH_EXIT) => {
+            return Err(ConfirmProfileError::ClosureMismatch(
+                deploy_data.profile.profile_settings.path.to_string(),
+            ))
+        }
+        a => return Err(ConfirmProfileError::SSHConfirmExitError(a)),
+    };
+
+    node_log!(info, deploy_data, "Deployment confirmed.");
+    emit_event(deploy_data, "confirmation_done", None);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum CancelProfileError {
+    #[error("Failed to run cancellation command over SSH: {0}")]
+    SSHCancelError(std::io::Error),
+    #[error("Cancelling over SSH resulted in a bad exit code (is the profile waiting for confirmation?): {0:?}")]
+    SSHCancelExitError(Option<i32>),
+}
+
+/// Makes a profile which is waiting for confirmation roll back now, instead of once its confirm timeout elapses
+pub async fn cancel_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), CancelProfileError> {
+    node_log!(
+        info,
+        deploy_data,
+        "Cancelling activation of profile `{}` for node `{}`",
+        deploy_data.profile_name,
+        deploy_data.node_name
+    );
+
+    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x.into(),
+        None => "/tmp".into(),
+    };
+
+    let mut argv = node_argv(deploy_data, &deploy_data.ssh_addr(deploy_defs));
+
+    let cancel_command = build_cancel_command(ConfirmCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path: &temp_path,
+        lock_file_name: deploy_data.merged_settings.lock_file_name.as_deref(),
+        confirm_command: None,
+    });
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Attempting to run command to cancel activation: {}",
+        cancel_command
+    );
+
+    argv.push(cancel_command);
+
+    let ssh_cancel_exit_status = SshRunner
+        .status(&argv, sudo_options(deploy_data, deploy_defs))
+        .await
+        .map_err(CancelProfileError::SSHCancelError)?;
+
+    match ssh_cancel_exit_status.code() {
+        Some(0) => (),
+        a => return Err(CancelProfileError::SSHCancelExitError(a)),
+    };
+
+    node_log!(
+        info,
+        deploy_data,
+        "Activation cancelled, the node is rolling back."
+    );
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RollbackError {
+    #[error("Failed to run rollback command over SSH: {0}")]
+    SSHRollbackError(std::io::Error),
+    #[error("Rolling back over SSH resulted in a bad exit code: {0:?}")]
+    SSHRollbackExitError(Option<i32>),
+}
+
+/// Rolls the profile back to its previous generation and activates that, like a failed activation
+/// does. Unlike `cancel_profile`, this works after the deployment has been confirmed.
+pub async fn rollback_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), RollbackError> {
+    node_log!(
+        info,
+        deploy_data,
+        "Rolling back profile `{}` for node `{}`",
+        deploy_data.profile_name,
+        deploy_data.node_name
+    );
+
+    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x.into(),
+        None => "/tmp".into(),
+    };
+
+    let mut argv = node_argv(deploy_data, &deploy_data.ssh_addr(deploy_defs));
+
+    let rollback_command = build_rollback_command(RollbackCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_path: &deploy_defs.profile_path,
+        temp_path: &temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+    });
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Attempting to run command to roll back: {}",
+        rollback_command
+    );
+
+    argv.push(rollback_command);
+
+    let ssh_rollback_exit_status = SshRunner
+        .status(&argv, sudo_options(deploy_data, deploy_defs))
+        .await
+        .map_err(RollbackError::SSHRollbackError)?;
+
+    match ssh_rollback_exit_status.code() {
+        Some(0) => (),
+        a => return Err(RollbackError::SSHRollbackExitError(a)),
+    };
+
+    node_log!(
+        info,
+        deploy_data,
+        "Rolled back profile `{}`",
+        deploy_data.profile_name
+    );
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DeployProfileError {
+    #[error("{0}")]
+    IncoherentRollback(crate::IncoherentRollbackError),
+
+    #[error("Failed to run command for measuring latency over SSH: {0}")]
+    SSHMeasureRttError(std::io::Error),
+    #[error("Measuring latency over SSH resulted in a bad exit code: {0:?}")]
+    SSHMeasureRttExitError(Option<i32>),
+
+    #[error("Failed to check for a stale lock over SSH: {0}")]
+    SSHCheckLockError(std::io::Error),
+    #[error("Checking for a stale lock over SSH resulted in a bad exit code: {0:?}")]
+    SSHCheckLockExitError(Option<i32>),
+    #[error("The lock `{0}` is left over from an earlier deployment, set `clearStaleLock` to remove it before deploying")]
+    StaleLock(String),
+    #[error("Failed to check the temporary path over SSH: {0}")]
+    SSHCheckTempPathError(std::io::Error),
+    #[error("Failed to check whether the node confirmed by itself over SSH: {0}")]
+    SSHSelfConfirmError(std::io::Error),
+    #[error(
+        "Checking whether the node confirmed by itself over SSH resulted in a bad exit code: {0:?}"
+    )]
+    SSHSelfConfirmExitError(Option<i32>),
+    #[error("The node did not confirm by itself with `selfConfirmCommand` before the confirm timeout elapsed")]
+    SelfConfirmTimeout,
+    #[error("Checking the temporary path over SSH resulted in a bad exit code: {0:?}")]
+    SSHCheckTempPathExitError(Option<i32>),
+    #[error("The temporary path `{0}` is not a directory the profile user can write to on the node, set `tempPath` to one it can")]
+    TempPathUnwritable(String),
+    #[error("Failed to run closure verification command over SSH: {0}")]
+    SSHVerifyError(std::io::Error),
+    #[error(
+        "Verifying the closure on the node failed, it may have been corrupted while copying: {0:?}"
+    )]
+    ClosureCorrupt(Option<i32>),
+
+    #[error("Failed to spawn activation command over SSH: {0}")]
+    SSHSpawnActivateError(std::io::Error),
+
+    #[error("Failed to run activation command over SSH: {0}")]
+    SSHActivateError(std::io::Error),
+    #[error(
+        "Activating over SSH resulted in a bad exit code: {code:?}{}",
+        stderr_snippet(stderr)
+    )]
+    SSHActivateExitError { code: Option<i32>, stderr: String },
+
+    #[error("Failed to run wait command over SSH: {0}")]
+    SSHWaitError(std::io::Error),
+    #[error("Waiting over SSH resulted in a bad exit code: {0:?}")]
+    SSHWaitExitError(Option<i32>),
+
+    #[error("Error confirming deployment: {0}")]
+    ConfirmError(#[from] ConfirmProfileError),
+
+    #[error("Failed to run command for reading the profile over SSH: {0}")]
+    SSHReadProfileError(std::io::Error),
+    #[error("Reading the profile over SSH resulted in a bad exit code: {0:?}")]
+    SSHReadProfileExitError(Option<i32>),
+    #[error("Error converting the profile path to utf8: {0}")]
+    ReadProfileUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Profile was expected to point to `{0}` after deployment, but points to `{1}`")]
+    ProfileMismatch(String, String),
+
+    #[error("Failed to run command for checking units over SSH: {0}")]
+    SSHUnitStatesError(std::io::Error),
+    #[error("Checking units over SSH resulted in a bad exit code: {0:?}")]
+    SSHUnitStatesExitError(Option<i32>),
+    #[error("Error converting the states of units to utf8: {0}")]
+    UnitStatesUtf8(std::string::FromUtf8Error),
+    #[error("Could not parse the states of units")]
+    ParseUnitStates,
+    #[error("Unit `{0}` is {1} after activation, instead of active")]
+    UnitNotActive(String, String),
+    #[error("Unit `{0}` was not restarted by activation")]
+    UnitNotRestarted(String),
+
+    #[error("Failed to run health check command over SSH: {0}")]
+    SSHHealthCheckError(std::io::Error),
+    #[error("Health check failed with exit code: {0:?}")]
+    HealthCheckFailed(Option<i32>),
+    #[error("Health check did not finish within {0}s")]
+    HealthCheckTimeout(u16),
+    #[error("`healthCheckTimeout` has to be at least one second")]
+    ZeroHealthCheckTimeout,
+    #[error("The `healthCheckTimeout` of {0}s does not fit into the confirm timeout of {1}s, the node would roll back before the health check ends")]
+    HealthCheckTooLong(u16, u16),
+
+    #[error("`confirmTimeout` is {0}s, but has to be between {min}s and {max}s: the node would roll back before it could be confirmed, or stay unconfirmed for too long", min = CONFIRM_TIMEOUT_RANGE.start(), max = CONFIRM_TIMEOUT_RANGE.end())]
+    InvalidConfirmTimeout(u16),
+    #[error("The `stabilization` interval has to be at least one second")]
+    StabilizationInterval,
+    #[error("The `stabilization` window of {0}s does not fit into the confirm timeout of {1}s, the node would roll back before it ends")]
+    StabilizationTooLong(u16, u16),
+    #[error("Failed to run stabilization command over SSH: {0}")]
+    SSHStabilizationError(std::io::Error),
+    #[error("Stabilization command failed after {0}s with exit code: {1:?}")]
+    StabilizationFailed(u64, Option<i32>),
+
+    #[error("Activation did not finish within the activation timeout of {0}s")]
+    ActivationTimeout(u16),
+    #[error("Waiting for the activation did not finish within the wait timeout of {0}s")]
+    WaitTimeout(u16),
+    #[error("Deploying was cancelled")]
+    Cancelled,
+    #[error("Deploying was not confirmed for the node")]
+    Aborted,
+    #[error("Failed to run `{0}`: {1}")]
+    HookError(&'static str, std::io::Error),
+    #[error("`{0}` resulted in a bad exit code: {1:?}")]
+    HookFailed(&'static str, Option<i32>),
+    #[error("Failed to upload `{0}` to the node: {1}")]
+    UploadError(String, std::io::Error),
+    #[error("Uploading `{0}` to the node resulted in a bad exit code: {1:?}")]
+    UploadExitError(String, Option<i32>),
+    #[error("Invalid mode `{1}` for uploading `{0}`, expected octal permissions like `0600`")]
+    InvalidUploadMode(String, String),
+    #[error("Failed to ask for confirmation: {0}")]
+    PromptError(std::io::Error),
+    #[error("Profile `{0}` uses the lock file `{1}` of profile `{2}` on the same node, so they can't be deployed concurrently")]
+    LockPathConflict(String, String, String),
+    #[error("Invalid profile dependencies: {0}")]
+    ProfileDependencies(crate::graph::DependencyError),
+
+    #[error("{0}, the profile was rolled back")]
+    RolledBack(Box<DeployProfileError>),
+}
+
+impl DeployProfileError {
+    /// If the node went back to the previous profile (or will, with magic rollback) because of this error
+    pub fn rolled_back(&self) -> bool {
+        matches!(self, DeployProfileError::RolledBack(_))
+    }
+
+    fn into_rolled_back(self) -> DeployProfileError {
+        match self {
+            DeployProfileError::RolledBack(_) => self,
+            x => DeployProfileError::RolledBack(Box::new(x)),
+        }
+    }
+}
+
+/// Waits for `activation`, failing with `ActivationTimeout` once `timeout` seconds have passed
+async fn with_activation_timeout<T>(
+    timeout: Option<u16>,
+    activation: impl std::future::Future<Output = Result<T, DeployProfileError>>,
+) -> Result<T, DeployProfileError> {
+    let timeout = match timeout {
+        Some(x) => x,
+        None => return activation.await,
+    };
+
+    match tokio::time::timeout(Duration::from_secs(timeout as u64), activation).await {
+        Ok(x) => x,
+        Err(_) => Err(DeployProfileError::ActivationTimeout(timeout)),
+    }
+}
+
+#[tokio::test]
+async fn test_with_activation_timeout() {
+    let hung = futures_util::future::pending::<Result<(), DeployProfileError>>();
+    assert!(matches!(
+        with_activation_timeout(Some(0), hung).await,
+        Err(DeployProfileError::ActivationTimeout(0))
+    ));
+
+    let done = async { Ok::<_, DeployProfileError>(()) };
+    assert!(with_activation_timeout(Some(10), done).await.is_ok());
+
+    let no_timeout = async { Ok::<_, DeployProfileError>(()) };
+    assert!(with_activation_timeout(None, no_timeout).await.is_ok());
+}
+
+/// The error of the activation if it already failed, rather than `wait_err`. Waiting fails when
+/// the activation does, so its error only tells that (and not why) if the two race.
+fn prefer_activation_error(
+    wait_err: DeployProfileError,
+    recv_activate: &mut tokio::sync::oneshot::Receiver<DeployProfileError>,
+) -> DeployProfileError {
+    match recv_activate.try_recv() {
+        Ok(err) => {
+            debug!("Waiting failed after the activation did: {}", wait_err);
+            err
+        }
+        Err(_) => wait_err,
+    }
+}
+
+/// Appends what a command on the node wrote to standard error last to an error message
+fn stderr_snippet(stderr: &str) -> String {
+    match stderr.trim_end() {
+        "" => String::new(),
+        x => format!(", it ended with:\n{}", x),
+    }
+}
+
+#[test]
+fn test_activate_exit_error_display() {
+    assert_eq!(
+        DeployProfileError::SSHActivateExitError {
+            code: Some(1),
+            stderr: "Activating...\nerror: unit nginx.service failed\n".to_string(),
+        }
+        .to_string(),
+        "Activating over SSH resulted in a bad exit code: Some(1), it ended with:\nActivating...\nerror: unit nginx.service failed"
+    );
+    assert_eq!(
+        DeployProfileError::SSHActivateExitError {
+            code: Some(255),
+            stderr: String::new(),
+        }
+        .to_string(),
+        "Activating over SSH resulted in a bad exit code: Some(255)"
+    );
+}
+
+#[test]
+fn test_prefer_activation_error() {
+    let (send_activate, mut recv_activate) = tokio::sync::oneshot::channel();
+    send_activate
+        .send(DeployProfileError::SSHActivateExitError {
+            code: Some(1),
+            stderr: String::new(),
+        })
+        .unwrap();
+
+    assert!(matches!(
+        prefer_activation_error(
+            DeployProfileError::SSHWaitExitError(Some(1)),
+            &mut recv_activate
+        ),
+        DeployProfileError::SSHActivateExitError { code: Some(1), .. }
+    ));
+
+    // Dropping the sender is how a successful activation reports
+    let (send_activate, mut recv_activate) = tokio::sync::oneshot::channel();
+    drop(send_activate);
+
+    assert!(matches!(
+        prefer_activation_error(
+            DeployProfileError::SSHWaitExitError(Some(1)),
+            &mut recv_activate
+        ),
+        DeployProfileError::SSHWaitExitError(Some(1))
+    ));
+}
+
+/// Checks that the units in `verifyUnits` are active (and were restarted since `activate_started`, with `verifyUnitsRestarted`)
+async fn verify_units(
+    deploy_data: &super::DeployData<'_>,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    activate_started: Instant,
+) -> Result<(), DeployProfileError> {
+    let units = &deploy_data.merged_settings.verify_units;
+
+    let unit_states_command = build_unit_states_command(units);
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Checking units on the node: {}",
+        unit_states_command
+    );
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(unit_states_command);
+
+    let output = runner
+        .output(&argv, node_options(deploy_data))
+        .await
+        .map_err(DeployProfileError::SSHUnitStatesError)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(DeployProfileError::SSHUnitStatesExitError(a)),
+    };
+
+    let output = String::from_utf8(output.stdout).map_err(DeployProfileError::UnitStatesUtf8)?;
+    let (uptime, states) = parse_unit_states(&output).ok_or(DeployProfileError::ParseUnitStates)?;
+
+    // Comparing against the uptime of the node avoids depending on its clock agreeing with ours
+    let since = match deploy_data.merged_settings.verify_units_restarted {
+        Some(true) => Some(uptime - activate_started.elapsed().as_secs_f64()),
+        _ => None,
+    };
+
+    check_unit_states(units, &states, since)
+}
+
+/// How long `healthCheckCommand` may run, unless `healthCheckTimeout` is set. This fits into the
+/// default `confirmTimeout`.
+const DEFAULT_HEALTH_CHECK_TIMEOUT: u16 = 20;
+
+/// Rejects a `healthCheckTimeout` which can't be met, or with magic rollback, which runs past the
+/// `confirm_timeout`
+fn check_health_check_timeout(
+    timeout: u16,
+    confirm_timeout: Option<u16>,
+) -> Result<(), DeployProfileError> {
+    match confirm_timeout {
+        _ if timeout == 0 => Err(DeployProfileError::ZeroHealthCheckTimeout),
+        Some(confirm_timeout) if timeout >= confirm_timeout => Err(
+            DeployProfileError::HealthCheckTooLong(timeout, confirm_timeout),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_check_health_check_timeout() {
+    assert!(check_health_check_timeout(DEFAULT_HEALTH_CHECK_TIMEOUT, Some(30)).is_ok());
+    assert!(check_health_check_timeout(60, None).is_ok());
+    assert!(matches!(
+        check_health_check_timeout(60, Some(30)),
+        Err(DeployProfileError::HealthCheckTooLong(60, 30))
+    ));
+    assert!(matches!(
+        check_health_check_timeout(0, None),
+        Err(DeployProfileError::ZeroHealthCheckTimeout)
+    ));
+}
+
+/// Runs `command` on the node, which has to succeed within `healthCheckTimeout`
+async fn run_health_check(
+    deploy_data: &super::DeployData<'_>,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    command: &str,
+) -> Result<(), DeployProfileError> {
+    let timeout = deploy_data
+        .merged_settings
+        .health_check_timeout
+        .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT);
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Running health check on the node: {}",
+        command
+    );
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(command.to_string());
+
+    let status = runner.status(
+        &argv,
+        RunOptions {
+            null_stdin: true,
+            kill_on_drop: true,
+            ..node_options(deploy_data)
+        },
+    );
+
+    let status = tokio::time::timeout(Duration::from_secs(timeout.into()), status)
+        .await
+        .map_err(|_| DeployProfileError::HealthCheckTimeout(timeout))?
+        .map_err(DeployProfileError::SSHHealthCheckError)?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(DeployProfileError::HealthCheckFailed(a)),
+    }
+}
+
+/// The seconds `confirmTimeout` may be
+const CONFIRM_TIMEOUT_RANGE: std::ops::RangeInclusive<u16> = 1..=3600;
+
+fn check_confirm_timeout(confirm_timeout: u16) -> Result<(), DeployProfileError> {
+    match CONFIRM_TIMEOUT_RANGE.contains(&confirm_timeout) {
+        true => Ok(()),
+        false => Err(DeployProfileError::InvalidConfirmTimeout(confirm_timeout)),
+    }
+}
+
+#[test]
+fn test_check_confirm_timeout() {
+    assert!(check_confirm_timeout(30).is_ok());
+    assert!(check_confirm_timeout(1).is_ok());
+    assert!(check_confirm_timeout(3600).is_ok());
+    assert!(matches!(
+        check_confirm_timeout(0),
+        Err(DeployProfileError::InvalidConfirmTimeout(0))
+    ));
+    assert!(matches!(
+        check_confirm_timeout(7200),
+        Err(DeployProfileError::InvalidConfirmTimeout(7200))
+    ));
+    assert_eq!(
+        DeployProfileError::InvalidConfirmTimeout(0).to_string(),
+        "`confirmTimeout` is 0s, but has to be between 1s and 3600s: the node would roll back before it could be confirmed, or stay unconfirmed for too long"
+    );
+}
+
+/// Rejects a `stabilization` which can't succeed before the node rolls back by itself
+fn check_stabilization(
+    stabilization: &crate::data::Stabilization,
+    confirm_timeout: u16,
+) -> Result<(), DeployProfileError> {
+    if stabilization.interval == 0 {
+        return Err(DeployProfileError::StabilizationInterval);
+    }
+
+    if stabilization.window >= confirm_timeout {
+        return Err(DeployProfileError::StabilizationTooLong(
+            stabilization.window,
+            confirm_timeout,
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_stabilization() {
+    let stabilization = |interval, window| crate::data::Stabilization {
+        command: "systemctl is-active nginx".to_string(),
+        interval,
+        window,
+    };
+
+    assert!(check_stabilization(&stabilization(5, 20), 30).is_ok());
+    assert!(check_stabilization(&stabilization(1, 0), 30).is_ok());
+    assert!(matches!(
+        check_stabilization(&stabilization(5, 30), 30),
+        Err(DeployProfileError::StabilizationTooLong(30, 30))
+    ));
+    assert!(matches!(
+        check_stabilization(&stabilization(5, 60), 30),
+        Err(DeployProfileError::StabilizationTooLong(60, 30))
+    ));
+    assert!(matches!(
+        check_stabilization(&stabilization(0, 20), 30),
+        Err(DeployProfileError::StabilizationInterval)
+    ));
+}
+
+/// Runs the `stabilization` command on the node every interval until it succeeded for the whole
+/// window, failing as soon as it does
+async fn stabilize(
+    deploy_data: &super::DeployData<'_>,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    stabilization: &crate::data::Stabilization,
+) -> Result<(), DeployProfileError> {
+    let started = Instant::now();
+    let mut window =
+        crate::checks::HealthyWindow::new(Duration::from_secs(stabilization.window.into()));
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(stabilization.command.clone());
+
+    loop {
+        node_log!(
+            debug,
+            deploy_data,
+            "Running stabilization command on the node: {}",
+            stabilization.command
+        );
+
+        let status = runner
+            .status(
+                &argv,
+                RunOptions {
+                    null_stdin: true,
+                    kill_on_drop: true,
+                    ..node_options(deploy_data)
+                },
+            )
+            .await
+            .map_err(DeployProfileError::SSHStabilizationError)?;
+
+        if !status.success() {
+            return Err(DeployProfileError::StabilizationFailed(
+                started.elapsed().as_secs(),
+                status.code(),
+            ));
+        }
+
+        if window.record(true, Instant::now()) {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_secs(stabilization.interval.into())).await;
+    }
+}
+
+/// Checks that the profile on the node resolves to the deployed closure, whatever the activation script reported
+async fn check_profile_link(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+) -> Result<Option<u64>, DeployProfileError> {
+    let read_profile_command = build_read_profile_command(
+        &deploy_defs.profile_path,
+        deploy_data
+            .merged_settings
+            .current_closure_command
+            .as_deref(),
+    );
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Checking the deployed profile: {}",
+        read_profile_command
+    );
+
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(read_profile_command);
+
+    let output = runner
+        .output(&argv, node_options(deploy_data))
+        .await
+        .map_err(DeployProfileError::SSHReadProfileError)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(DeployProfileError::SSHReadProfileExitError(a)),
+    };
+
+    let output = String::from_utf8(output.stdout)?;
+    let mut lines = output.lines();
+    let generation = lines.next().and_then(parse_generation);
+    let actual = lines.next().unwrap_or("").trim_end();
+    let expected = deploy_data
+        .profile
+        .profile_settings
+        .path
+        .trim_end_matches('/');
+
+    if actual != expected {
+        return Err(DeployProfileError::ProfileMismatch(
+            expected.to_string(),
+            actual.to_string(),
+        ));
+    }
+
+    Ok(generation)
+}
+
+/// The start of the command line running a command on the node, which is appended to it: the
+/// SSH binary with the options of the node, or a local shell. `ssh_command` is taken as the
+/// program as a whole, arguments for it have to be in `ssh_opts`.
+pub(crate) fn make_node_argv(
+    local: bool,
+    ssh_command: &str,
+    ssh_addr: &str,
+    ssh_opts: &[String],
+) -> Vec<String> {
+    match local {
+        true => vec!["sh".to_string(), "-c".to_string()],
+        false => std::iter::once(ssh_command.to_string())
+            .chain(std::iter::once(ssh_addr.to_string()))
+            .chain(ssh_opts.iter().cloned())
+            .collect(),
+    }
+}
+
+#[test]
+fn test_make_node_argv_ssh_command() {
+    let opts = vec!["-v".to_string()];
+
+    assert_eq!(
+        make_node_argv(false, "/nix/store/blah-openssh/bin/ssh", "root@host", &opts),
+        vec!["/nix/store/blah-openssh/bin/ssh", "root@host", "-v"]
+    );
+    // Not split, so this fails to run rather than passing `-F /dev/null` before the address
+    assert_eq!(
+        make_node_argv(false, "ssh -F /dev/null", "root@host", &opts),
+        vec!["ssh -F /dev/null", "root@host", "-v"]
+    );
+    assert_eq!(
+        make_node_argv(true, "/nix/store/blah-openssh/bin/ssh", "root@host", &opts),
+        vec!["sh", "-c"]
+    );
+}
+
+/// The start of the command line running a command on `deploy_data`, see `make_node_argv`
+pub(crate) fn node_argv(deploy_data: &super::DeployData<'_>, ssh_addr: &str) -> Vec<String> {
+    make_node_argv(
+        deploy_data.is_local(),
+        deploy_data.ssh_command(),
+        ssh_addr,
+        &deploy_data.merged_settings.ssh_opts,
+    )
+}
+
+/// How a command line made by `node_argv` is run, unless it needs more
+fn node_options(deploy_data: &super::DeployData<'_>) -> RunOptions {
+    RunOptions {
+        env: deploy_data.ssh_env(),
+        stdout_to_stderr: deploy_data.cmd_overrides.log_format == super::LogFormat::Json,
+        ..Default::default()
+    }
+}
+
+/// How commands switching users with `sudo` are run, which are given `sudoPassword` on
+/// standard input if it is set
+fn sudo_options(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> RunOptions {
+    RunOptions {
+        stdin_secret: deploy_defs.sudo_password.clone(),
+        ..node_options(deploy_data)
+    }
+}
+
+/// The exit code of `ssh` when it fails by itself, like when connecting
+const SSH_ERROR_EXIT: i32 = 255;
+
+/// If running a command over SSH failed to connect to the node, rather than running the command.
+/// An exit code of 255 could also come from the command, so this is only safe for commands which
+/// can be repeated.
+fn is_connection_failure(result: &std::io::Result<std::process::ExitStatus>) -> bool {
+    use std::io::ErrorKind;
+
+    match result {
+        Ok(status) => status.code() == Some(SSH_ERROR_EXIT),
+        Err(err) => matches!(
+            err.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::TimedOut
+        ),
+    }
+}
+
+#[test]
+fn test_is_connection_failure() {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::process::ExitStatusExt;
+
+    let exit = |code: i32| Ok(std::process::ExitStatus::from_raw(code << 8));
+
+    assert!(is_connection_failure(&exit(255)));
+    assert!(!is_connection_failure(&exit(0)));
+    assert!(!is_connection_failure(&exit(1)));
+    assert!(is_connection_failure(&Err(Error::from(
+        ErrorKind::ConnectionRefused
+    ))));
+    assert!(is_connection_failure(&Err(Error::from(
+        ErrorKind::TimedOut
+    ))));
+    assert!(!is_connection_failure(&Err(Error::from(
+        ErrorKind::NotFound
+    ))));
+}
+
+/// Runs a command on the node made by `run`, which is retried with `sshConnectRetries` while it
+/// fails to connect
+async fn with_ssh_retries<F, Fut>(
+    deploy_data: &super::DeployData<'_>,
+    action: &str,
+    run: F,
+) -> std::io::Result<std::process::ExitStatus>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<std::process::ExitStatus>>,
+{
+    let retries = deploy_data.merged_settings.ssh_connect_retries.unwrap_or(0);
+
+    with_connect_retries(deploy_data, action, retries, None, run).await
+}
+
+/// Like `with_ssh_retries`, retrying up to `retries` times, but not once the next attempt would
+/// start after `deadline`
+async fn with_connect_retries<F, Fut>(
+    deploy_data: &super::DeployData<'_>,
+    action: &str,
+    retries: u8,
+    deadline: Option<Instant>,
+    mut run: F,
+) -> std::io::Result<std::process::ExitStatus>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<std::process::ExitStatus>>,
+{
+    let retries = match deploy_data.is_local() {
+        true => 0,
+        false => retries,
+    };
+
+    let mut attempt = 0;
+
+    loop {
+        let result = run().await;
+
+        if attempt >= retries || !is_connection_failure(&result) {
+            return result;
+        }
+
+        let delay = crate::push::retry_delay(attempt);
+
+        if let Some(deadline) = deadline {
+            if Instant::now() + delay >= deadline {
+                node_log!(
+                    debug,
+                    deploy_data,
+                    "Not retrying {}, the next attempt would be too late",
+                    action
+                );
+                return result;
+            }
+        }
+
+        node_log!(
+            warn,
+            deploy_data,
+            "Failed to connect to node `{}` for {}, retrying in {}s ({}/{})",
+            deploy_data.node_name,
+            action,
+            delay.as_secs(),
+            attempt + 1,
+            retries
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// A lifecycle event of deploying a profile, as a single line of JSON
+fn make_event_line(
+    event: &str,
+    node_name: &str,
+    profile_name: &str,
+    timestamp: u64,
+    error: Option<&str>,
+) -> String {
+    let mut line = serde_json::json!({
+        "event": event,
+        "node_name": node_name,
+        "profile_name": profile_name,
+        "timestamp": timestamp,
+    });
+
+    if let Some(error) = error {
+        line["error"] = error.into();
+    }
+
+    line.to_string()
+}
+
+#[test]
+fn test_make_event_line() {
+    assert_eq!(
+        make_event_line("activation_started", "example", "system", 1600000000, None),
+        r#"{"event":"activation_started","node_name":"example","profile_name":"system","timestamp":1600000000}"#
+    );
+
+    let line = make_event_line(
+        "error",
+        "example",
+        "sy\"stem",
+        1600000000,
+        Some("failed:\nsomehow"),
+    );
+    assert!(!line.contains('\n'));
+
+    let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(parsed["profile_name"], "sy\"stem");
+    assert_eq!(parsed["error"], "failed:\nsomehow");
+}
+
+/// With `--log-format json`, prints an event of deploying `deploy_data` to standard output, where
+/// it isn't mixed with the logs
+fn emit_event(deploy_data: &super::DeployData<'_>, event: &str, error: Option<&str>) {
+    if deploy_data.cmd_overrides.log_format != super::LogFormat::Json {
+        return;
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    println!(
+        "{}",
+        make_event_line(
+            event,
+            deploy_data.node_name,
+            deploy_data.profile_name,
+            timestamp,
+            error
+        )
+    );
+}
+
+/// With `--explain`, narrates a decision made while deploying `deploy_data`
+fn explain(deploy_data: &super::DeployData<'_>, explanation: &str) {
+    if deploy_data.explain {
+        node_log!(info, deploy_data, "{}", explanation);
+    }
+}
+
+/// How commands whose output `streamLogs` is about are run
+fn streamed_options(deploy_data: &super::DeployData<'_>) -> RunOptions {
+    RunOptions {
+        stream_prefix: match deploy_data.merged_settings.stream_logs {
+            Some(true) => Some(log_prefix(deploy_data)),
+            _ => None,
+        },
+        ..node_options(deploy_data)
+    }
+}
+
+/// How the activation command is run, keeping the end of its output for when it fails
+fn activate_options(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> RunOptions {
+    RunOptions {
+        capture_stderr: true,
+        stdin_secret: deploy_defs.sudo_password.clone(),
+        ..streamed_options(deploy_data)
+    }
+}
+
+/// Waits for the node to remove the lock by itself, which it does once `selfConfirmCommand`
+/// succeeded (or when it rolls back). Fails once `deadline` would pass before the next check.
+async fn wait_for_self_confirm(
+    deploy_data: &super::DeployData<'_>,
+    runner: &dyn CommandRunner,
+    ssh_addr: &str,
+    deadline: Option<Instant>,
+) -> Result<(), DeployProfileError> {
+    let mut argv = node_argv(deploy_data, ssh_addr);
+    argv.push(build_check_lock_command(&profile_lock_path(deploy_data)));
+
+    loop {
+        let output = runner
+            .output(&argv, node_options(deploy_data))
+            .await
+            .map_err(DeployProfileError::SSHSelfConfirmError)?;
+
+        match output.status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::SSHSelfConfirmExitError(a)),
+        };
+
+        if String::from_utf8_lossy(&output.stdout).trim() != "exists" {
+            return Ok(());
+        }
+
+        if let Some(deadline) = deadline {
+            if Instant::now() + crate::checks::CHECK_INTERVAL >= deadline {
+                return Err(DeployProfileError::SelfConfirmTimeout);
+            }
+        }
+
+        tokio::time::sleep(crate::checks::CHECK_INTERVAL).await;
+    }
+}
+
+/// A profile which has been activated and is waiting for confirmation. With magic rollback,
+/// dropping this without calling [`PendingConfirmation::confirm`] leaves the canary file in place,
+/// so the node rolls back by itself once `confirm_timeout` elapses.
+pub struct PendingConfirmation<'a> {
+    deploy_data: &'a super::DeployData<'a>,
+    deploy_defs: &'a super::DeployDefs,
+    runner: &'a dyn CommandRunner,
+    temp_path: Cow<'a, str>,
+    ssh_addr: String,
+    // Only present with magic rollback, resolves once the activation process exits
+    recv_activated: Option<tokio::sync::oneshot::Receiver<()>>,
+    // Ends as an error unless the confirmation and the profile check succeed
+    deploy_span: Option<Span>,
+    // When the node is expected to roll back by itself, as seen from here
+    deadline: Option<Instant>,
+    activate_started: Instant,
+    timer: PhaseTimer,
+}
+
+impl<'a> PendingConfirmation<'a> {
+    /// When the node rolls back unless confirmed, `None` without magic rollback. This is an
+    /// estimate taken once waiting finished, so it is somewhat later than the actual one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// If the activation rolls back unless confirmed (by [`PendingConfirmation::confirm`], or the node itself)
+    pub fn needs_confirmation(&self) -> bool {
+        self.recv_activated.is_some()
+    }
+
+    /// Leaves confirming the activation to something else, which has to remove the lock on the node
+    /// before the confirm timeout elapses, otherwise it rolls back
+    pub fn defer(mut self) {
+        if self.recv_activated.take().is_some() {
+            node_log!(
+                info,
+                self.deploy_data,
+                "Leaving confirmation of profile `{}` for node `{}` to something else, remove `{}` on the node to confirm it",
+                self.deploy_data.profile_name,
+                self.deploy_data.node_name,
+                profile_lock_path(self.deploy_data)
+            );
+        }
+
+        if let Some(mut deploy_span) = self.deploy_span.take() {
+            deploy_span.set_attribute("deploy.outcome", "deferred");
+            deploy_span.end(SpanStatus::Ok);
+        }
+    }
+
+    /// Checks `verifyUnits`, confirms the activation if magic rollback is enabled, then checks that the profile points to the closure
+    /// and runs `onConfirmCommand`
+    pub async fn confirm(mut self) -> Result<(), DeployProfileError> {
+        // With `selfConfirmCommand` the node confirms by itself, the profile shows whether it did
+        let self_confirmed_generation = match (
+            self.recv_activated.take(),
+            &self.deploy_data.merged_settings.self_confirm_command,
+        ) {
+            (Some(recv_activated), Some(_)) => {
+                explain(
+                    self.deploy_data,
+                    "`selfConfirmCommand` is set, so instead of confirming I wait for the node to run it and confirm by itself",
+                );
+
+                node_log!(
+                    info,
+                    self.deploy_data,
+                    "Waiting for node `{}` to confirm profile `{}` by itself",
+                    self.deploy_data.node_name,
+                    self.deploy_data.profile_name
+                );
+
+                // This only tells that the node started waiting for confirmation
+                recv_activated.await.ok();
+
+                wait_for_self_confirm(self.deploy_data, self.runner, &self.ssh_addr, self.deadline)
+                    .await
+                    .map_err(|err| err.into_rolled_back())?;
+
+                let checked = check_profile_link(
+                    self.deploy_data,
+                    self.deploy_defs,
+                    self.runner,
+                    &self.ssh_addr,
+                )
+                .await;
+
+                Some(checked.map_err(|err| match err {
+                    DeployProfileError::ProfileMismatch(..) => err.into_rolled_back(),
+                    err => err,
+                })?)
+            }
+            (recv_activated, _) => {
+                self.recv_activated = recv_activated;
+                None
+            }
+        };
+
+        // Failing this leaves the activation unconfirmed, so it rolls back
+        if !self.deploy_data.merged_settings.verify_units.is_empty() {
+            explain(
+                self.deploy_data,
+                &format!(
+                    "`verifyUnits` is set, so before confirming I check that {} are active{}",
+                    self.deploy_data.merged_settings.verify_units.join(", "),
+                    match self.deploy_data.merged_settings.verify_units_restarted {
+                        Some(true) => " and were restarted by this activation",
+                        _ => "",
+                    }
+                ),
+            );
+
+            let verified = verify_units(
+                self.deploy_data,
+                self.runner,
+                &self.ssh_addr,
+                self.activate_started,
+            )
+            .await;
+
+            if let Err(err) = verified {
+                return Err(match self.recv_activated {
+                    Some(_) => err.into_rolled_back(),
+                    None => err,
+                });
+            }
+        }
+
+        // Like `verifyUnits`, this runs before confirming so that a failure rolls back
+        if let Some(health_check_command) = &self.deploy_data.merged_settings.health_check_command {
+            explain(
+                self.deploy_data,
+                "`healthCheckCommand` is set, so before confirming I run it on the node",
+            );
+
+            let checked = run_health_check(
+                self.deploy_data,
+                self.runner,
+                &self.ssh_addr,
+                health_check_command,
+            )
+            .await;
+
+            if let Err(err) = checked {
+                return Err(match self.recv_activated {
+                    Some(_) => err.into_rolled_back(),
+                    None => err,
+                });
+            }
+        }
+
+        // Only an activation which rolls back unless confirmed can be held back until it is stable
+        if let (Some(_), Some(stabilization)) = (
+            &self.recv_activated,
+            &self.deploy_data.merged_settings.stabilization,
+        ) {
+            explain(
+                self.deploy_data,
+                &format!(
+                    "`stabilization` is set, so before confirming I run its command every {}s until it succeeded for {}s",
+                    stabilization.interval, stabilization.window
+                ),
+            );
+
+            stabilize(self.deploy_data, self.runner, &self.ssh_addr, stabilization)
+                .await
+                .map_err(|err| err.into_rolled_back())?;
+        }
+
+        if let Some(recv_activated) = self.recv_activated.take() {
+            node_log!(
+                info,
+                self.deploy_data,
+                "Attempting to confirm activation of profile `{}` for node `{}`",
+                self.deploy_data.profile_name,
+                self.deploy_data.node_name
+            );
+
+            if let Some(deadline) = self.deadline {
+                explain(
+                    self.deploy_data,
+                    &format!(
+                        "The node rolls back in about {}s unless it is confirmed, proceeding to confirm{}",
+                        deadline.saturating_duration_since(Instant::now()).as_secs(),
+                        match self.deploy_data.merged_settings.confirm_checks {
+                            Some(_) => " once `confirmChecks` pass",
+                            None => "",
+                        }
+                    ),
+                );
+            }
+
+            let confirm_span = Span::start("confirm", self.deploy_span.as_ref());
+
+            let c = confirm_profile(
+                self.deploy_data,
+                self.deploy_defs,
+                self.runner,
+                self.temp_path.clone(),
+                &self.ssh_addr,
+                self.deadline,
+            )
+            .await;
+            recv_activated.await.ok();
+            c.map_err(|err| DeployProfileError::from(err).into_rolled_back())?;
+
+            confirm_span.end(SpanStatus::Ok);
+        }
+
+        let generation = match self_confirmed_generation {
+            Some(x) => x,
+            None => {
+                explain(
+                    self.deploy_data,
+                    "Checking that the profile on the node points to the closure, in case something else changed it",
+                );
+
+                check_profile_link(
+                    self.deploy_data,
+                    self.deploy_defs,
+                    self.runner,
+                    &self.ssh_addr,
+                )
+                .await?
+            }
+        };
+
+        if let Some(on_confirm_command) = &self.deploy_data.merged_settings.on_confirm_command {
+            explain(
+                self.deploy_data,
+                "`onConfirmCommand` is set, so I run it locally now that the profile is live",
+            );
+
+            let command = make_on_confirm_command(
+                on_confirm_command,
+                self.deploy_data.node_name,
+                self.deploy_data.profile_name,
+                &self.deploy_data.profile.profile_settings.path,
+                generation,
+            );
+
+            run_on_confirm_command(&command, self.deploy_data).await;
+        }
+
+        if let Some(mut deploy_span) = self.deploy_span.take() {
+            deploy_span.set_attribute("deploy.outcome", "success");
+            deploy_span.end(SpanStatus::Ok);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for PendingConfirmation<'a> {
+    fn drop(&mut self) {
+        if self.recv_activated.is_some() {
+            node_log!(
+                warn,
+                self.deploy_data,
+                "Profile `{}` for node `{}` was not confirmed, it will roll back once its confirm timeout elapses",
+                self.deploy_data.profile_name, self.deploy_data.node_name
+            );
+        }
+    }
+}
+
+/// A connection shared by every SSH command of a run to a node (`sshMultiplexing`), which is
+/// closed when this is dropped
+#[derive(Debug)]
+pub struct SshMaster {
+    ssh_addr: String,
+    ssh_command: String,
+    ssh_opts: Vec<String>,
+    ssh_env: Vec<(String, String)>,
+}
+
+impl SshMaster {
+    /// Returns one master per shared connection used by `parts`
+    pub fn for_profiles<'a>(
+        parts: impl IntoIterator<Item = (&'a super::DeployData<'a>, &'a super::DeployDefs)>,
+    ) -> Vec<SshMaster> {
+        let mut masters: Vec<SshMaster> = Vec::new();
+
+        for (deploy_data, deploy_defs) in parts {
+            if deploy_data.ssh_control_path.is_none() {
+                continue;
+            }
+
+            let ssh_addr = deploy_data.ssh_addr(deploy_defs);
+            let ssh_command = deploy_data.ssh_command();
+            let ssh_opts = &deploy_data.merged_settings.ssh_opts;
+            let ssh_env = deploy_data.ssh_env();
+
+            if !masters.iter().any(|x| {
+                x.ssh_addr == ssh_addr
+                    && x.ssh_command == ssh_command
+                    && &x.ssh_opts == ssh_opts
+                    && x.ssh_env == ssh_env
+            }) {
+                masters.push(SshMaster {
+                    ssh_addr,
+                    ssh_command: ssh_command.to_string(),
+                    ssh_opts: ssh_opts.clone(),
+                    ssh_env,
+                });
+            }
+        }
+
+        masters
+    }
+
+    /// Opens the connection in the background. Without this the first command run on the node
+    /// would open it, and keep its standard error open for as long as the connection lasts.
+    pub async fn open(&self) {
+        debug!("Opening a shared SSH connection to {}", self.ssh_addr);
+
+        let status = Command::new(&self.ssh_command)
+            .arg(&self.ssh_addr)
+            .args(&self.ssh_opts)
+            .envs(self.ssh_env.clone())
+            .arg("true")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await;
+
+        match status {
+            Ok(x) if x.success() => (),
+            // Each command then reports the actual problem when connecting by itself
+            _ => warn!(
+                "Failed to open a shared SSH connection to {}",
+                self.ssh_addr
+            ),
+        }
+    }
+}
+
+impl Drop for SshMaster {
+    fn drop(&mut self) {
+        debug!("Closing the shared SSH connection to {}", self.ssh_addr);
+
+        // This fails harmlessly when the connection was never opened, or already timed out
+        let _ = std::process::Command::new(&self.ssh_command)
+            .arg(&self.ssh_addr)
+            .args(&self.ssh_opts)
+            .envs(self.ssh_env.clone())
+            .arg("-O")
+            .arg("exit")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+}
+
+fn make_activate_command(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &str,
+    confirm_timeout: u16,
+    rollback: RollbackStrategy,
+    snapshot: Option<&SnapshotCommands>,
+) -> String {
+    let argv = build_activate_command(ActivateCommandData {
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        rollback,
+        temp_path,
+        confirm_timeout,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        umask: deploy_data.merged_settings.umask.as_deref(),
+        lock_file_name: deploy_data.merged_settings.lock_file_name.as_deref(),
+        working_dir: deploy_data.merged_settings.working_dir.as_deref(),
+        snapshot,
+        self_confirm_command: deploy_data
+            .merged_settings
+            .self_confirm_command
+            .as_deref()
+            .filter(|_| rollback == RollbackStrategy::Magic),
+        activation_env: deploy_data.merged_settings.activation_env.as_ref(),
+    });
+
+    remote_command(
+        &argv,
+        &deploy_defs.sudo,
+        deploy_data.merged_settings.working_dir.as_deref(),
+        deploy_data.merged_settings.working_dir_after_sudo == Some(true),
+    )
+}
+
+fn make_wait_command(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &str,
+) -> String {
+    let argv = build_wait_command(WaitCommandData {
+        closure: &deploy_data.profile.profile_settings.path,
+        temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        lock_file_name: deploy_data.merged_settings.lock_file_name.as_deref(),
+    });
+
+    // Waiting doesn't depend on the working directory
+    remote_command(&argv, &deploy_defs.sudo, None, false)
+}
+
+/// Quotes `arg` for a shell, unless it is safe without
+fn quote_arg(arg: &str) -> Cow<'_, str> {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c));
+
+    match safe {
+        true => arg.into(),
+        false => shell_escape(arg).into(),
+    }
+}
+
+#[test]
+fn test_quote_arg() {
+    assert_eq!(quote_arg("admin@example.com"), "admin@example.com");
+    assert_eq!(quote_arg("ControlPath=/tmp/x-%r"), "ControlPath=/tmp/x-%r");
+    assert_eq!(quote_arg("nc -X 5"), "'nc -X 5'");
+    assert_eq!(quote_arg(""), "''");
+}
+
+/// The commands deploying `deploy_data` runs over SSH, as lines which can be pasted into a shell.
+/// Steps which depend on the node (like measuring latency or checking units) are left out.
+fn make_dry_run_commands(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    rollback: RollbackStrategy,
+) -> Vec<(&'static str, String)> {
+    let temp_path = deploy_data
+        .merged_settings
+        .temp_path
+        .as_deref()
+        .unwrap_or("/tmp");
+
+    let ssh_addr = deploy_data.ssh_addr(deploy_defs);
+
+    let make_ssh_command = |command: &str| {
+        let mut argv = node_argv(deploy_data, &ssh_addr);
+        argv.push(command.to_string());
+
+        shell_join(&argv)
+    };
+
+    let snapshot = make_snapshot_commands(
+        &deploy_data.merged_settings,
+        &deploy_snapshot_name(deploy_data),
+    );
+
+    let mut commands = vec![(
+        "activate",
+        make_ssh_command(&make_activate_command(
+            deploy_data,
+            deploy_defs,
+            temp_path,
+            deploy_data.merged_settings.confirm_timeout.unwrap_or(30),
+            rollback,
+            snapshot.as_ref(),
+        )),
+    )];
+
+    if rollback == RollbackStrategy::Magic {
+        commands.push((
+            "wait",
+            make_ssh_command(&make_wait_command(deploy_data, deploy_defs, temp_path)),
+        ));
+
+        // The node confirms by itself otherwise
+        if deploy_data.merged_settings.self_confirm_command.is_none() {
+            let confirm_command = build_confirm_command(ConfirmCommandData {
+                sudo: &deploy_defs.sudo,
+                closure: &deploy_data.profile.profile_settings.path,
+                temp_path,
+                lock_file_name: deploy_data.merged_settings.lock_file_name.as_deref(),
+                confirm_command: deploy_data.merged_settings.confirm_command.as_deref(),
+            });
+
+            if let Some(check) = &deploy_data.merged_settings.confirm_checks {
+                for command in crate::checks::check_commands(check) {
+                    commands.push(("confirm_check", make_ssh_command(command)));
+                }
+            }
+
+            commands.push(("confirm", make_ssh_command(&confirm_command)));
+        }
+    }
+
+    commands
+}
+
+/// Asks on `output` whether to deploy to a node with `requireConfirmation`, reading the answer
+/// from `input`. Without a terminal this aborts, as nobody could answer.
+async fn ask_confirmation(
+    deploy_data: &super::DeployData<'_>,
+    interactive: bool,
+    input: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    output: &mut impl std::io::Write,
+) -> Result<(), DeployProfileError> {
+    use tokio::io::AsyncBufReadExt;
+
+    if !interactive {
+        node_log!(
+            error,
+            deploy_data,
+            "Node `{}` requires confirmation, but there is no terminal to ask on (pass `--yes` to deploy anyway)",
+            deploy_data.node_name
+        );
+        return Err(DeployProfileError::Aborted);
+    }
+
+    write!(
+        output,
+        "Node `{}` requires confirmation. Deploy profile `{}` ({})? [y/N] ",
+        deploy_data.node_name, deploy_data.profile_name, deploy_data.profile.profile_settings.path
+    )
+    .and_then(|_| output.flush())
+    .map_err(DeployProfileError::PromptError)?;
+
+    let mut s = String::new();
+    input
+        .read_line(&mut s)
+        .await
+        .map_err(DeployProfileError::PromptError)?;
+
+    match yn::yes(&s) {
+        true => Ok(()),
+        false => Err(DeployProfileError::Aborted),
+    }
+}
+
+#[tokio::test]
+async fn test_ask_confirmation() {
+    let node = crate::mock_node(serde_json::json!({
+        "requireConfirmation": true,
+    }));
+
+    let cmd_overrides = Default::default();
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+
+    let ask = |interactive, answer: &'static str| {
+        let deploy_data = &deploy_data;
+        async move {
+            let mut output = Vec::new();
+            let result = ask_confirmation(
+                deploy_data,
+                interactive,
+                &mut answer.as_bytes(),
+                &mut output,
+            )
+            .await;
+            (result, String::from_utf8(output).unwrap())
+        }
+    };
+
+    // Nothing is asked, or read, without a terminal
+    let (result, output) = ask(false, "yes\n").await;
+    assert!(matches!(result, Err(DeployProfileError::Aborted)));
+    assert_eq!(output, "");
+
+    let (result, output) = ask(true, "y\n").await;
+    assert!(result.is_ok());
+    assert!(output.contains("`example`") && output.contains("/nix/store/blah-system"));
+
+    assert!(matches!(
+        ask(true, "n\n").await.0,
+        Err(DeployProfileError::Aborted)
+    ));
+    assert!(matches!(
+        ask(true, "").await.0,
+        Err(DeployProfileError::Aborted)
+    ));
+}
+
+// Only one question is asked on the terminal at a time, when several nodes require confirmation
+static PROMPT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Asks on the terminal whether to deploy, if the node has `requireConfirmation` and `--yes`
+/// wasn't given. Other deployments keep going while waiting for the answer. The question goes to
+/// standard error, as standard output may be for `--log-format json`.
+async fn confirm_deployment(deploy_data: &super::DeployData<'_>) -> Result<(), DeployProfileError> {
+    use std::io::IsTerminal;
+
+    if deploy_data.node.node_settings.require_confirmation != Some(true)
+        || deploy_data.cmd_overrides.yes
+    {
+        return Ok(());
+    }
+
+    let _prompt = PROMPT_LOCK.lock().await;
+
+    ask_confirmation(
+        deploy_data,
+        std::io::stdin().is_terminal(),
+        &mut tokio::io::BufReader::new(tokio::io::stdin()),
+        &mut std::io::stderr(),
+    )
+    .await
+}
+
+/// When a phase of deploying started, relative to when activating did, and how long it took
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseTiming {
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Collects how long the phases of deploying a profile take, also from the task watching the
+/// activation in the background
+#[derive(Debug, Clone)]
+struct PhaseTimer {
+    started: Instant,
+    log_prefix: String,
+    phases: std::sync::Arc<std::sync::Mutex<HashMap<&'static str, PhaseTiming>>>,
+}
+
+impl PhaseTimer {
+    fn new(deploy_data: &super::DeployData<'_>) -> Self {
+        PhaseTimer {
+            started: Instant::now(),
+            log_prefix: log_prefix(deploy_data),
+            phases: Default::default(),
+        }
+    }
+
+    /// Records `phase` as started at `start` and ending now
+    fn record(&self, phase: &'static str, start: Instant) {
+        let timing = PhaseTiming {
+            start: start.saturating_duration_since(self.started),
+            duration: start.elapsed(),
+        };
+
+        debug!(
+            "{} phase={} start_ms={} duration_ms={}",
+            self.log_prefix,
+            phase,
+            timing.start.as_millis(),
+            timing.duration.as_millis()
+        );
+
+        self.phases.lock().unwrap().insert(phase, timing);
+    }
+
+    fn phases(&self) -> HashMap<&'static str, PhaseTiming> {
+        self.phases.lock().unwrap().clone()
+    }
+}
+
+/// The outcome of deploying a profile successfully, a rollback is an error instead (see
+/// [`DeployProfileError::rolled_back`])
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeployResult {
+    pub node_name: String,
+    pub profile_name: String,
+    /// How long activating (and waiting for it) took
+    pub duration: Duration,
+    /// If the activation was confirmed, rather than done without magic rollback
+    pub confirmed: bool,
+    /// If confirming the activation was left to something else, with `externalConfirm`
+    pub confirm_deferred: bool,
+    /// How long `connect` (everything before activating), `activate`, `wait` and `confirm` took.
+    /// With magic rollback, `activate` and `wait` run at the same time, and `activate` is left out
+    /// if it has not finished by the end of confirming.
+    pub phases: HashMap<&'static str, PhaseTiming>,
+}
+
+/// Rejects settings of a profile which can't work, before anything is done, returning how it
+/// rolls back
+fn check_deploy_settings(
+    deploy_data: &super::DeployData<'_>,
+) -> Result<RollbackStrategy, DeployProfileError> {
+    let rollback = deploy_data
+        .rollback_strategy()
+        .map_err(DeployProfileError::IncoherentRollback)?;
+
+    check_confirm_timeout(deploy_data.merged_settings.confirm_timeout.unwrap_or(30))?;
+
+    if deploy_data.merged_settings.health_check_command.is_some() {
+        check_health_check_timeout(
+            deploy_data
+                .merged_settings
+                .health_check_timeout
+                .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT),
+            match rollback {
+                RollbackStrategy::Magic => {
+                    Some(deploy_data.merged_settings.confirm_timeout.unwrap_or(30))
+                }
+                RollbackStrategy::Auto | RollbackStrategy::None => None,
+            },
+        )?;
+    }
+
+    if let (RollbackStrategy::Magic, Some(stabilization)) =
+        (rollback, &deploy_data.merged_settings.stabilization)
+    {
+        check_stabilization(
+            stabilization,
+            deploy_data.merged_settings.confirm_timeout.unwrap_or(30),
+        )?;
+    }
+
+    Ok(rollback)
+}
+
+/// What comes before activating a profile, whether it is deployed by itself or as part of a
+/// group: asking for confirmation and `preDeployHook`
+async fn before_activating(deploy_data: &super::DeployData<'_>) -> Result<(), DeployProfileError> {
+    confirm_deployment(deploy_data).await?;
+
+    if let Some(hook) = &deploy_data.merged_settings.pre_deploy_hook {
+        explain(
+            deploy_data,
+            "`preDeployHook` is set, so I run it locally first and only deploy if it succeeds",
+        );
+
+        run_deploy_hook(deploy_data, "preDeployHook", hook).await?;
+    }
+
+    Ok(())
+}
+
+/// What comes after confirming a profile: `postDeployHook`
+async fn after_confirming(deploy_data: &super::DeployData<'_>) {
+    if let Some(hook) = &deploy_data.merged_settings.post_deploy_hook {
+        // The profile is live by now, so a failure can't undo anything
+        if let Err(err) = run_deploy_hook(deploy_data, "postDeployHook", hook).await {
+            node_log!(warn, deploy_data, "{}", err);
+        }
+    }
+}
+
+/// Warns that a profile won't be confirmed, if deploying it was stopped by `err` while waiting
+/// for confirmation
+fn warn_stopped(deploy_data: &super::DeployData<'_>, err: &DeployProfileError) {
+    if let DeployProfileError::RolledBack(ref err) = err {
+        let reason = match **err {
+            DeployProfileError::Cancelled => "Cancelled",
+            _ => return,
+        };
+
+        node_log!(
+            warn,
+            deploy_data,
+            "{}, profile `{}` for node `{}` will not be confirmed and rolls back once its confirm timeout elapses",
+            reason, deploy_data.profile_name, deploy_data.node_name
+        );
+    }
+}
+
+/// What comes once deploying a profile is over, successfully or failing with `error`: fetching
+/// the logs of the activation and the error event
+async fn after_deploying(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    error: Option<&str>,
+) {
+    if let Some(local_root) = &deploy_data.merged_settings.fetch_logs_to {
+        explain(
+            deploy_data,
+            "`fetchLogsTo` is set, so now that deploying is over I copy the logs of the activation from the node",
+        );
+
+        match fetch_activation_logs(deploy_data, deploy_defs, runner, local_root).await {
+            Ok(Some(_)) => (),
+            Ok(None) => node_log!(
+                warn,
+                deploy_data,
+                "`fetchLogsTo` is set, but there are no logs to fetch without `--log-dir`"
+            ),
+            Err(err) => node_log!(warn, deploy_data, "Failed to fetch logs: {}", err),
+        }
+    }
+
+    if let Some(err) = error {
+        emit_event(deploy_data, "error", Some(err));
+    }
+}
+
+pub async fn deploy_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    cancel: &CancellationToken,
+) -> Result<DeployResult, DeployProfileError> {
+    let rollback = check_deploy_settings(deploy_data)?;
+
+    if deploy_data.cmd_overrides.dry_run {
+        node_log!(
+            info,
+            deploy_data,
+            "Dry run, these commands would deploy profile `{}` for node `{}`:",
+            deploy_data.profile_name,
+            deploy_data.node_name
+        );
+
+        for (step, command) in make_dry_run_commands(deploy_data, deploy_defs, rollback) {
+            node_log!(info, deploy_data, "[{}] {}", step, command);
+        }
+
+        return Ok(DeployResult {
+            node_name: deploy_data.node_name.to_string(),
+            profile_name: deploy_data.profile_name.to_string(),
+            duration: Duration::from_secs(0),
+            confirmed: false,
+            confirm_deferred: false,
+            phases: HashMap::new(),
+        });
+    }
+
+    before_activating(deploy_data).await?;
+
+    let result = async {
+        if cancel.is_cancelled() {
+            return Err(DeployProfileError::Cancelled);
+        }
+
+        // Not counting the confirmation prompt or hooks, which may take a while themselves
+        let started = Instant::now();
+        let activating = activate_profile(deploy_data, deploy_defs, runner);
+
+        // Only activating with magic rollback can be stopped safely, by never confirming it. Once
+        // it is waiting for confirmation, confirming is allowed to finish.
+        let pending = match rollback {
+            RollbackStrategy::Magic => {
+                until_cancelled(activating, cancel).await?
+            }
+            RollbackStrategy::Auto | RollbackStrategy::None => activating.await?,
+        };
+
+        let duration = started.elapsed();
+        let timer = pending.timer.clone();
+
+        if pending.needs_confirmation()
+            && deploy_data.merged_settings.external_confirm == Some(true)
+        {
+            explain(
+                deploy_data,
+                "`externalConfirm` is set, so now that the node waits for confirmation I leave it to something else",
+            );
+
+            pending.defer();
+
+            return Ok(DeployResult {
+                node_name: deploy_data.node_name.to_string(),
+                profile_name: deploy_data.profile_name.to_string(),
+                duration,
+                confirmed: false,
+                confirm_deferred: true,
+                phases: timer.phases(),
+            });
+        }
+
+        let confirmed = pending.needs_confirmation();
+
+        let confirm_started = Instant::now();
+        pending.confirm().await?;
+        timer.record("confirm", confirm_started);
+
+        after_confirming(deploy_data).await;
+
+        Ok(DeployResult {
+            node_name: deploy_data.node_name.to_string(),
+            profile_name: deploy_data.profile_name.to_string(),
+            duration,
+            confirmed,
+            confirm_deferred: false,
+            phases: timer.phases(),
+        })
+    }
+    .await;
+
+    if let Err(ref err) = result {
+        warn_stopped(deploy_data, err);
+    }
+
+    after_deploying(
+        deploy_data,
+        deploy_defs,
+        runner,
+        result.as_ref().err().map(|x| x.to_string()).as_deref(),
+    )
+    .await;
+
+    result
+}
+
+#[derive(Error, Debug)]
+pub enum PushAndDeployError {
+    #[error("Failed to push profile: {0}")]
+    Push(#[from] crate::push::PushProfileError),
+    #[error("Failed to deploy profile: {0}")]
+    Deploy(#[from] DeployProfileError),
+}
+
+/// Copies the closure to the node with [`crate::push::push_profile`], then deploys it like
+/// [`deploy_profile`], for deploying when the closure isn't on the node yet
+pub async fn push_and_deploy_profile(
+    push_data: crate::push::PushProfileData<'_>,
+    runner: &dyn CommandRunner,
+    cancel: &CancellationToken,
+) -> Result<DeployResult, PushAndDeployError> {
+    let deploy_data = push_data.deploy_data;
+    let deploy_defs = push_data.deploy_defs;
+
+    crate::push::push_profile(push_data).await?;
+
+    Ok(deploy_profile(deploy_data, deploy_defs, runner, cancel).await?)
+}
+
+/// Copies the logs `activate-rs` wrote to `--log-dir` on the node into `<node>/<profile>/<timestamp>`
+/// below `local_root`, returning where they were stored. Without `--log-dir` there are none.
+async fn fetch_activation_logs(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    runner: &dyn CommandRunner,
+    local_root: &std::path::Path,
+) -> Result<Option<std::path::PathBuf>, crate::logs::FetchLogsError> {
+    use crate::logs::FetchLogsError;
+
+    let remote_dir = match deploy_data.log_dir {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    let local_dir = local_root
+        .join(deploy_data.node_name)
+        .join(deploy_data.profile_name)
+        .join(timestamp.to_string());
+
+    let mut argv = node_argv(deploy_data, &deploy_data.ssh_addr(deploy_defs));
+    argv.push(crate::logs::build_fetch_logs_command(remote_dir));
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Fetching logs from the node: {:?}",
+        argv
+    );
+
+    let output = runner
+        .output(
+            &argv,
+            RunOptions {
+                null_stdin: true,
+                ..node_options(deploy_data)
+            },
+        )
+        .await
+        .map_err(FetchLogsError::SSHFetchError)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(FetchLogsError::SSHFetchExitError(a)),
+    };
+
+    crate::logs::store_logs(&output.stdout, &local_dir, false)
+        .await
+        .map(Some)
+}
+
+/// Stops deployments which are still running, like the other nodes of a fleet once deploying it
+/// is abandoned. Clones share whether they were cancelled.
+///
+/// This is a small stand-in for `tokio_util::sync::CancellationToken`, which only exists for
+/// newer versions of tokio than the one used here.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: std::sync::Arc<tokio::sync::watch::Sender<bool>>,
+    receiver: tokio::sync::watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+
+        CancellationToken {
+            sender: std::sync::Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn cancel(&self) {
+        // This holds a receiver itself, so sending can't fail
+        self.sender.send(true).ok();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Finishes once this is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                futures_util::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tokio::test]
+async fn test_cancellation_token() {
+    let token = CancellationToken::new();
+    let clone = token.clone();
+    assert!(!clone.is_cancelled());
+
+    let waiting = tokio::spawn(async move { clone.cancelled().await });
+    token.cancel();
+
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(token.is_cancelled());
+    // Already cancelled tokens finish right away
+    token.cancelled().await;
+}
+
+/// Runs `deploy` unless `cancel` is cancelled meanwhile, in which case it is dropped before it
+/// could confirm anything
+async fn until_cancelled<T>(
+    deploy: impl std::future::Future<Output = Result<T, DeployProfileError>>,
+    cancel: &CancellationToken,
+) -> Result<T, DeployProfileError> {
+    let result = tokio::select! {
+        x = deploy => x,
+        () = cancel.cancelled() => return Err(DeployProfileError::Cancelled.into_rolled_back()),
+    };
+
+    // Ctrl-C, which cancels deploying in `deploy`, reaches the SSH commands as well, which may fail
+    // before it is noticed here
+    match result {
+        Err(_) if cancel.is_cancelled() => Err(DeployProfileError::Cancelled.into_rolled_back()),
+        result => result,
+    }
+}
+
+#[tokio::test]
+async fn test_dry_run_spawns_nothing() {
+    let node = crate::mock_node(serde_json::json!({
+        // Any command run over SSH would fail the deployment
+        "sshOpts": ["-o", "ProxyCommand=false"],
+        "confirmChecks": {
+            "allOf": [{ "command": "systemctl is-active app" }, { "http": "http://localhost" }],
+        },
+    }));
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        dry_run: true,
+        ..Default::default()
+    };
+
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let runner = crate::runner::MockRunner::default();
+    let result = deploy_profile(
+        &deploy_data,
+        &deploy_defs,
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    assert!(!result.confirmed);
+    assert!(runner.calls().is_empty());
+
+    let commands = make_dry_run_commands(&deploy_data, &deploy_defs, RollbackStrategy::Magic);
+    assert_eq!(
+        commands.iter().map(|(step, _)| *step).collect::<Vec<_>>(),
+        vec!["activate", "wait", "confirm_check", "confirm"]
+    );
+    assert!(commands[2].1.ends_with(" 'systemctl is-active app'"));
+    assert!(commands[0]
+        .1
+        .starts_with("ssh admin@example.com -o ControlMaster=auto"));
+    assert!(commands[0].1.contains(" -o ProxyCommand=false "));
+    assert!(commands[0].1.ends_with(
+        " 'sudo -u '\\''root'\\'' /nix/store/blah-system/activate-rs --lock-file-name deploy-rs-canary-blah-test --temp-path /tmp activate /nix/store/blah-system /nix/var/nix/profiles/system --confirm-timeout 30 --magic-rollback --auto-rollback'"
+    ));
+}
+
+/// Deploys a magic rollback profile with the commands answered by `responses`, returning the
+/// result along with which steps were run, in order
+#[cfg(test)]
+async fn deploy_mocked(
+    settings: serde_json::Value,
+    responses: Vec<(&str, crate::runner::MockResponse)>,
+) -> (Result<DeployResult, DeployProfileError>, Vec<&'static str>) {
+    deploy_mocked_with(
+        settings,
+        &crate::runner::MockRunner::new(responses),
+        &CancellationToken::new(),
+    )
+    .await
+}
+
+/// Like `deploy_mocked`, with a runner which can be looked at afterwards
+#[cfg(test)]
+async fn deploy_mocked_with(
+    settings: serde_json::Value,
+    runner: &crate::runner::MockRunner,
+    cancel: &CancellationToken,
+) -> (Result<DeployResult, DeployProfileError>, Vec<&'static str>) {
+    let node = crate::mock_node(settings);
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let result = deploy_profile(&deploy_data, &deploy_defs, runner, cancel).await;
+
+    let steps = runner
+        .calls()
+        .iter()
+        .map(|x| match x {
+            x if x.contains(" activate /") => "activate",
+            x if x.contains(" wait /") => "wait",
+            x if x.contains("readlink") => "check",
+            x if x.contains("test -d") => "check_temp",
+            x if x.contains("test -e") => "check_lock",
+            x if x.contains("rm -f") => "clear_lock",
+            x if x.contains("rm ") => "confirm",
+            x if x.contains("cat > ") => "upload",
+            x if x.contains("tar -cf") => "fetch_logs",
+            _ => "other",
+        })
+        .collect();
+
+    (result, steps)
+}
+
+#[cfg(test)]
+const MOCK_PROFILE_LINK: &str = "system-42-link\n/nix/store/blah-system\n";
+
+/// Reads the `system` profile as [`MOCK_PROFILE_LINK`], like once it was activated
+#[cfg(test)]
+fn profile_link_response() -> (&'static str, crate::runner::MockResponse) {
+    (
+        "readlink",
+        crate::runner::MockResponse {
+            stdout: MOCK_PROFILE_LINK.to_string(),
+            ..crate::runner::MockResponse::exit(0)
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_deploy_confirms_after_waiting() {
+    use crate::runner::MockResponse;
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({}),
+        vec![
+            (
+                " activate /",
+                MockResponse {
+                    duration: Some(Duration::from_millis(50)),
+                    ..MockResponse::exit(0)
+                },
+            ),
+            profile_link_response(),
+        ],
+    )
+    .await;
+
+    assert!(result.unwrap().confirmed);
+    assert_eq!(
+        steps,
+        vec![
+            "check_temp",
+            "check_lock",
+            "activate",
+            "wait",
+            "confirm",
+            "check"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_deploy_confirm_checks() {
+    use crate::runner::MockResponse;
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({
+            "confirmChecks": { "command": "systemctl is-active app" },
+        }),
+        vec![
+            ("systemctl is-active app", MockResponse::exit(3)),
+            profile_link_response(),
+        ],
+    )
+    .await;
+
+    // Checks run on the node like every other command, and without passing nothing is confirmed
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => match *err {
+            DeployProfileError::ConfirmError(ConfirmProfileError::ChecksFailed(ref failures)) => {
+                assert_eq!(
+                    failures,
+                    &["command `systemctl is-active app` (exit code Some(3))"]
+                )
+            }
+            ref x => panic!("expected failed checks, got {:?}", x),
+        },
+        x => panic!("expected a rollback, got {:?}", x),
+    }
+    assert_eq!(
+        steps,
+        vec!["check_temp", "check_lock", "activate", "wait", "other"]
+    );
+}
+
+#[tokio::test]
+async fn test_deploy_phase_timings() {
+    use crate::runner::MockResponse;
+
+    let (result, _) = deploy_mocked(
+        serde_json::json!({ "preDeployHook": "sleep 0.5" }),
+        vec![
+            (
+                " wait /",
+                MockResponse {
+                    duration: Some(Duration::from_millis(20)),
+                    ..MockResponse::exit(0)
+                },
+            ),
+            profile_link_response(),
+        ],
+    )
+    .await;
+
+    let result = result.unwrap();
+    // The hook runs before activating, so it doesn't count
+    assert!(result.duration < Duration::from_millis(500));
+
+    let phases = result.phases;
+    let mut names: Vec<_> = phases.keys().copied().collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["activate", "confirm", "connect", "wait"]);
+
+    // Activating and waiting overlap, confirming follows after
+    assert_eq!(phases["activate"].start, phases["wait"].start);
+    assert!(phases["wait"].duration >= Duration::from_millis(20));
+    assert!(phases["confirm"].start >= phases["wait"].start + phases["wait"].duration);
+}
+
+#[tokio::test]
+async fn test_deploy_uploads_before_activating() {
+    use crate::runner::MockResponse;
+
+    let files = serde_json::json!({
+        "preActivationFiles": [
+            { "local": "./app.env", "remote": "/run/keys/app.env", "mode": "0400" },
+            { "local": "./db.key", "remote": "/run/keys/db.key" },
+        ],
+    });
+    let profile_link = profile_link_response();
+
+    let (result, steps) = deploy_mocked(files.clone(), vec![profile_link.clone()]).await;
+    assert!(result.is_ok());
+    assert_eq!(
+        steps,
+        vec![
+            "check_temp",
+            "check_lock",
+            "upload",
+            "upload",
+            "activate",
+            "wait",
+            "confirm",
+            "check"
+        ]
+    );
+
+    // Neither the second file nor the activation follow a failed upload
+    let (result, steps) = deploy_mocked(
+        files,
+        vec![("app.env", MockResponse::exit(1)), profile_link],
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(DeployProfileError::UploadExitError(_, Some(1)))
+    ));
+    assert_eq!(steps, vec!["check_temp", "check_lock", "upload"]);
+}
+
+#[tokio::test]
+async fn test_deploy_stale_lock() {
+    use crate::runner::MockResponse;
+
+    let responses = || {
+        vec![
+            (
+                "test -e",
+                MockResponse {
+                    stdout: "exists\n".to_string(),
+                    ..MockResponse::exit(0)
+                },
+            ),
+            profile_link_response(),
+        ]
+    };
+
+    let (result, steps) = deploy_mocked(serde_json::json!({}), responses()).await;
+    assert!(matches!(result, Err(DeployProfileError::StaleLock(_))));
+    assert_eq!(steps, vec!["check_temp", "check_lock"]);
+
+    let (result, steps) =
+        deploy_mocked(serde_json::json!({ "clearStaleLock": true }), responses()).await;
+    assert!(result.is_ok());
+    assert_eq!(
+        steps,
+        vec![
+            "check_temp",
+            "check_lock",
+            "clear_lock",
+            "activate",
+            "wait",
+            "confirm",
+            "check"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_deploy_unwritable_temp_path() {
+    use crate::runner::MockResponse;
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "tempPath": "/nonexistent" }),
+        vec![("test -d", MockResponse::exit(1))],
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(DeployProfileError::TempPathUnwritable(x)) if x == "/nonexistent"
+    ));
+    assert_eq!(steps, vec!["check_temp"]);
+}
+
+#[tokio::test]
+async fn test_deploy_activation_failure_wins_over_waiting() {
+    use crate::runner::MockResponse;
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({}),
+        vec![
+            (" activate /", MockResponse::exit(1)),
+            (
+                " wait /",
+                MockResponse {
+                    duration: None,
+                    ..MockResponse::exit(0)
+                },
+            ),
+        ],
+    )
+    .await;
+
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(
+                *err,
+                DeployProfileError::SSHActivateExitError { code: Some(1), .. }
+            ))
+        }
+        x => panic!("expected a rolled back activation failure, got {:?}", x),
+    }
+    assert_eq!(steps, vec!["check_temp", "check_lock", "activate", "wait"]);
+}
+
+#[tokio::test]
+async fn test_deploy_activation_failure_cancels_waiting() {
+    use crate::runner::MockResponse;
+
+    let node = crate::mock_node(serde_json::json!({
+        "sshMultiplexing": false,
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let runner = crate::runner::MockRunner::new(vec![
+        (
+            " activate /",
+            MockResponse {
+                duration: Some(Duration::from_millis(50)),
+                ..MockResponse::exit(1)
+            },
+        ),
+        (
+            " wait /",
+            MockResponse {
+                duration: None,
+                ..MockResponse::exit(0)
+            },
+        ),
+    ]);
+
+    // The wait command never ends by itself
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        deploy_profile(
+            &deploy_data,
+            &deploy_defs,
+            &runner,
+            &CancellationToken::new(),
+        ),
+    )
+    .await
+    .expect("waiting was not cancelled");
+
+    assert!(matches!(result, Err(DeployProfileError::RolledBack(_))));
+    assert_eq!(runner.running(), 0);
+}
+
+#[tokio::test]
+async fn test_deploy_activation_timeout() {
+    use crate::runner::MockResponse;
+
+    let never = MockResponse {
+        duration: None,
+        ..MockResponse::exit(0)
+    };
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "activationTimeout": 1 }),
+        vec![(" activate /", never.clone()), (" wait /", never)],
+    )
+    .await;
+
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(*err, DeployProfileError::ActivationTimeout(1)))
+        }
+        x => panic!("expected a rolled back activation timeout, got {:?}", x),
+    }
+    assert_eq!(steps, vec!["check_temp", "check_lock", "activate", "wait"]);
+}
+
+#[tokio::test]
+async fn test_deploy_cancelled() {
+    use crate::runner::{MockResponse, MockRunner};
+
+    let profile_link = profile_link_response();
+
+    // Cancelled while waiting for the activation, which is then left to roll back
+    let runner = MockRunner::new(vec![
+        (
+            " wait /",
+            MockResponse {
+                duration: Some(Duration::from_millis(500)),
+                ..MockResponse::exit(0)
+            },
+        ),
+        profile_link.clone(),
+    ]);
+    let cancel = CancellationToken::new();
+    let ((result, steps), ()) = tokio::join!(
+        deploy_mocked_with(serde_json::json!({}), &runner, &cancel),
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        }
+    );
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(*err, DeployProfileError::Cancelled))
+        }
+        x => panic!("expected a rolled back cancellation, got {:?}", x),
+    }
+    assert_eq!(steps, vec!["check_temp", "check_lock", "activate", "wait"]);
+
+    // Once confirming, it is left to finish
+    let runner = MockRunner::new(vec![
+        (
+            "rm ",
+            MockResponse {
+                duration: Some(Duration::from_millis(500)),
+                ..MockResponse::exit(0)
+            },
+        ),
+        profile_link.clone(),
+    ]);
+    let confirming_cancel = CancellationToken::new();
+    let ((result, steps), ()) = tokio::join!(
+        deploy_mocked_with(serde_json::json!({}), &runner, &confirming_cancel),
+        async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            confirming_cancel.cancel();
+        }
+    );
+    assert!(result.unwrap().confirmed);
+    assert_eq!(steps.last(), Some(&"check"));
+
+    // Without magic rollback, a cancelled deployment doesn't start activating
+    let runner = MockRunner::new(vec![profile_link]);
+    let (result, steps) = deploy_mocked_with(
+        serde_json::json!({ "magicRollback": false }),
+        &runner,
+        &cancel,
+    )
+    .await;
+    assert!(matches!(result, Err(DeployProfileError::Cancelled)));
+    assert!(steps.is_empty());
+}
+
+#[tokio::test]
+async fn test_deploy_ssh_agent_and_identity() {
+    let runner = crate::runner::MockRunner::new(vec![profile_link_response()]);
+    let (result, steps) = deploy_mocked_with(
+        serde_json::json!({
+            "sshAgentSock": "/run/ci/agent.sock",
+            "identityFile": "/run/ci/deploy_key",
+        }),
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await;
+    assert!(result.is_ok());
+
+    let invocations = runner.invocations();
+    for step in &["activate", "wait", "confirm"] {
+        let (argv, options) = &invocations[steps.iter().position(|x| x == step).unwrap()];
+
+        assert_eq!(
+            options.env,
+            vec![(
+                "SSH_AUTH_SOCK".to_string(),
+                "/run/ci/agent.sock".to_string()
+            )],
+            "{}",
+            step
+        );
+        assert!(
+            argv.windows(4)
+                .any(|x| x == ["-i", "/run/ci/deploy_key", "-o", "IdentitiesOnly=yes"]),
+            "{}: {:?}",
+            step,
+            argv
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_activate_user() {
+    let node = crate::mock_node(serde_json::json!({
+        "sshUser": "deploy",
+        "sshMultiplexing": false,
+        "profiles": {
+            "system": { "path": "/nix/store/blah-system", "activateUser": "svc" },
+        },
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert_eq!(deploy_data.ssh_addr(&deploy_defs), "deploy@example.com");
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -u 'svc'"));
+    // The profile is still the one of `user`
+    assert_eq!(deploy_defs.profile_path, "/nix/var/nix/profiles/system");
+
+    let runner = crate::runner::MockRunner::new(vec![profile_link_response()]);
+    deploy_profile(
+        &deploy_data,
+        &deploy_defs,
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+
+    let calls = runner.calls();
+    let activate = calls.iter().find(|x| x.contains(" activate /")).unwrap();
+    let confirm = calls
+        .iter()
+        .find(|x| x.contains("rm ") && !x.contains("rm -f"))
+        .unwrap();
+    assert!(activate.starts_with("sudo -u 'svc' "), "{}", activate);
+    assert!(confirm.starts_with("sudo -u 'svc' "), "{}", confirm);
+}
+
+#[tokio::test]
+async fn test_deploy_sudo_password() {
+    let node = crate::mock_node(serde_json::json!({
+        "sshMultiplexing": false,
+        // Printed so that the password itself isn't in the settings
+        "sudoPassword": { "command": "printf 'hunter%d' 2" },
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert_eq!(
+        deploy_defs.sudo.as_deref(),
+        Some("sudo -k -S -p '' -u 'root'")
+    );
+    assert!(!format!("{:?}", deploy_defs).contains("hunter2"));
+    assert!(!serde_json::to_string(&deploy_defs)
+        .unwrap()
+        .contains("hunter2"));
+
+    let runner = crate::runner::MockRunner::new(vec![profile_link_response()]);
+    deploy_profile(
+        &deploy_data,
+        &deploy_defs,
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+
+    // The password is only ever given on standard input, to the commands using sudo
+    let invocations = runner.invocations();
+    for (argv, options) in &invocations {
+        assert!(!argv.iter().any(|x| x.contains("hunter2")), "{:?}", argv);
+        assert!(!format!("{:?}", options).contains("hunter2"));
+
+        let command = argv.last().unwrap();
+        let secret = options.stdin_secret.as_ref().map(|x| x.expose());
+        match command.starts_with("sudo -k -S ") {
+            true => assert_eq!(secret, Some("hunter2"), "{}", command),
+            false => assert_eq!(secret, None, "{}", command),
+        }
+    }
+    for pattern in &[" activate /", " wait /", "rm "] {
+        assert!(invocations.iter().any(|(argv, options)| {
+            argv.last().unwrap().contains(pattern) && options.stdin_secret.is_some()
+        }));
+    }
+
+    let dry_run = make_dry_run_commands(&deploy_data, &deploy_defs, RollbackStrategy::Magic);
+    assert!(!dry_run.iter().any(|(_, x)| x.contains("hunter2")));
+}
+
+#[tokio::test]
+async fn test_deploy_fetches_logs() {
+    use crate::runner::{MockResponse, MockRunner};
+
+    let local_root =
+        std::env::temp_dir().join(format!("deploy-rs-test-fetch-logs-{}", std::process::id()));
+    let node = crate::mock_node(serde_json::json!({
+        "magicRollback": false,
+        "sshMultiplexing": false,
+        "fetchLogsTo": local_root,
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+    let deploy_data = crate::make_deploy_data(
+        &Default::default(),
+        &node,
+        "example",
+        &node.node_settings.profiles["system"],
+        "system",
+        &cmd_overrides,
+        false,
+        false,
+        Some("/var/log/deploy-rs"),
+    );
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let profile_link = profile_link_response();
+    // The logs are only fetched after everything else
+    let fetched_last = |runner: &MockRunner| {
+        let calls = runner.calls();
+        calls.iter().position(|x| x.contains("tar -cf")) == Some(calls.len() - 1)
+    };
+
+    let runner = MockRunner::new(vec![profile_link.clone()]);
+    let result = deploy_profile(
+        &deploy_data,
+        &deploy_defs,
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await;
+    assert!(result.is_ok());
+    assert!(fetched_last(&runner));
+
+    let runner = MockRunner::new(vec![(" activate /", MockResponse::exit(1)), profile_link]);
+    let result = deploy_profile(
+        &deploy_data,
+        &deploy_defs,
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await;
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => assert!(matches!(
+            *err,
+            DeployProfileError::SSHActivateExitError { code: Some(1), .. }
+        )),
+        x => panic!("expected a rolled back activation failure, got {:?}", x),
+    }
+    assert!(fetched_last(&runner));
+
+    std::fs::remove_dir_all(&local_root).ok();
+}
+
+#[tokio::test]
+async fn test_deploy_confirm_retries() {
+    use crate::runner::MockResponse;
+
+    let profile_link = profile_link_response();
+    let settings = serde_json::json!({ "confirmRetries": 1 });
+
+    // SSH failing to connect is retried
+    let (result, steps) = deploy_mocked(
+        settings.clone(),
+        vec![("rm '", MockResponse::exit(255)), profile_link.clone()],
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(steps.iter().filter(|x| **x == "confirm").count(), 2);
+
+    // Unless it would be too late to confirm
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "confirmRetries": 1, "confirmTimeout": 1 }),
+        vec![("rm '", MockResponse::exit(255)), profile_link.clone()],
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(steps.iter().filter(|x| **x == "confirm").count(), 1);
+
+    // Confirming failing on the node is not
+    let (result, steps) = deploy_mocked(
+        settings,
+        vec![("rm '", MockResponse::exit(1)), profile_link],
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(steps.iter().filter(|x| **x == "confirm").count(), 1);
+}
+
+#[tokio::test]
+async fn test_deploy_invalid_confirm_timeout() {
+    for confirm_timeout in &[0, 7200] {
+        let (result, steps) = deploy_mocked(
+            serde_json::json!({ "confirmTimeout": confirm_timeout }),
+            vec![],
+        )
+        .await;
+
+        assert!(
+            matches!(result, Err(DeployProfileError::InvalidConfirmTimeout(x)) if x == *confirm_timeout)
+        );
+        assert!(steps.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_stabilization() {
+    use crate::runner::MockResponse;
+
+    let profile_link = profile_link_response();
+    let stabilization = |window| {
+        serde_json::json!({
+            "stabilization": { "command": "check-stable", "interval": 1, "window": window },
+        })
+    };
+
+    let (result, steps) = deploy_mocked(
+        stabilization(0),
+        vec![
+            ("check-stable", MockResponse::exit(1)),
+            profile_link.clone(),
+        ],
+    )
+    .await;
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => assert!(matches!(
+            *err,
+            DeployProfileError::StabilizationFailed(0, Some(1))
+        )),
+        x => panic!("expected a rolled back stabilization failure, got {:?}", x),
+    }
+    // The lock is left for the node to roll back
+    assert!(!steps.contains(&"confirm"));
+
+    // Nothing runs on the node if the window can't fit into the confirm timeout
+    let (result, steps) = deploy_mocked(stabilization(30), vec![profile_link]).await;
+    assert!(matches!(
+        result,
+        Err(DeployProfileError::StabilizationTooLong(30, 30))
+    ));
+    assert!(steps.is_empty());
+}
+
+#[tokio::test]
+async fn test_deploy_external_confirm() {
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "externalConfirm": true }),
+        vec![profile_link_response()],
+    )
+    .await;
+    let result = result.unwrap();
+    assert!(!result.confirmed);
+    assert!(result.confirm_deferred);
+    // The lock is left in place for whatever confirms instead
+    assert_eq!(steps, vec!["check_temp", "check_lock", "activate", "wait"]);
+}
+
+#[tokio::test]
+async fn test_deploy_self_confirm() {
+    use crate::runner::MockResponse;
+
+    let settings = serde_json::json!({
+        "selfConfirmCommand": "curl -f http://localhost/health",
+        "confirmTimeout": 1,
+    });
+    let profile_link = profile_link_response();
+
+    let (result, steps) = deploy_mocked(settings.clone(), vec![profile_link.clone()]).await;
+    assert!(result.unwrap().confirmed);
+    assert_eq!(
+        steps,
+        vec![
+            "check_temp",
+            "check_lock",
+            "activate",
+            "wait",
+            "check_lock",
+            "check"
+        ]
+    );
+
+    // A node which never confirms still has the lock once the confirm timeout elapsed, the lock
+    // found before activating is cleared as it is the same command
+    let mut settings = settings;
+    settings["clearStaleLock"] = serde_json::json!(true);
+    let (result, steps) = deploy_mocked(
+        settings,
+        vec![
+            (
+                "test -e",
+                MockResponse {
+                    stdout: "exists\n".to_string(),
+                    ..MockResponse::exit(0)
+                },
+            ),
+            profile_link,
+        ],
+    )
+    .await;
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(*err, DeployProfileError::SelfConfirmTimeout))
+        }
+        x => panic!("expected a self confirm timeout, got {:?}", x),
+    }
+    assert!(!steps.contains(&"check"));
+}
+
+#[tokio::test]
+async fn test_deploy_hooks() {
+    let temp_dir = std::env::temp_dir().join(format!("deploy-rs-hooks-{}", std::process::id()));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+    let hook_output = temp_dir.join("post");
+
+    let profile_link = profile_link_response();
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({
+            "preDeployHook": r#"test "$DEPLOY_NODE" = example && test "$DEPLOY_PROFILE" = system"#,
+            "postDeployHook": format!(
+                r#"echo "$DEPLOY_NODE $DEPLOY_PROFILE" > '{}'"#,
+                hook_output.display()
+            ),
+        }),
+        vec![profile_link.clone()],
+    )
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(steps.last(), Some(&"check"));
+    assert_eq!(
+        std::fs::read_to_string(&hook_output).unwrap(),
+        "example system\n"
+    );
+
+    // Nothing runs on the node after a failed pre-deploy hook
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "preDeployHook": "exit 3" }),
+        vec![profile_link.clone()],
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(DeployProfileError::HookFailed("preDeployHook", Some(3)))
+    ));
+    assert!(steps.is_empty());
+
+    // While the deployment stands once the post-deploy hook runs
+    let (result, _) = deploy_mocked(
+        serde_json::json!({ "postDeployHook": "exit 1" }),
+        vec![profile_link],
+    )
+    .await;
+    assert!(result.is_ok());
+
+    std::fs::remove_dir_all(&temp_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_deploy_rollback_strategies() {
+    let profile_link = profile_link_response();
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "magicRollback": false }),
+        vec![profile_link.clone()],
+    )
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(steps, vec!["activate", "check"]);
+
+    // Nothing runs on the node when the settings contradict each other
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "autoRollback": false }),
+        vec![profile_link],
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(DeployProfileError::IncoherentRollback(_))
+    ));
+    assert!(steps.is_empty());
+}
+
+#[tokio::test]
+async fn test_deploy_wait_timeout() {
+    use crate::runner::MockResponse;
+
+    let never = MockResponse {
+        duration: None,
+        ..MockResponse::exit(0)
+    };
+
+    let (result, steps) = deploy_mocked(
+        serde_json::json!({ "waitTimeout": 1 }),
+        vec![(" activate /", never.clone()), (" wait /", never)],
+    )
+    .await;
+
+    match result {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(*err, DeployProfileError::WaitTimeout(1)))
+        }
+        x => panic!("expected a rolled back wait timeout, got {:?}", x),
+    }
+    assert_eq!(steps, vec!["check_temp", "check_lock", "activate", "wait"]);
+}
+
+#[tokio::test]
+async fn test_health_check_before_confirm() {
+    let temp_dir = std::env::temp_dir().join(format!("deploy-rs-health-{}", std::process::id()));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+    let temp_path = temp_dir.to_str().unwrap().to_string();
+
+    let node = crate::mock_node(serde_json::json!({
+        "hostname": "localhost",
+        "sshUser": "me",
+        "user": "me",
+        "local": true,
+        "tempPath": temp_path,
+        "healthCheckCommand": "exit 3",
+    }));
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    // The canary file is removed when confirming, which must not happen after a failed health check
+    let lock_path = crate::make_lock_path(
+        &temp_path,
+        "/nix/store/blah-system",
+        deploy_data.merged_settings.lock_file_name.as_deref(),
+    );
+    std::fs::write(&lock_path, "/nix/store/blah-system").unwrap();
+
+    let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
+    send_activated.send(()).unwrap();
+
+    let pending = PendingConfirmation {
+        deploy_data: &deploy_data,
+        deploy_defs: &deploy_defs,
+        runner: &crate::runner::SshRunner,
+        temp_path: temp_path.clone().into(),
+        ssh_addr: "me@localhost".to_string(),
+        recv_activated: Some(recv_activated),
+        deploy_span: None,
+        deadline: None,
+        activate_started: Instant::now(),
+        timer: PhaseTimer::new(&deploy_data),
+    };
+
+    match pending.confirm().await {
+        Err(DeployProfileError::RolledBack(err)) => {
+            assert!(matches!(
+                *err,
+                DeployProfileError::HealthCheckFailed(Some(3))
+            ))
+        }
+        x => panic!(
+            "expected a rolled back health check failure, got {:?}",
+            x.err()
+        ),
+    }
+    assert!(std::path::Path::new(&lock_path).exists());
+
+    std::fs::remove_dir_all(&temp_dir).unwrap();
+}
+
+#[test]
+fn test_local_node_commands() {
+    let node = crate::mock_node(serde_json::json!({
+        "hostname": "localhost",
+        "local": true,
+    }));
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data = crate::mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert!(deploy_data.is_local());
+    // Nothing is shared without SSH
+    assert_eq!(deploy_data.ssh_control_path, None);
+
+    let commands = make_dry_run_commands(&deploy_data, &deploy_defs, RollbackStrategy::Magic);
+
+    let activate = make_activate_command(
+        &deploy_data,
+        &deploy_defs,
+        "/tmp",
+        30,
+        RollbackStrategy::Magic,
+        None,
+    );
+    assert_eq!(
+        commands[0],
+        ("activate", format!("sh -c {}", shell_escape(&activate)))
+    );
+    assert!(activate.starts_with("sudo -u 'root' /nix/store/blah-system/activate-rs "));
+
+    let wait = make_wait_command(&deploy_data, &deploy_defs, "/tmp");
+    assert_eq!(
+        commands[1],
+        ("wait", format!("sh -c {}", shell_escape(&wait)))
+    );
+    assert_eq!(
+        wait,
+        "sudo -u 'root' /nix/store/blah-system/activate-rs --lock-file-name deploy-rs-canary-blah-test --temp-path /tmp wait /nix/store/blah-system"
+    );
+
+    assert_eq!(
+        make_node_argv(
+            false,
+            "ssh",
+            "admin@example.com",
+            &["-p".to_string(), "2121".to_string()]
+        ),
+        vec!["ssh", "admin@example.com", "-p", "2121"]
+    );
+}
+
+/// Activates a profile, up to the point where it needs to be confirmed
+pub async fn activate_profile<'a>(
+    deploy_data: &'a super::DeployData<'a>,
+    deploy_defs: &'a super::DeployDefs,
+    runner: &'a dyn CommandRunner,
+) -> Result<PendingConfirmation<'a>, DeployProfileError> {
+    node_log!(
+        info,
+        deploy_data,
+        "Activating profile `{}` for node `{}`",
+        deploy_data.profile_name,
+        deploy_data.node_name
+    );
+    emit_event(deploy_data, "activation_started", None);
+
+    // Dropping these spans on an early return records them as errors
+    let mut deploy_span = Span::start_profile(deploy_data.node_name, deploy_data.profile_name);
+    deploy_span.set_attribute("deploy.outcome", "failure");
+    let connect_span = Span::start("connect", Some(&deploy_span));
+
+    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x.into(),
+        None => "/tmp".into(),
+    };
+
+    let ssh_addr = deploy_data.ssh_addr(deploy_defs);
+
+    let timer = PhaseTimer::new(deploy_data);
+
+    let mut confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+    check_confirm_timeout(confirm_timeout)?;
+
+    let rollback = deploy_data
+        .rollback_strategy()
+        .map_err(DeployProfileError::IncoherentRollback)?;
+    let magic_rollback = rollback == RollbackStrategy::Magic;
+    let auto_rollback = rollback != RollbackStrategy::None;
+
+    if magic_rollback && deploy_data.merged_settings.auto_confirm_timeout == Some(true) {
+        explain(
+            deploy_data,
+            "`autoConfirmTimeout` is set, so I measure the SSH latency first and raise the confirm timeout if it is too low for it",
+        );
+
+        node_log!(debug, deploy_data, "Measuring SSH latency to {}", ssh_addr);
+
+        let mut argv = node_argv(deploy_data, &ssh_addr);
+        argv.push("true".to_string());
+
+        let start = Instant::now();
+
+        let ssh_rtt_exit_status = runner
+            .status(&argv, node_options(deploy_data))
+            .await
+            .map_err(DeployProfileError::SSHMeasureRttError)?;
+
+        match ssh_rtt_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::SSHMeasureRttExitError(a)),
+        };
+
+        let rtt = start.elapsed();
+
+        node_log!(
+            debug,
+            deploy_data,
+            "Connecting and running a command took {:?}",
+            rtt
+        );
+
+        let adjusted = confirm_timeout_for_rtt(confirm_timeout, rtt);
+        if adjusted != confirm_timeout {
+            node_log!(
+                warn,
+                deploy_data,
+                "Confirm timeout of {}s is dangerously low for a {:?} round trip to `{}`, raising it to {}s",
+                confirm_timeout, rtt, deploy_data.node_name, adjusted
+            );
+            confirm_timeout = adjusted;
+        }
+    }
+
+    match rollback {
+        RollbackStrategy::Magic => explain(
+            deploy_data,
+            &format!(
+                "Magic rollback is enabled, so I start the activation in the background and spawn a waiter, then the node rolls back unless I confirm within {}s",
+                confirm_timeout
+            ),
+        ),
+        RollbackStrategy::Auto => explain(
+            deploy_data,
+            "Magic rollback is disabled but auto rollback is enabled, so I activate once and the node rolls back by itself if activation fails",
+        ),
+        RollbackStrategy::None => explain(
+            deploy_data,
+            "Both magic and auto rollback are disabled, so I activate once and nothing is rolled back if it fails",
+        ),
+    }
+
+    let snapshot = make_snapshot_commands(
+        &deploy_data.merged_settings,
+        &deploy_snapshot_name(deploy_data),
+    );
+
+    if snapshot.is_some() {
+        explain(
+            deploy_data,
+            "`preActivateSnapshot` is set, so the node takes a snapshot before activating",
+        );
+    }
+
+    let self_activate_command = make_activate_command(
+        deploy_data,
+        deploy_defs,
+        &temp_path,
+        confirm_timeout,
+        rollback,
+        snapshot.as_ref(),
+    );
+
+    node_log!(
+        debug,
+        deploy_data,
+        "Constructed activation command: {}",
+        self_activate_command
+    );
+
+    let mut ssh_activate_argv = node_argv(deploy_data, &ssh_addr);
+    ssh_activate_argv.push(self_activate_command);
+
+    if deploy_data.merged_settings.verify_closure_on_remote == Some(true) {
+        explain(
+            deploy_data,
+            "`verifyClosureOnRemote` is set, so I verify the closure on the node before activating it",
+        );
+
+        let verify_command = build_verify_command(&deploy_data.profile.profile_settings.path);
+
+        node_log!(
+            debug,
+            deploy_data,
+            "Verifying closure on the node: {}",
+            verify_command
+        );
+
+        let verify_span = Span::start("verify", Some(&deploy_span));
+
+        let mut argv = node_argv(deploy_data, &ssh_addr);
+        argv.push(verify_command);
+
+        let ssh_verify_exit_status = runner
+            .status(&argv, node_options(deploy_data))
+            .await
+            .map_err(DeployProfileError::SSHVerifyError)?;
+
+        match ssh_verify_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::ClosureCorrupt(a)),
+        };
+
+        verify_span.end(SpanStatus::Ok);
+    }
+
+    if magic_rollback {
+        check_temp_path(deploy_data, deploy_defs, runner, &ssh_addr, &temp_path).await?;
+        check_stale_lock(deploy_data, deploy_defs, runner, &ssh_addr).await?;
+    }
+
+    if let Some(files) = &deploy_data.node.node_settings.pre_activation_files {
+        explain(
+            deploy_data,
+            "`preActivationFiles` is set, so I copy those files to the node before activating",
+        );
+
+        upload_files(deploy_data, deploy_defs, runner, &ssh_addr, files).await?;
+    }
+
+    // Activating again is not safe, so only whether the node can be reached is retried
+    if !deploy_data.is_local() && deploy_data.merged_settings.ssh_connect_retries.unwrap_or(0) > 0 {
+        let mut argv = node_argv(deploy_data, &ssh_addr);
+        argv.push("true".to_string());
+
+        let ssh_probe_exit_status = with_ssh_retries(deploy_data, "activating", || {
+            runner.status(
+                &argv,
+                RunOptions {
+                    null_stdin: true,
+                    ..node_options(deploy_data)
+                },
+            )
+        })
+        .await
+        .map_err(DeployProfileError::SSHActivateError)?;
+
+        match ssh_probe_exit_status.code() {
+            Some(0) => (),
+            a => {
+                return Err(DeployProfileError::SSHActivateExitError {
+                    code: a,
+                    stderr: String::new(),
+                })
+            }
+        };
+    }
+
+    timer.record("connect", timer.started);
+    connect_span.end(SpanStatus::Ok);
+
+    let activate_started = Instant::now();
+
+    if !magic_rollback {
+        if auto_rollback {
+            deploy_span.set_attribute("deploy.outcome", "rolled_back");
+        }
+
+        let activate_span = Span::start("activate", Some(&deploy_span));
+
+        let mut ssh_activate = runner
+            .spawn(
+                &ssh_activate_argv,
+                activate_options(deploy_data, deploy_defs),
+            )
+            .map_err(DeployProfileError::SSHActivateError)?;
+
+        let ssh_activate_exit_status = ssh_activate
+            .wait()
+            .await
+            .map_err(DeployProfileError::SSHActivateError)?;
+
+        match ssh_activate_exit_status.code() {
+            Some(0) => (),
+            code => {
+                let err = DeployProfileError::SSHActivateExitError {
+                    code,
+                    stderr: ssh_activate.stderr(),
+                };
+
+                // The activation rolls back by itself when it fails with auto rollback
+                return Err(match auto_rollback {
+                    true => err.into_rolled_back(),
+                    false => err,
+                });
+            }
+        };
+
+        activate_span.end(SpanStatus::Ok);
+        timer.record("activate", activate_started);
+
+        node_log!(info, deploy_data, "Success activating, done!");
+        emit_event(deploy_data, "activation_succeeded", None);
+    } else {
+        let self_wait_command = make_wait_command(deploy_data, deploy_defs, &temp_path);
+
+        node_log!(
+            debug,
+            deploy_data,
+            "Constructed wait command: {}",
+            self_wait_command
+        );
+
+        let mut ssh_wait_argv = node_argv(deploy_data, &ssh_addr);
+        ssh_wait_argv.push(self_wait_command);
+
+        // From here on, the node rolls back by itself unless it gets confirmed
+        deploy_span.set_attribute("deploy.outcome", "rolled_back");
+
+        let activate_span = Span::start("activate", Some(&deploy_span));
+
+        let ssh_activate = runner
+            .spawn(
+                &ssh_activate_argv,
+                activate_options(deploy_data, deploy_defs),
+            )
+            .map_err(DeployProfileError::SSHSpawnActivateError)?;
+
+        node_log!(info, deploy_data, "Creating activation waiter");
+        emit_event(deploy_data, "waiter_created", None);
+
+        let (send_activate, mut recv_activate) = tokio::sync::oneshot::channel();
         let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
+        let (send_kill, recv_kill) = tokio::sync::oneshot::channel();
+
+        let prefix = log_prefix(deploy_data);
+        let activate_timer = timer.clone();
+
+        tokio::spawn(async move {
+            let mut ssh_activate = ssh_activate;
+
+            let o = tokio::select! {
+                x = ssh_activate.wait() => x,
+                Ok(()) = recv_kill => {
+                    debug!("{} Killing the activate command", prefix);
+                    ssh_activate.kill().await.ok();
+                    return;
+                },
+            };
+
+            activate_timer.record("activate", activate_started);
+
+            let maybe_err = match o {
+                Err(x) => Some(DeployProfileError::SSHActivateError(x)),
+                Ok(x) => match x.code() {
+                    Some(0) => None,
+                    code => Some(DeployProfileError::SSHActivateExitError {
+                        code,
+                        stderr: ssh_activate.stderr(),
+                    }),
+                },
+            };
+            drop(ssh_activate);
+
+            activate_span.end(match maybe_err {
+                None => SpanStatus::Ok,
+                Some(_) => SpanStatus::Error,
+            });
+
+            // The receivers are gone if the activation has already been dealt with
+            if let Some(err) = maybe_err {
+                send_activate.send(err).ok();
+            }
+
+            send_activated.send(()).ok();
+        });
+
+        let wait_span = Span::start("wait", Some(&deploy_span));
+
+        let activation_timeout = deploy_data.merged_settings.activation_timeout;
+        if let Some(timeout) = activation_timeout {
+            explain(
+                deploy_data,
+                &format!("`activationTimeout` is set, so I give up on the activation if it has not finished after {}s, leaving it unconfirmed", timeout),
+            );
+        }
+
+        let wait_timeout = deploy_data.merged_settings.wait_timeout;
+        if let Some(timeout) = wait_timeout {
+            explain(
+                deploy_data,
+                &format!("`waitTimeout` is set, so I give up on the activation if waiting for it has not finished after {}s, leaving it unconfirmed", timeout),
+            );
+        }
+
+        let waited = with_activation_timeout(activation_timeout, async {
+            let wait = with_ssh_retries(deploy_data, "waiting", || async {
+                let options = RunOptions {
+                    // The waiter would otherwise outlive a timed out activation
+                    kill_on_drop: true,
+                    stdin_secret: deploy_defs.sudo_password.clone(),
+                    ..streamed_options(deploy_data)
+                };
+                runner.spawn(&ssh_wait_argv, options)?.wait().await
+            });
+
+            // Only waiting is bounded by this, unlike by `activationTimeout`
+            let wait = async {
+                match wait_timeout {
+                    Some(timeout) => {
+                        tokio::time::timeout(Duration::from_secs(timeout as u64), wait)
+                            .await
+                            .map_err(|_| DeployProfileError::WaitTimeout(timeout))
+                    }
+                    None => Ok(wait.await),
+                }
+            };
+
+            let wait_result = tokio::select! {
+                x = wait => x?,
+                // Nothing is sent when the activation succeeds, then only the wait command is left.
+                // Otherwise the activation did not get far enough to be waited for, so waiting is
+                // given up on (which kills the wait command) instead of running into its timeout.
+                Ok(err) = &mut recv_activate => {
+                    node_log!(
+                        debug,
+                        deploy_data,
+                        "Activate command exited with an error, cancelling the wait command"
+                    );
+                    return Err(err.into_rolled_back());
+                },
+            };
+
+            node_log!(debug, deploy_data, "Wait command ended");
+
+            let waited = match wait_result {
+                Err(err) => Err(DeployProfileError::SSHWaitError(err)),
+                Ok(status) => match status.code() {
+                    Some(0) => Ok(()),
+                    a => Err(DeployProfileError::SSHWaitExitError(a)),
+                },
+            };
+
+            waited
+                .map_err(|err| prefer_activation_error(err, &mut recv_activate).into_rolled_back())
+        })
+        .await;
+
+        match waited {
+            Err(err @ DeployProfileError::ActivationTimeout(_))
+            | Err(err @ DeployProfileError::WaitTimeout(_)) => {
+                // Only the local SSH command is killed. If the activation got as far as waiting for
+                // confirmation, the node rolls back once its confirm timeout elapses, otherwise it
+                // keeps running there.
+                send_kill.send(()).ok();
+                return Err(err.into_rolled_back());
+            }
+            x => x?,
+        }
+
+        wait_span.end(SpanStatus::Ok);
+        timer.record("wait", activate_started);
+
+        node_log!(
+            info,
+            deploy_data,
+            "Success activating, waiting for confirmation"
+        );
+        emit_event(deploy_data, "activation_succeeded", None);
+
+        return Ok(PendingConfirmation {
+            deploy_data,
+            deploy_defs,
+            runner,
+            temp_path,
+            ssh_addr,
+            recv_activated: Some(recv_activated),
+            deploy_span: Some(deploy_span),
+            deadline: Some(Instant::now() + Duration::from_secs(confirm_timeout as u64)),
+            activate_started,
+            timer,
+        });
+    }
+
+    Ok(PendingConfirmation {
+        deploy_data,
+        deploy_defs,
+        runner,
+        temp_path,
+        ssh_addr,
+        recv_activated: None,
+        deploy_span: Some(deploy_span),
+        deadline: None,
+        activate_started,
+        timer,
+    })
+}
+
+/// The canary file of the activation of `deploy_data`, as `activate-rs` derives it
+fn profile_lock_path(deploy_data: &super::DeployData<'_>) -> String {
+    super::make_lock_path(
+        deploy_data
+            .merged_settings
+            .temp_path
+            .as_deref()
+            .unwrap_or("/tmp"),
+        &deploy_data.profile.profile_settings.path,
+        deploy_data.merged_settings.lock_file_name.as_deref(),
+    )
+}
+
+/// Deploys independent profiles concurrently, at most `max_parallel` at a time. Each profile is
+/// deployed like by [`deploy_profile`], once the profiles of its node it depends on with
+/// `dependsOn` are, and the errors of all those which failed are returned. Profiles on the same
+/// node have to use different lock files, or confirming one would confirm the other as well, so
+/// nothing is deployed if two of them share one.
+pub async fn deploy_profiles_concurrent<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+    runner: &dyn CommandRunner,
+    max_parallel: usize,
+    cancel: &CancellationToken,
+) -> Result<Vec<DeployResult>, Vec<DeployProfileError>> {
+    let lock_paths: Vec<String> = targets.iter().map(|(x, _)| profile_lock_path(x)).collect();
+
+    let mut conflicts = Vec::new();
+
+    for (i, (deploy_data, _)) in targets.iter().enumerate() {
+        let earlier = targets[..i]
+            .iter()
+            .zip(&lock_paths)
+            .find(|((x, _), lock_path)| {
+                x.node_name == deploy_data.node_name && **lock_path == lock_paths[i]
+            });
+
+        if let Some(((other, _), lock_path)) = earlier {
+            conflicts.push(DeployProfileError::LockPathConflict(
+                deploy_data.profile_name.to_string(),
+                lock_path.to_string(),
+                other.profile_name.to_string(),
+            ));
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    // Profiles only depend on those of their own node
+    let names: Vec<String> = targets
+        .iter()
+        .map(|(x, _)| format!("{}.{}", x.node_name, x.profile_name))
+        .collect();
+    let deps: Vec<Vec<String>> = targets
+        .iter()
+        .map(|(x, _)| {
+            x.profile
+                .profile_settings
+                .depends_on
+                .iter()
+                .flatten()
+                .map(|dep| format!("{}.{}", x.node_name, dep))
+                .collect()
+        })
+        .collect();
+
+    let semaphore = tokio::sync::Semaphore::new(max_parallel.max(1));
+    let finished = std::sync::Mutex::new(Vec::new());
+
+    let (semaphore, finished_ref) = (&semaphore, &finished);
+
+    let result = crate::graph::run_with_dependencies(
+        names
+            .iter()
+            .zip(&deps)
+            .zip(targets.iter().enumerate())
+            .map(|((name, deps), target)| (name.as_str(), &deps[..], target))
+            .collect(),
+        |(i, (deploy_data, deploy_defs))| async move {
+            let _permit = semaphore.acquire().await;
+
+            let result = match cancel.is_cancelled() {
+                true => Err(DeployProfileError::Cancelled),
+                false => deploy_profile(deploy_data, deploy_defs, runner, cancel).await,
+            };
+            let failed = result.is_err();
+
+            if let Err(ref err) = result {
+                error!(
+                    "Failed to deploy profile `{}` for node `{}`: {}",
+                    deploy_data.profile_name, deploy_data.node_name, err
+                );
+            }
+
+            finished_ref.lock().unwrap().push((i, result));
+
+            match failed {
+                true => Err(UnitFailed::Deploy),
+                false => Ok(()),
+            }
+        },
+        true,
+    )
+    .await;
+
+    if let Err(UnitFailed::Dependencies(err)) = result {
+        return Err(vec![DeployProfileError::ProfileDependencies(err)]);
+    }
+
+    let mut finished = finished.into_inner().unwrap();
+    // In the order of `targets`, not the one they finished in
+    finished.sort_by_key(|(i, _)| *i);
+
+    for (i, (deploy_data, _)) in targets.iter().enumerate() {
+        if !finished.iter().any(|(x, _)| *x == i) {
+            warn!(
+                "Skipped deploying profile `{}` of node `{}`, as a profile it depends on failed",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut errs = Vec::new();
+
+    for (_, result) in finished {
+        match result {
+            Ok(x) => results.push(x),
+            Err(err) => errs.push(err),
+        }
+    }
+
+    match errs.is_empty() {
+        true => Ok(results),
+        false => Err(errs),
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_profiles_concurrent() {
+    use crate::runner::{MockResponse, MockRunner};
+
+    let node = crate::mock_node(serde_json::json!({
+        "sshMultiplexing": false,
+        "profiles": {
+            "system": { "path": "/nix/store/aaa-system" },
+            "app": { "path": "/nix/store/bbb-app" },
+            "db": { "path": "/nix/store/ccc-db", "dependsOn": ["system"] },
+        },
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data: Vec<_> = ["system", "app", "db"]
+        .iter()
+        .map(|profile_name| {
+            crate::make_deploy_data(
+                &Default::default(),
+                &node,
+                "example",
+                &node.node_settings.profiles[*profile_name],
+                profile_name,
+                &cmd_overrides,
+                false,
+                false,
+                None,
+            )
+        })
+        .collect();
+    let deploy_defs: Vec<_> = deploy_data.iter().map(|x| x.defs().unwrap()).collect();
+    let targets: Vec<_> = deploy_data.iter().zip(&deploy_defs).collect();
+
+    let link = |profile_name: &str, closure: &str| MockResponse {
+        stdout: format!("{}-1-link\n/nix/store/{}\n", profile_name, closure),
+        ..MockResponse::exit(0)
+    };
+    let runner = MockRunner::new(vec![
+        (
+            " activate /",
+            MockResponse {
+                duration: Some(Duration::from_millis(20)),
+                ..MockResponse::exit(0)
+            },
+        ),
+        (
+            "readlink '/nix/var/nix/profiles/system'",
+            link("system", "aaa-system"),
+        ),
+        ("/app'", link("app", "bbb-app")),
+        ("/db'", link("db", "ccc-db")),
+    ]);
+    let results = deploy_profiles_concurrent(&targets, &runner, 2, &CancellationToken::new())
+        .await
+        .unwrap();
+    let profile_names: Vec<_> = results.iter().map(|x| x.profile_name.as_str()).collect();
+    assert_eq!(profile_names, ["system", "app", "db"]);
+
+    let calls = runner.calls();
+    let position = |pattern: &str| calls.iter().position(|x| x.contains(pattern)).unwrap();
+    let activate = |closure| position(&format!("{}/activate-rs --lock-file-name", closure));
+    let confirm = |lock_path| position(&format!("rm '\\''{}'\\''", lock_path));
+
+    // Each profile is confirmed through its own lock file, `app` activating before `system` is
+    // confirmed
+    let (system, app) = (
+        confirm("/tmp/deploy-rs-canary-aaa-test"),
+        confirm("/tmp/deploy-rs-canary-bbb-test"),
+    );
+    assert_ne!(system, app);
+    assert!(activate("bbb-app") < system);
+    // `db` depends on `system`
+    assert!(activate("ccc-db") > system);
+
+    let node = crate::mock_node(serde_json::json!({
+        "profiles": {
+            "app": { "path": "/nix/store/bbb-app", "lockFileName": "deploy-rs-app" },
+            "other-app": { "path": "/nix/store/ddd-app", "lockFileName": "deploy-rs-app" },
+        },
+    }));
+    let deploy_data: Vec<_> = ["app", "other-app"]
+        .iter()
+        .map(|profile_name| {
+            crate::make_deploy_data(
+                &Default::default(),
+                &node,
+                "example",
+                &node.node_settings.profiles[*profile_name],
+                profile_name,
+                &cmd_overrides,
+                false,
+                false,
+                None,
+            )
+        })
+        .collect();
+    let deploy_defs: Vec<_> = deploy_data.iter().map(|x| x.defs().unwrap()).collect();
+    let targets: Vec<_> = deploy_data.iter().zip(&deploy_defs).collect();
+
+    let runner = MockRunner::default();
+    let errs = deploy_profiles_concurrent(&targets, &runner, 2, &CancellationToken::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        &errs[..],
+        [DeployProfileError::LockPathConflict(profile, _, other)]
+            if profile == "other-app" && other == "app"
+    ));
+    assert!(runner.calls().is_empty());
+}
+
+/// How many units (nodes, or confirm groups) are deployed at the same time unless `--max-parallel`
+/// says otherwise
+pub fn default_max_parallel(unit_count: usize) -> usize {
+    unit_count.clamp(1, 10)
+}
+
+#[derive(Error, Debug)]
+pub enum DeployGroupError {
+    #[error("Profile `{0}` of node `{1}` does not use magic rollback, which is required to deploy it as part of a group")]
+    NoMagicRollback(String, String),
+    #[error("Profile `{0}` of node `{1}` confirms itself with `selfConfirmCommand`, so it can't be deployed as part of a group")]
+    SelfConfirm(String, String),
+    #[error("Profile `{0}` of node `{1}` is confirmed by something else with `externalConfirm`, so it can't be deployed as part of a group")]
+    ExternalConfirm(String, String),
+    #[error("Failed to activate profile `{0}` of node `{1}`, the whole group will roll back: {2}")]
+    Activate(String, String, DeployProfileError),
+    #[error(
+        "Failed to confirm profile `{0}` of node `{1}`, the rest of the group will roll back: {2}"
+    )]
+    Confirm(String, String, DeployProfileError),
+    #[error("The group could not be confirmed before the smallest confirm timeout ran out, the rest of it will roll back")]
+    ConfirmTimeout,
+    #[error("Profile `{0}` of node `{1}` uses the lock file `{2}` of profile `{3}`, so they can't be deployed as part of the same group")]
+    LockPathConflict(String, String, String, String),
+    #[error("Failed to prepare deploying profile `{0}` of node `{1}`, so none of the group is deployed: {2}")]
+    Prepare(String, String, DeployProfileError),
+    #[error("Deploying the group was stopped before it was confirmed, the whole group will roll back: {0}")]
+    Stopped(DeployProfileError),
+    #[error(
+        "The activation quorum of the group is {0}, but it has to be between 1 and its {1} nodes"
+    )]
+    InvalidQuorum(usize, usize),
+    #[error("Only {0} nodes of the group were activated, short of its quorum of {1}, the whole group will roll back: {2}")]
+    QuorumNotReached(usize, usize, Box<DeployGroupError>),
+}
 
-        tokio::spawn(async move {
-            let o = ssh_activate.wait_with_output().await;
+impl DeployGroupError {
+    /// If the group (or what was not confirmed of it) rolled back because of this error
+    pub fn rolled_back(&self) -> bool {
+        !matches!(
+            self,
+            DeployGroupError::NoMagicRollback(..)
+                | DeployGroupError::SelfConfirm(..)
+                | DeployGroupError::ExternalConfirm(..)
+                | DeployGroupError::LockPathConflict(..)
+                | DeployGroupError::Prepare(..)
+                | DeployGroupError::InvalidQuorum(..)
+        )
+    }
+}
 
-            let maybe_err = match o {
-                Err(x) => Some(DeployProfileError::SSHActivateError(x)),
-                Ok(ref x) => match x.status.code() {
-                    Some(0) => None,
-                    a => Some(DeployProfileError::SSHActivateExitError(a)),
+/// How many nodes of a group have to be activated to confirm them, the highest one configured
+/// on its nodes, or all of them. This counts activations, not confirmations: the quorum is checked
+/// before confirming, which then has to succeed for every activated node, a node failing its
+/// confirmation rolls back the whole group even if the rest would still reach the quorum.
+fn group_quorum(
+    node_count: usize,
+    quorums: impl Iterator<Item = Option<usize>>,
+) -> Result<usize, DeployGroupError> {
+    match quorums.flatten().max() {
+        Some(quorum) if quorum == 0 || quorum > node_count => {
+            Err(DeployGroupError::InvalidQuorum(quorum, node_count))
+        }
+        Some(quorum) => Ok(quorum),
+        None => Ok(node_count),
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_group_hooks() {
+    use crate::runner::MockResponse;
+
+    let temp_dir =
+        std::env::temp_dir().join(format!("deploy-rs-group-hooks-{}", std::process::id()));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+    let hook = |name: &str| {
+        format!(
+            r#"echo "$DEPLOY_NODE" >> '{}'"#,
+            temp_dir.join(name).display()
+        )
+    };
+
+    let node = crate::mock_node(serde_json::json!({
+        "confirmGroup": "example",
+        "preDeployHook": hook("pre"),
+        "postDeployHook": hook("post"),
+    }));
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data: Vec<_> = ["one", "two"]
+        .iter()
+        .map(|name| {
+            crate::make_deploy_data(
+                &Default::default(),
+                &node,
+                name,
+                &node.node_settings.profiles["system"],
+                "system",
+                &cmd_overrides,
+                false,
+                false,
+                None,
+            )
+        })
+        .collect();
+    let deploy_defs: Vec<_> = deploy_data.iter().map(|x| x.defs().unwrap()).collect();
+    let targets: Vec<_> = deploy_data.iter().zip(deploy_defs.iter()).collect();
+
+    let profile_link = profile_link_response();
+
+    let runner = crate::runner::MockRunner::new(vec![profile_link.clone()]);
+    deploy_group(&targets, &runner, &CancellationToken::new())
+        .await
+        .unwrap();
+    for name in &["pre", "post"] {
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.join(name)).unwrap(),
+            "one\ntwo\n"
+        );
+    }
+
+    // The pre-deploy hooks all run before anything is activated, the post-deploy ones not at all
+    // once the group rolls back
+    std::fs::remove_file(temp_dir.join("pre")).unwrap();
+    std::fs::remove_file(temp_dir.join("post")).unwrap();
+    let runner =
+        crate::runner::MockRunner::new(vec![profile_link, (" wait /", MockResponse::exit(1))]);
+    let result = deploy_group(&targets, &runner, &CancellationToken::new()).await;
+    assert!(matches!(
+        result,
+        Err(DeployGroupError::QuorumNotReached(..))
+    ));
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.join("pre")).unwrap(),
+        "one\ntwo\n"
+    );
+    assert!(!temp_dir.join("post").exists());
+
+    std::fs::remove_dir_all(&temp_dir).unwrap();
+}
+
+#[test]
+fn test_group_quorum() {
+    assert_eq!(group_quorum(5, vec![None, None].into_iter()).unwrap(), 5);
+    assert_eq!(
+        group_quorum(5, vec![Some(3), None, Some(2)].into_iter()).unwrap(),
+        3
+    );
+    assert!(matches!(
+        group_quorum(2, vec![Some(3)].into_iter()),
+        Err(DeployGroupError::InvalidQuorum(3, 2))
+    ));
+    assert!(matches!(
+        group_quorum(2, vec![Some(0)].into_iter()),
+        Err(DeployGroupError::InvalidQuorum(0, 2))
+    ));
+}
+
+/// How much earlier than the estimated deadline confirming a group has to finish, as the
+/// estimate is taken after the node already started counting
+const GROUP_CONFIRM_MARGIN: Duration = Duration::from_secs(1);
+
+/// The time by which every confirmation of a group has to be done, from the earliest deadline
+fn group_confirm_deadline(deadlines: impl Iterator<Item = Option<Instant>>) -> Option<Instant> {
+    deadlines
+        .flatten()
+        .min()
+        .map(|x| x.checked_sub(GROUP_CONFIRM_MARGIN).unwrap_or(x))
+}
+
+#[test]
+fn test_group_confirm_deadline() {
+    let now = Instant::now() + Duration::from_secs(60);
+
+    assert_eq!(group_confirm_deadline(vec![None, None].into_iter()), None);
+    assert_eq!(
+        group_confirm_deadline(
+            vec![
+                Some(now + Duration::from_secs(30)),
+                None,
+                Some(now + Duration::from_secs(10)),
+            ]
+            .into_iter()
+        ),
+        Some(now + Duration::from_secs(9))
+    );
+}
+
+/// Confirms every pending profile concurrently, within the confirm timeout of whichever runs out
+/// first. After the first failure the confirmations still running are abandoned, so those nodes
+/// roll back. Nodes which were already confirmed at that point stay deployed, as confirming
+/// can't be undone.
+pub async fn confirm_group(pending: Vec<PendingConfirmation<'_>>) -> Result<(), DeployGroupError> {
+    let deadline = group_confirm_deadline(pending.iter().map(|x| x.deadline()));
+
+    let mut confirmations: FuturesUnordered<_> = pending
+        .into_iter()
+        .map(|x| async move {
+            let names = (
+                x.deploy_data.profile_name.to_string(),
+                x.deploy_data.node_name.to_string(),
+            );
+
+            x.confirm()
+                .await
+                .map_err(|err| DeployGroupError::Confirm(names.0, names.1, err))
+        })
+        .collect();
+
+    let confirm_all = async {
+        while let Some(result) = confirmations.next().await {
+            result?;
+        }
+
+        Ok(())
+    };
+
+    match deadline {
+        Some(deadline) => {
+            debug!(
+                "Confirming the group within {:?}",
+                deadline.saturating_duration_since(Instant::now())
+            );
+
+            tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), confirm_all)
+                .await
+                .map_err(|_| DeployGroupError::ConfirmTimeout)?
+        }
+        None => confirm_all.await,
+    }
+}
+
+/// Deploys the profiles of several nodes atomically. Every profile gets activated (nodes
+/// concurrently, profiles of a node in order), and they are only confirmed once the activations
+/// of all nodes succeeded, or of at least `activationQuorum` of them. If too many nodes fail, nothing
+/// is confirmed and the whole group rolls back. The quorum is checked before confirming, so it
+/// doesn't cover confirmations: if any activated node fails to confirm, the whole group rolls back.
+///
+/// Each profile goes through the same steps as with [`deploy_profile`], only its hooks and the
+/// confirmation prompt run for every profile before anything is activated.
+pub async fn deploy_group<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+    runner: &'a dyn CommandRunner,
+    cancel: &CancellationToken,
+) -> Result<Vec<DeployResult>, DeployGroupError> {
+    let result = deploy_group_profiles(targets, runner, cancel).await;
+
+    let error = result.as_ref().err().map(|x| x.to_string());
+    for (deploy_data, deploy_defs) in targets {
+        if let Err(DeployGroupError::Stopped(ref err)) = result {
+            warn_stopped(deploy_data, err);
+        }
+
+        after_deploying(deploy_data, deploy_defs, runner, error.as_deref()).await;
+    }
+
+    result
+}
+
+async fn deploy_group_profiles<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+    runner: &'a dyn CommandRunner,
+    cancel: &CancellationToken,
+) -> Result<Vec<DeployResult>, DeployGroupError> {
+    // Profiles of a node wait for confirmation at the same time, so confirming one mustn't confirm
+    // another as well
+    let lock_paths: Vec<String> = targets.iter().map(|(x, _)| profile_lock_path(x)).collect();
+
+    for (i, (deploy_data, _)) in targets.iter().enumerate() {
+        let earlier = targets[..i]
+            .iter()
+            .zip(&lock_paths)
+            .find(|((x, _), lock_path)| {
+                x.node_name == deploy_data.node_name && **lock_path == lock_paths[i]
+            });
+
+        if let Some(((other, _), lock_path)) = earlier {
+            return Err(DeployGroupError::LockPathConflict(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+                lock_path.to_string(),
+                other.profile_name.to_string(),
+            ));
+        }
+    }
+
+    for (deploy_data, _) in targets {
+        if deploy_data.merged_settings.magic_rollback == Some(false) {
+            return Err(DeployGroupError::NoMagicRollback(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+            ));
+        }
+
+        // The group couldn't hold back a node which confirms itself
+        if deploy_data.merged_settings.self_confirm_command.is_some() {
+            return Err(DeployGroupError::SelfConfirm(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+            ));
+        }
+
+        if deploy_data.merged_settings.external_confirm == Some(true) {
+            return Err(DeployGroupError::ExternalConfirm(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+            ));
+        }
+    }
+
+    let mut nodes: Vec<Vec<(&super::DeployData, &super::DeployDefs)>> = Vec::new();
+
+    for target in targets {
+        match nodes
+            .iter_mut()
+            .find(|x| x[0].0.node_name == target.0.node_name)
+        {
+            Some(node_targets) => node_targets.push(*target),
+            None => nodes.push(vec![*target]),
+        }
+    }
+
+    for (deploy_data, _) in targets {
+        check_deploy_settings(deploy_data).map_err(|err| {
+            DeployGroupError::Prepare(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+                err,
+            )
+        })?;
+    }
+
+    // Done for every profile before anything is activated, as the group rolls back together
+    for (deploy_data, _) in targets {
+        before_activating(deploy_data).await.map_err(|err| {
+            DeployGroupError::Prepare(
+                deploy_data.profile_name.to_string(),
+                deploy_data.node_name.to_string(),
+                err,
+            )
+        })?;
+    }
+
+    let quorum = group_quorum(
+        nodes.len(),
+        nodes
+            .iter()
+            .map(|x| x[0].0.node.node_settings.activation_quorum),
+    )?;
+
+    let activations = nodes.into_iter().map(|node_targets| async move {
+        let mut pending = Vec::new();
+
+        for (deploy_data, deploy_defs) in node_targets {
+            match activate_profile(deploy_data, deploy_defs, runner).await {
+                Ok(x) => pending.push(x),
+                Err(err) => {
+                    return Err(DeployGroupError::Activate(
+                        deploy_data.profile_name.to_string(),
+                        deploy_data.node_name.to_string(),
+                        err,
+                    ))
+                }
+            }
+        }
+
+        Ok(pending)
+    });
+
+    let mut pending = Vec::new();
+    let mut activated = 0;
+    let mut errs = Vec::new();
+
+    // Nothing is confirmed yet, so stopping only leaves the nodes to roll back
+    let results = until_cancelled(async { Ok(join_all(activations).await) }, cancel)
+        .await
+        .map_err(DeployGroupError::Stopped)?;
+
+    for result in results {
+        match result {
+            Ok(x) => {
+                pending.extend(x);
+                activated += 1;
+            }
+            Err(err) => errs.push(err),
+        }
+    }
+
+    if activated < quorum {
+        // Dropping the pending confirmations makes every node roll back
+        return Err(DeployGroupError::QuorumNotReached(
+            activated,
+            quorum,
+            Box::new(errs.remove(0)),
+        ));
+    }
+
+    if errs.is_empty() {
+        info!("Every profile in the group was activated, confirming all of them");
+    } else {
+        for err in errs {
+            error!("{}", err);
+        }
+
+        warn!(
+            "{} nodes of the group were activated, reaching its quorum of {}, confirming them (all of them have to be confirmed)",
+            activated, quorum
+        );
+    }
+
+    let confirming: Vec<_> = pending
+        .iter()
+        .map(|x| (x.deploy_data, x.activate_started.elapsed(), x.timer.clone()))
+        .collect();
+    confirm_group(pending).await?;
+
+    let mut results = Vec::new();
+
+    for (deploy_data, duration, timer) in confirming {
+        after_confirming(deploy_data).await;
+
+        results.push(DeployResult {
+            node_name: deploy_data.node_name.to_string(),
+            profile_name: deploy_data.profile_name.to_string(),
+            duration,
+            confirmed: true,
+            confirm_deferred: false,
+            phases: timer.phases(),
+        });
+    }
+
+    Ok(results)
+}
+
+#[tokio::test]
+async fn test_deploy_group_lock_paths() {
+    let node = crate::mock_node(serde_json::json!({
+        "confirmGroup": "example",
+        // Any command run over SSH would fail the deployment
+        "sshOpts": ["-o", "ProxyCommand=false"],
+        "profiles": {
+            "system": { "path": "/nix/store/aaa-system" },
+            "app": { "path": "/nix/store/bbb-app" },
+            "other-app": { "path": "/nix/store/ccc-app", "lockFileName": "deploy-rs-app" },
+            "same-app": { "path": "/nix/store/ddd-app", "lockFileName": "deploy-rs-app" },
+        },
+    }));
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data = |profile_name| {
+        crate::make_deploy_data(
+            &Default::default(),
+            &node,
+            "example",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            &cmd_overrides,
+            false,
+            false,
+            None,
+        )
+    };
+
+    let system = deploy_data("system");
+    let app = deploy_data("app");
+    assert_eq!(profile_lock_path(&system), "/tmp/deploy-rs-canary-aaa-test");
+    assert_eq!(profile_lock_path(&app), "/tmp/deploy-rs-canary-bbb-test");
+
+    let other_app = deploy_data("other-app");
+    let same_app = deploy_data("same-app");
+    let other_app_defs = other_app.defs().unwrap();
+    let same_app_defs = same_app.defs().unwrap();
+
+    let runner = crate::runner::MockRunner::default();
+    let err = deploy_group(
+        &[(&other_app, &other_app_defs), (&same_app, &same_app_defs)],
+        &runner,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        DeployGroupError::LockPathConflict(ref profile, _, _, ref other)
+            if profile == "same-app" && other == "other-app"
+    ));
+    assert!(runner.calls().is_empty());
+}
+
+/// Profiles which are deployed together, either those of a single node or those of every node in
+/// a confirm group
+struct DeployUnit<'a> {
+    name: String,
+    /// The names of the units this one depends on
+    deps: Vec<String>,
+    group: bool,
+    targets: Vec<(&'a super::DeployData<'a>, &'a super::DeployDefs)>,
+}
+
+fn unit_name(node_name: &str, node: &crate::data::Node) -> String {
+    match node.node_settings.confirm_group {
+        Some(ref group) => format!("group {}", group),
+        None => node_name.to_string(),
+    }
+}
+
+/// Splits `targets` into units by their node and `confirmGroup`, keeping their order. Units depend
+/// on the units of the nodes in `dependsOn`.
+fn make_units<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+) -> Vec<DeployUnit<'a>> {
+    let mut units: Vec<DeployUnit> = Vec::new();
+
+    for target in targets {
+        let node = target.0.node;
+        let name = unit_name(target.0.node_name, node);
+
+        let deps: Vec<String> = node
+            .node_settings
+            .depends_on
+            .iter()
+            .map(
+                |dep| match targets.iter().find(|(x, _)| x.node_name == dep) {
+                    Some((dep_data, _)) => unit_name(dep, dep_data.node),
+                    None => dep.to_owned(),
                 },
-            };
+            )
+            .collect();
 
-            if let Some(err) = maybe_err {
-                send_activate.send(err).unwrap();
+        match units.iter_mut().find(|x| x.name == name) {
+            Some(unit) => {
+                for dep in deps {
+                    if !unit.deps.contains(&dep) {
+                        unit.deps.push(dep);
+                    }
+                }
+                unit.targets.push(*target);
+            }
+            None => units.push(DeployUnit {
+                name,
+                deps,
+                group: node.node_settings.confirm_group.is_some(),
+                targets: vec![*target],
+            }),
+        }
+    }
+
+    units
+}
+
+#[derive(Error, Debug)]
+pub enum DeployUnitError {
+    #[error("Failed to deploy profile: {0}")]
+    Profile(#[from] DeployProfileError),
+    #[error("Failed to deploy group: {0}")]
+    Group(#[from] DeployGroupError),
+    #[error("Failed to deploy {} profiles: {}", .0.len(), .0.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("; "))]
+    Profiles(Vec<DeployProfileError>),
+}
+
+impl DeployUnitError {
+    pub fn rolled_back(&self) -> bool {
+        match self {
+            DeployUnitError::Profile(err) => err.rolled_back(),
+            DeployUnitError::Group(err) => err.rolled_back(),
+            DeployUnitError::Profiles(errs) => errs.iter().all(|x| x.rolled_back()),
+        }
+    }
+}
+
+/// Deploys the profiles of `unit`, together as a group, concurrently with
+/// [`deploy_profiles_concurrent`] up to `max_parallel_profiles` at a time, or one after the other.
+/// One after the other, the rest are skipped after a profile fails, as they may rely on the one
+/// before them.
+async fn deploy_unit(
+    unit: &DeployUnit<'_>,
+    runner: &dyn CommandRunner,
+    max_parallel_profiles: Option<usize>,
+    cancel: &CancellationToken,
+) -> Result<Vec<DeployResult>, DeployUnitError> {
+    if unit.group {
+        return Ok(deploy_group(&unit.targets, runner, cancel).await?);
+    }
+
+    if let Some(max_parallel) = max_parallel_profiles {
+        return deploy_profiles_concurrent(&unit.targets, runner, max_parallel, cancel)
+            .await
+            .map_err(DeployUnitError::Profiles);
+    }
+
+    let mut results = Vec::new();
+
+    for (deploy_data, deploy_defs) in &unit.targets {
+        let result = deploy_profile(deploy_data, deploy_defs, runner, cancel).await?;
+
+        debug!(
+            "Activated profile `{}` of node `{}` in {:.1}s{}",
+            result.profile_name,
+            result.node_name,
+            result.duration.as_secs_f64(),
+            match (result.confirmed, result.confirm_deferred) {
+                (true, _) => " and confirmed it",
+                (false, true) => ", leaving it to be confirmed",
+                (false, false) => "",
+            }
+        );
+
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+/// How [`deploy_fleet`] goes about deploying
+#[derive(Debug, Clone)]
+pub struct FleetOptions {
+    /// How many units (nodes, or confirm groups) are deployed at the same time at most, by default
+    /// [`default_max_parallel`]
+    pub max_parallel: Option<usize>,
+    /// How many profiles of a node are deployed at the same time at most, only ordered by their
+    /// `dependsOn`. By default they are deployed one after another.
+    pub max_parallel_profiles: Option<usize>,
+    /// Keep deploying the units which don't depend on one which failed, instead of stopping
+    pub keep_going: bool,
+    /// Check that every node can be reached before deploying to any of them
+    pub check_connectivity: bool,
+}
+
+/// What deploying one unit of a fleet, a node or a confirm group, resulted in
+#[derive(Debug)]
+pub struct DeployOutcome {
+    pub node_names: Vec<String>,
+    pub result: Result<Vec<DeployResult>, DeployUnitError>,
+}
+
+#[derive(Error, Debug)]
+pub enum DeployFleetError {
+    #[error("Invalid node dependencies: {0}")]
+    Dependencies(#[from] crate::graph::DependencyError),
+    #[error("Not deploying anything, as {} nodes can't be reached: {}", .0.len(), .0.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "))]
+    Unreachable(Vec<crate::preflight::ConnectivityError>),
+    #[error("Deploying failed on {} of {} nodes: {}", .failed_nodes.len(), .node_count, .failed_nodes.join(", "))]
+    Failed {
+        node_count: usize,
+        failed_nodes: Vec<String>,
+        /// Every unit which was deployed (or attempted), in the order of the targets
+        outcomes: Vec<DeployOutcome>,
+    },
+}
+
+impl DeployFleetError {
+    /// If every node which failed was rolled back
+    pub fn rolled_back(&self) -> bool {
+        match self {
+            DeployFleetError::Failed { outcomes, .. } => outcomes.iter().all(|x| match x.result {
+                Ok(_) => true,
+                Err(ref err) => err.rolled_back(),
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// Logs how many nodes succeeded, failing if any unit did
+fn summarize_outcomes(
+    node_count: usize,
+    outcomes: Vec<DeployOutcome>,
+) -> Result<Vec<DeployOutcome>, DeployFleetError> {
+    let mut failed_nodes: Vec<String> = Vec::new();
+
+    for outcome in &outcomes {
+        if outcome.result.is_err() {
+            for node_name in &outcome.node_names {
+                if !failed_nodes.contains(node_name) {
+                    failed_nodes.push(node_name.clone());
+                }
+            }
+        }
+    }
+
+    if failed_nodes.is_empty() {
+        info!("Deployed to all {} nodes", node_count);
+
+        return Ok(outcomes);
+    }
+
+    error!(
+        "Deployed to {} of {} nodes, failed on: {}",
+        node_count - failed_nodes.len(),
+        node_count,
+        failed_nodes.join(", ")
+    );
+
+    Err(DeployFleetError::Failed {
+        node_count,
+        failed_nodes,
+        outcomes,
+    })
+}
+
+#[test]
+fn test_summarize_outcomes() {
+    let outcome = |node_names: &[&str], ok| DeployOutcome {
+        node_names: node_names.iter().map(|x| x.to_string()).collect(),
+        result: match ok {
+            true => Ok(Vec::new()),
+            false => Err(DeployProfileError::Aborted.into()),
+        },
+    };
+
+    let outcomes =
+        summarize_outcomes(2, vec![outcome(&["a"], true), outcome(&["b"], true)]).unwrap();
+    assert_eq!(outcomes.len(), 2);
+
+    let err = summarize_outcomes(
+        4,
+        vec![
+            outcome(&["a"], true),
+            outcome(&["b"], false),
+            outcome(&["c", "d"], false),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Deploying failed on 3 of 4 nodes: b, c, d");
+    assert!(!err.rolled_back());
+}
+
+/// Deploys `targets` to their nodes, which are in the order their profiles are deployed. Nodes are
+/// deployed as soon as the nodes they depend on with `dependsOn` are, at most
+/// `options.max_parallel` at a time, and nodes with the same `confirmGroup` together with
+/// [`deploy_group`]. The profiles of any other node are deployed one after another with
+/// [`deploy_profile`], or concurrently with [`deploy_profiles_concurrent`] given
+/// `options.max_parallel_profiles`.
+///
+/// After the first failure, `cancel` is cancelled so that no other node is confirmed, unless
+/// `options.keep_going` is set. Then only the nodes which depend on the one which failed are
+/// skipped. The outcome of every node which was deployed is returned in the order of `targets`, as
+/// part of the error if any failed.
+pub async fn deploy_fleet<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+    runner: &dyn CommandRunner,
+    options: &FleetOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<DeployOutcome>, DeployFleetError> {
+    if options.check_connectivity {
+        crate::preflight::check_fleet_connectivity(targets)
+            .await
+            .map_err(DeployFleetError::Unreachable)?;
+    }
+
+    let mut node_names: Vec<&str> = Vec::new();
+    for (deploy_data, _) in targets {
+        if !node_names.contains(&deploy_data.node_name) {
+            node_names.push(deploy_data.node_name);
+        }
+    }
+
+    let units = make_units(targets);
+    let semaphore = tokio::sync::Semaphore::new(
+        options
+            .max_parallel
+            .unwrap_or_else(|| default_max_parallel(units.len()))
+            .max(1),
+    );
+    let outcomes = std::sync::Mutex::new(Vec::new());
+
+    let (semaphore, outcomes_ref) = (&semaphore, &outcomes);
+
+    let result = crate::graph::run_with_dependencies(
+        units
+            .iter()
+            .enumerate()
+            .map(|(i, unit)| (unit.name.as_str(), &unit.deps[..], (i, unit)))
+            .collect(),
+        |(i, unit)| async move {
+            let _permit = semaphore.acquire().await;
+
+            // Units which were waiting for their turn when deploying was stopped aren't started
+            if cancel.is_cancelled() {
+                return Err(UnitFailed::Skipped);
+            }
+
+            let result = deploy_unit(unit, runner, options.max_parallel_profiles, cancel).await;
+            let failed = result.is_err();
+
+            if let Err(ref err) = result {
+                error!("{}", err);
+
+                if !options.keep_going {
+                    cancel.cancel();
+                }
             }
 
-            send_activated.send(()).unwrap();
+            let mut unit_nodes: Vec<String> = Vec::new();
+            for (deploy_data, _) in &unit.targets {
+                if !unit_nodes.iter().any(|x| x == deploy_data.node_name) {
+                    unit_nodes.push(deploy_data.node_name.to_string());
+                }
+            }
+
+            outcomes_ref.lock().unwrap().push((
+                i,
+                DeployOutcome {
+                    node_names: unit_nodes,
+                    result,
+                },
+            ));
+
+            match failed {
+                true => Err(UnitFailed::Deploy),
+                false => Ok(()),
+            }
+        },
+        options.keep_going,
+    )
+    .await;
+
+    if let Err(UnitFailed::Dependencies(err)) = result {
+        return Err(err.into());
+    }
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    // Deterministic, whichever unit finished first
+    outcomes.sort_by_key(|(i, _)| *i);
+    let outcomes: Vec<DeployOutcome> = outcomes.into_iter().map(|(_, x)| x).collect();
+
+    for unit in &units {
+        let deployed = outcomes.iter().any(|x| {
+            x.node_names
+                .iter()
+                .any(|x| x == unit.targets[0].0.node_name)
         });
 
-        tokio::select! {
-            x = ssh_wait_command.arg(self_wait_command).status() => {
-                debug!("Wait command ended");
-                match x.map_err(DeployProfileError::SSHWaitError)?.code() {
-                    Some(0) => (),
-                    a => return Err(DeployProfileError::SSHWaitExitError(a)),
-                };
+        if !deployed {
+            warn!(
+                "Skipped deploying `{}`, as deploying failed before it started",
+                unit.name
+            );
+        }
+    }
+
+    summarize_outcomes(node_names.len(), outcomes)
+}
+
+/// Deploys `targets` like [`deploy_fleet`] with its default options, only continuing with the nodes
+/// which don't depend on one which failed given `keep_going`. Every outcome is returned, as part of
+/// the error if any node failed.
+pub async fn deploy_all<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+    runner: &dyn CommandRunner,
+    keep_going: bool,
+) -> Result<Vec<DeployOutcome>, DeployFleetError> {
+    let options = FleetOptions {
+        max_parallel: None,
+        max_parallel_profiles: None,
+        keep_going,
+        check_connectivity: false,
+    };
+
+    deploy_fleet(targets, runner, &options, &CancellationToken::new()).await
+}
+
+/// Why a unit passed to [`crate::graph::run_with_dependencies`] failed, the errors of deploying
+/// them are kept with their outcomes
+enum UnitFailed {
+    Dependencies(crate::graph::DependencyError),
+    Deploy,
+    Skipped,
+}
+
+impl From<crate::graph::DependencyError> for UnitFailed {
+    fn from(err: crate::graph::DependencyError) -> Self {
+        UnitFailed::Dependencies(err)
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_fleet() {
+    use crate::runner::{MockResponse, MockRunner};
+
+    let node_names = ["a", "b", "c", "d", "e"];
+    let nodes: Vec<crate::data::Node> = node_names
+        .iter()
+        .map(|node_name| {
+            crate::mock_node(serde_json::json!({
+                "hostname": format!("{}.example.com", node_name),
+                "magicRollback": false,
+                "profiles": {
+                    "system": {
+                        "path": "/nix/store/blah-system",
+                        "profilePath": format!("/nix/var/nix/profiles/system-{}", node_name),
+                    },
+                },
+            }))
+        })
+        .collect();
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data: Vec<_> = node_names
+        .iter()
+        .zip(&nodes)
+        .map(|(node_name, node)| {
+            crate::make_deploy_data(
+                &Default::default(),
+                node,
+                node_name,
+                &node.node_settings.profiles["system"],
+                "system",
+                &cmd_overrides,
+                false,
+                false,
+                None,
+            )
+        })
+        .collect();
+    let deploy_defs: Vec<_> = deploy_data.iter().map(|x| x.defs().unwrap()).collect();
+    let targets: Vec<_> = deploy_data.iter().zip(&deploy_defs).collect();
+
+    // Activating takes long enough for the other deployments to start meanwhile, if they may, and
+    // longest on `a`, so that it finishes last
+    let responses = vec![
+        profile_link_response(),
+        (
+            " activate /nix/store/blah-system /nix/var/nix/profiles/system-a",
+            MockResponse {
+                duration: Some(Duration::from_millis(100)),
+                ..MockResponse::exit(0)
             },
-            x = recv_activate => {
-                debug!("Activate command exited with an error");
-                return Err(x.unwrap());
+        ),
+        (
+            " activate /",
+            MockResponse {
+                duration: Some(Duration::from_millis(20)),
+                ..MockResponse::exit(0)
+            },
+        ),
+    ];
+    let runner = MockRunner::new(responses.clone());
+
+    let options = FleetOptions {
+        max_parallel: Some(2),
+        max_parallel_profiles: None,
+        keep_going: false,
+        check_connectivity: false,
+    };
+    let outcomes = deploy_fleet(&targets, &runner, &options, &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(runner.max_running(), 2);
+    let outcome_nodes: Vec<_> = outcomes.iter().map(|x| x.node_names.join(",")).collect();
+    assert_eq!(outcome_nodes, node_names);
+    assert!(outcomes
+        .iter()
+        .all(|x| matches!(x.result, Ok(ref results) if results.len() == 1)));
+
+    // Every node at once by default, as there are fewer than 10
+    let options = FleetOptions {
+        max_parallel: None,
+        ..options
+    };
+    let runner = MockRunner::new(responses);
+    deploy_fleet(&targets, &runner, &options, &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(runner.max_running(), 5);
+
+    assert_eq!(default_max_parallel(0), 1);
+    assert_eq!(default_max_parallel(5), 5);
+    assert_eq!(default_max_parallel(40), 10);
+}
+
+#[tokio::test]
+async fn test_deploy_fleet_keep_going() {
+    use crate::runner::{MockResponse, MockRunner};
+
+    let node = |system: &str, depends_on: &[&str]| {
+        crate::mock_node(serde_json::json!({
+            "magicRollback": false,
+            "dependsOn": depends_on,
+            "profiles": {
+                "system": { "path": system },
+                "app": { "path": "/nix/store/blah-app" },
             },
+        }))
+    };
+    let node_names = ["a", "b", "c", "d"];
+    let nodes = [
+        node("/nix/store/blah-system", &[]),
+        node("/nix/store/broken-system", &[]),
+        node("/nix/store/blah-system", &[]),
+        node("/nix/store/blah-system", &["b"]),
+    ];
+
+    let cmd_overrides = crate::CmdOverrides {
+        run_id: Some("test".to_string()),
+        ..Default::default()
+    };
+
+    let mut deploy_data = Vec::new();
+    for (node_name, node) in node_names.iter().zip(&nodes) {
+        for profile_name in &["system", "app"] {
+            deploy_data.push(crate::make_deploy_data(
+                &Default::default(),
+                node,
+                node_name,
+                &node.node_settings.profiles[*profile_name],
+                profile_name,
+                &cmd_overrides,
+                false,
+                false,
+                None,
+            ));
         }
+    }
+    let deploy_defs: Vec<_> = deploy_data.iter().map(|x| x.defs().unwrap()).collect();
+    let targets: Vec<_> = deploy_data.iter().zip(&deploy_defs).collect();
+
+    // Only activating the system profile of `b` fails
+    let runner = MockRunner::new(vec![
+        ("broken-system", MockResponse::exit(1)),
+        (
+            "readlink '/nix/var/nix/profiles/system'",
+            MockResponse {
+                stdout: MOCK_PROFILE_LINK.to_string(),
+                ..MockResponse::exit(0)
+            },
+        ),
+        (
+            "readlink",
+            MockResponse {
+                stdout: "app-7-link\n/nix/store/blah-app\n".to_string(),
+                ..MockResponse::exit(0)
+            },
+        ),
+    ]);
 
-        info!("Success activating, attempting to confirm activation");
+    let options = FleetOptions {
+        max_parallel: Some(1),
+        max_parallel_profiles: None,
+        keep_going: true,
+        check_connectivity: false,
+    };
+    let cancel = CancellationToken::new();
+    let err = deploy_fleet(&targets, &runner, &options, &cancel)
+        .await
+        .unwrap_err();
+    assert!(!cancel.is_cancelled());
 
-        let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
-        recv_activated.await.unwrap();
-        c?;
+    match err {
+        DeployFleetError::Failed {
+            node_count,
+            failed_nodes,
+            outcomes,
+        } => {
+            assert_eq!(node_count, 4);
+            assert_eq!(failed_nodes, vec!["b"]);
+            // `d` depends on `b`, so it is skipped, as is the app of `b`
+            assert_eq!(
+                outcomes
+                    .iter()
+                    .map(|x| (
+                        x.node_names[0].as_str(),
+                        x.result.as_ref().map(|x| x.len()).ok()
+                    ))
+                    .collect::<Vec<_>>(),
+                vec![("a", Some(2)), ("b", None), ("c", Some(2))]
+            );
+        }
+        x => panic!("expected a failed fleet, got {:?}", x),
     }
 
-    Ok(())
+    // Without keep going, nothing is deployed after `b`
+    let options = FleetOptions {
+        keep_going: false,
+        ..options
+    };
+    let err = deploy_fleet(&targets, &runner, &options, &cancel)
+        .await
+        .unwrap_err();
+    assert!(cancel.is_cancelled());
+    assert!(matches!(err, DeployFleetError::Failed { ref outcomes, .. } if outcomes.len() == 2));
+
+    let err = deploy_all(&targets, &runner, true).await.unwrap_err();
+    assert!(matches!(
+        err,
+        DeployFleetError::Failed { ref failed_nodes, ref outcomes, .. }
+            if failed_nodes == &["b"] && outcomes.len() == 3
+    ));
 }
@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DependencyError {
+    #[error("`{0}` depends on `{1}`, which is not defined")]
+    Unknown(String, String),
+    #[error("Dependency cycle detected: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+}
+
+/// Checks that every dependency refers to a known entry, and that the dependencies contain no cycles
+pub fn check_dependencies(deps: &HashMap<&str, &[String]>) -> Result<(), DependencyError> {
+    let mut names: Vec<&str> = deps.keys().copied().collect();
+    // Sorted so that the reported cycle is always the same one
+    names.sort_unstable();
+
+    for name in &names {
+        for dep in deps[name].iter() {
+            if !deps.contains_key(dep.as_str()) {
+                return Err(DependencyError::Unknown(name.to_string(), dep.to_owned()));
+            }
+        }
+    }
+
+    let mut finished: HashSet<&str> = HashSet::new();
+
+    for name in names {
+        let mut path = Vec::new();
+        find_cycle(name, deps, &mut path, &mut finished)?;
+    }
+
+    Ok(())
+}
+
+fn find_cycle<'a>(
+    name: &'a str,
+    deps: &HashMap<&'a str, &'a [String]>,
+    path: &mut Vec<&'a str>,
+    finished: &mut HashSet<&'a str>,
+) -> Result<(), DependencyError> {
+    if finished.contains(name) {
+        return Ok(());
+    }
+
+    if let Some(start) = path.iter().position(|x| *x == name) {
+        let mut cycle: Vec<String> = path[start..].iter().map(|x| x.to_string()).collect();
+        cycle.push(name.to_string());
+        return Err(DependencyError::Cycle(cycle));
+    }
+
+    path.push(name);
+    for dep in deps[name].iter() {
+        find_cycle(dep, deps, path, finished)?;
+    }
+    path.pop();
+
+    finished.insert(name);
+
+    Ok(())
+}
+
+/// Orders `names` so that every entry comes after its dependencies, otherwise keeping the order of
+/// `names`, after checking the dependencies like [`check_dependencies`]. Dependencies which are
+/// not part of `names` are considered satisfied.
+pub fn sort_by_dependencies<'a>(
+    names: &[&'a str],
+    deps: &HashMap<&'a str, &'a [String]>,
+) -> Result<Vec<&'a str>, DependencyError> {
+    check_dependencies(deps)?;
+
+    let mut pending: Vec<&str> = names.to_vec();
+    let mut sorted: Vec<&str> = Vec::new();
+
+    // Without cycles there is always an entry whose dependencies are all sorted already
+    while !pending.is_empty() {
+        let i = pending
+            .iter()
+            .position(|name| match deps.get(name) {
+                Some(x) => x
+                    .iter()
+                    .all(|dep| sorted.contains(&dep.as_str()) || !names.contains(&dep.as_str())),
+                None => true,
+            })
+            .expect("dependencies contain no cycles");
+
+        sorted.push(pending.remove(i));
+    }
+
+    Ok(sorted)
+}
+
+/// Runs `f` for every entry, starting an entry only once all of its dependencies have finished
+/// successfully, and running everything else concurrently. Dependencies on names which are not
+/// part of `entries` are considered satisfied.
+///
+/// After the first error no further entries are started, but the ones already running are
+/// allowed to finish (they may be in the middle of an activation), then the first error is returned.
+/// With `keep_going`, the entries which don't depend on one which failed are still started.
+pub async fn run_with_dependencies<'a, T, F, Fut, E>(
+    entries: Vec<(&'a str, &'a [String], T)>,
+    f: F,
+    keep_going: bool,
+) -> Result<(), E>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: From<DependencyError>,
+{
+    let selected: HashSet<&str> = entries.iter().map(|(name, _, _)| *name).collect();
+
+    let deps: HashMap<&str, &[String]> = entries
+        .iter()
+        .map(|(name, deps, _)| (*name, *deps))
+        .collect();
+    let selected_deps: Vec<(&str, Vec<String>)> = deps
+        .iter()
+        .map(|(name, deps)| {
+            (
+                *name,
+                deps.iter()
+                    .filter(|dep| selected.contains(dep.as_str()))
+                    .cloned()
+                    .collect(),
+            )
+        })
+        .collect();
+    check_dependencies(
+        &selected_deps
+            .iter()
+            .map(|(name, deps)| (*name, &deps[..]))
+            .collect(),
+    )?;
+
+    let f = &f;
+
+    let mut pending = entries;
+    let mut done: HashSet<&str> = HashSet::new();
+    let mut running = FuturesUnordered::new();
+    let mut first_err = None;
+
+    loop {
+        if first_err.is_none() || keep_going {
+            let mut i = 0;
+            while i < pending.len() {
+                let ready = pending[i]
+                    .1
+                    .iter()
+                    .all(|dep| done.contains(dep.as_str()) || !selected.contains(dep.as_str()));
+
+                if ready {
+                    let (name, _, x) = pending.remove(i);
+                    debug!("Dependencies of `{}` are done, starting it", name);
+                    running.push(async move { (name, f(x).await) });
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        match running.next().await {
+            Some((name, Ok(()))) => {
+                done.insert(name);
+            }
+            Some((_, Err(err))) => {
+                if first_err.is_none() {
+                    first_err = Some(err);
+                }
+            }
+            None => break,
+        }
+    }
+
+    for (name, _, _) in &pending {
+        debug!("`{}` was not started, as something failed before", name);
+    }
+
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_check_dependencies() {
+    let none: Vec<String> = vec![];
+    let on_a = ["a".to_string()];
+    let on_b = ["b".to_string()];
+    let on_c = ["c".to_string()];
+    let on_x = ["x".to_string()];
+
+    let ok: HashMap<&str, &[String]> = vec![("a", &none[..]), ("b", &on_a[..]), ("c", &on_a[..])]
+        .into_iter()
+        .collect();
+    assert_eq!(check_dependencies(&ok), Ok(()));
+
+    let unknown: HashMap<&str, &[String]> = vec![("a", &none[..]), ("b", &on_x[..])]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        check_dependencies(&unknown),
+        Err(DependencyError::Unknown("b".to_string(), "x".to_string()))
+    );
+
+    let cycle: HashMap<&str, &[String]> =
+        vec![("a", &on_c[..]), ("b", &on_a[..]), ("c", &on_b[..])]
+            .into_iter()
+            .collect();
+    assert_eq!(
+        check_dependencies(&cycle),
+        Err(DependencyError::Cycle(vec![
+            "a".to_string(),
+            "c".to_string(),
+            "b".to_string(),
+            "a".to_string()
+        ]))
+    );
+}
+
+#[test]
+fn test_sort_by_dependencies() {
+    let none: Vec<String> = vec![];
+    let on_a = ["a".to_string()];
+    let on_b = ["b".to_string()];
+    let on_c = ["c".to_string()];
+    let on_b_c = ["b".to_string(), "c".to_string()];
+
+    let chain: HashMap<&str, &[String]> =
+        vec![("a", &none[..]), ("b", &on_a[..]), ("c", &on_b[..])]
+            .into_iter()
+            .collect();
+    assert_eq!(
+        sort_by_dependencies(&["c", "b", "a"], &chain),
+        Ok(vec!["a", "b", "c"])
+    );
+
+    // Entries which don't depend on each other keep their order
+    let diamond: HashMap<&str, &[String]> = vec![
+        ("a", &none[..]),
+        ("b", &on_a[..]),
+        ("c", &on_a[..]),
+        ("d", &on_b_c[..]),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        sort_by_dependencies(&["d", "c", "b", "a"], &diamond),
+        Ok(vec!["a", "c", "b", "d"])
+    );
+    assert_eq!(
+        sort_by_dependencies(&["a", "b", "c", "d"], &diamond),
+        Ok(vec!["a", "b", "c", "d"])
+    );
+
+    let cycle: HashMap<&str, &[String]> =
+        vec![("a", &on_c[..]), ("b", &on_a[..]), ("c", &on_b[..])]
+            .into_iter()
+            .collect();
+    assert_eq!(
+        sort_by_dependencies(&["a", "b", "c"], &cycle),
+        Err(DependencyError::Cycle(vec![
+            "a".to_string(),
+            "c".to_string(),
+            "b".to_string(),
+            "a".to_string()
+        ]))
+    );
+}
+
+#[tokio::test]
+async fn test_run_with_dependencies() {
+    use std::sync::Mutex;
+
+    let none: Vec<String> = vec![];
+    let on_a = ["a".to_string()];
+    let on_b = ["b".to_string(), "not-selected".to_string()];
+
+    let events = Mutex::new(Vec::new());
+
+    run_with_dependencies::<_, _, _, DependencyError>(
+        vec![
+            ("c", &on_b[..], "c"),
+            ("b", &on_a[..], "b"),
+            ("a", &none[..], "a"),
+        ],
+        |name| {
+            let events = &events;
+            async move {
+                events.lock().unwrap().push(format!("start {}", name));
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                events.lock().unwrap().push(format!("end {}", name));
+                Ok(())
+            }
+        },
+        false,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        events.into_inner().unwrap(),
+        vec!["start a", "end a", "start b", "end b", "start c", "end c"]
+    );
+}
+
+#[tokio::test]
+async fn test_run_with_dependencies_stops_after_error() {
+    let none: Vec<String> = vec![];
+    let on_a = ["a".to_string()];
+    let on_d = ["d".to_string()];
+    let (none, on_a, on_d) = (&none, &on_a, &on_d);
+
+    let run = |keep_going| async move {
+        let started = std::sync::Mutex::new(Vec::new());
+
+        let result = run_with_dependencies(
+            vec![
+                ("a", &none[..], "a"),
+                ("b", &on_a[..], "b"),
+                ("c", &on_d[..], "c"),
+                ("d", &none[..], "d"),
+            ],
+            |name| {
+                let started = &started;
+                async move {
+                    started.lock().unwrap().push(name);
+                    match name {
+                        "a" => Err(DependencyError::Unknown(name.to_string(), String::new())),
+                        _ => {
+                            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                            Ok(())
+                        }
+                    }
+                }
+            },
+            keep_going,
+        )
+        .await;
+
+        (result, started.into_inner().unwrap())
+    };
+
+    // `d` was already running when `a` failed
+    let (result, started) = run(false).await;
+    assert!(result.is_err());
+    assert_eq!(started, vec!["a", "d"]);
+
+    // Only what depends on `a` is left out
+    let (result, started) = run(true).await;
+    assert!(result.is_err());
+    assert_eq!(started, vec!["a", "d", "c"]);
+}
@@ -7,14 +7,119 @@ use rnix::{types::*, SyntaxKind::*};
 
 use merge::Merge;
 
+use std::collections::HashMap;
+
 use thiserror::Error;
 
 use flexi_logger::*;
 
-pub fn make_lock_path(temp_path: &str, closure: &str) -> String {
-    let lock_hash =
-        &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
-    format!("{}/deploy-rs-canary-{}", temp_path, lock_hash)
+pub fn make_lock_path(temp_path: &str, closure: &str, lock_file_name: Option<&str>) -> String {
+    if let Some(lock_file_name) = lock_file_name {
+        return format!("{}/{}", temp_path, lock_file_name);
+    }
+
+    format!("{}/{}", temp_path, make_default_lock_file_name(closure))
+}
+
+fn make_default_lock_file_name(closure: &str) -> String {
+    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
+    format!("deploy-rs-canary-{}", lock_hash)
+}
+
+/// The default lock file name of a profile deployed in the run `run_id`, so that concurrent runs
+/// of the same closure don't share a lock
+fn make_run_lock_file_name(closure: &str, run_id: &str) -> String {
+    format!("{}-{}", make_default_lock_file_name(closure), run_id)
+}
+
+#[test]
+fn test_make_run_lock_file_name() {
+    let closure = "/nix/store/abc123-system";
+
+    assert_eq!(
+        make_run_lock_file_name(closure, "ci-42"),
+        "deploy-rs-canary-abc123-ci-42"
+    );
+    assert_eq!(
+        make_lock_path("/tmp", closure, None),
+        "/tmp/deploy-rs-canary-abc123"
+    );
+}
+
+/// A hash of the (node, profile, closure) triples a deployment would deploy, independent of their
+/// order. This uses 64 bit FNV-1a, which is stable across builds, unlike the hashers of `std`.
+pub fn make_plan_hash(targets: &[(&str, &str, &str)]) -> String {
+    let mut targets = targets.to_vec();
+    targets.sort_unstable();
+    targets.dedup();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for (node, profile, closure) in targets {
+        for part in &[node, profile, closure] {
+            // Separating the parts keeps `("ab", "c")` and `("a", "bc")` apart
+            for byte in part.bytes().chain(std::iter::once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+
+    format!("{:016x}", hash)
+}
+
+#[test]
+fn test_make_plan_hash() {
+    let web = ("web01", "system", "/nix/store/abc-system");
+    let db = ("db01", "system", "/nix/store/def-system");
+
+    assert_eq!(make_plan_hash(&[]), "cbf29ce484222325");
+    assert_eq!(make_plan_hash(&[web, db]), make_plan_hash(&[db, web]));
+    assert_ne!(
+        make_plan_hash(&[web, db]),
+        make_plan_hash(&[web, ("db01", "system", "/nix/store/xyz-system")])
+    );
+    assert_ne!(
+        make_plan_hash(&[("ab", "c", "d")]),
+        make_plan_hash(&[("a", "bc", "d")])
+    );
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RunIdError {
+    #[error("Run id `{0}` may only contain letters, digits, `.`, `_` and `-`")]
+    InvalidCharacters(String),
+}
+
+/// Makes a run id unique to this invocation
+pub fn make_run_id() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    format!("{}-{}", secs, std::process::id())
+}
+
+/// Run ids end up in file names on the nodes
+pub fn check_run_id(run_id: &str) -> Result<(), RunIdError> {
+    if run_id.is_empty()
+        || !run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+    {
+        return Err(RunIdError::InvalidCharacters(run_id.to_string()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_run_id() {
+    assert_eq!(check_run_id("ci-main_42.1"), Ok(()));
+    assert!(check_run_id(&make_run_id()).is_ok());
+    assert!(check_run_id("a/b").is_err());
+    assert!(check_run_id("").is_err());
 }
 
 fn make_emoji(level: log::Level) -> &'static str {
@@ -125,21 +230,249 @@ pub fn init_logger(
     Ok(())
 }
 
+pub mod checks;
 pub mod data;
 pub mod deploy;
+pub mod graph;
+pub mod logs;
+pub mod preflight;
 pub mod push;
+pub mod runner;
+pub mod telemetry;
 
-#[derive(Debug)]
+/// How lifecycle events of deployments are reported
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Only through the human readable logs
+    #[default]
+    Text,
+    /// Also as a line of JSON each, on standard output
+    Json,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Unknown log format `{0}`, expected `text` or `json`")]
+pub struct ParseLogFormatError(String);
+
+impl LogFormat {
+    /// Where the commands deploy-rs runs write their standard output, which is standard error with
+    /// `json` so that standard output only carries the events
+    pub fn child_stdout(self) -> std::process::Stdio {
+        match self {
+            LogFormat::Text => std::process::Stdio::inherit(),
+            LogFormat::Json => std::io::stderr().into(),
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ParseLogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            x => Err(ParseLogFormatError(x.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
-    pub ssh_opts: Option<String>,
+    pub ssh_opts: Option<Vec<String>>,
+    pub ssh_command: Option<String>,
     pub fast_connection: Option<bool>,
     pub auto_rollback: Option<bool>,
     pub hostname: Option<String>,
+    pub ssh_port: Option<u16>,
     pub magic_rollback: Option<bool>,
     pub temp_path: Option<String>,
     pub confirm_timeout: Option<u16>,
+    /// Applied after everything else, to matching targets only
+    pub targeted: Vec<TargetedOverride>,
+    /// Namespaces the artifacts of this run on the nodes, like lock files
+    pub run_id: Option<String>,
+    /// Print the commands deploying would run over SSH, instead of running them
+    pub dry_run: bool,
+    pub log_format: LogFormat,
+    /// Deploy without asking, even nodes with `requireConfirmation`
+    pub yes: bool,
+    /// Never color the log, such as for CI
+    pub no_color: bool,
+    /// Deploy the closure read from here instead of the evaluated one
+    pub closure_from: Option<ClosureSource>,
+    /// Print the resolved deployment of each target as JSON, instead of deploying
+    pub print_plan: bool,
+    /// How many nodes are deployed at the same time at most
+    pub max_parallel: Option<usize>,
+    /// How many profiles of a node are deployed at the same time at most
+    pub max_parallel_profiles: Option<usize>,
+    /// Keep deploying the nodes which don't depend on one which failed
+    pub keep_going: bool,
+    /// Check that every node can be reached before deploying to any of them
+    pub check_connectivity: bool,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum OverrideSelector {
+    Node(String),
+    Profile(String),
+}
+
+impl std::fmt::Display for OverrideSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverrideSelector::Node(x) => write!(f, "node:{}", x),
+            OverrideSelector::Profile(x) => write!(f, "profile:{}", x),
+        }
+    }
+}
+
+/// A setting given with `--set <selector> <setting>=<value>`
+#[derive(PartialEq, Debug)]
+pub struct TargetedOverride {
+    pub selector: OverrideSelector,
+    pub settings: data::GenericSettings,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseOverrideError {
+    #[error("Unknown selector `{0}`, expected `node:<name>` or `profile:<name>`")]
+    Selector(String),
+    #[error("Expected `<setting>=<value>`, got `{0}`")]
+    Assignment(String),
+    #[error("Invalid value for `{0}`: {1}")]
+    Value(String, serde_json::Error),
+    #[error("Unknown setting `{0}`")]
+    UnknownSetting(String),
+}
+
+/// Setting names are accepted both as in Nix (`autoRollback`) and as on the CLI (`auto_rollback`)
+fn snake_to_camel(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = false;
+
+    for c in name.chars() {
+        match c {
+            '_' | '-' => upper = true,
+            c if upper => {
+                out.extend(c.to_uppercase());
+                upper = false;
+            }
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+impl TargetedOverride {
+    /// Values are parsed as JSON, falling back to a plain string
+    pub fn parse(selector: &str, assignment: &str) -> Result<TargetedOverride, ParseOverrideError> {
+        let selector = match selector.split_once(':') {
+            Some(("node", name)) => OverrideSelector::Node(name.to_string()),
+            Some(("profile", name)) => OverrideSelector::Profile(name.to_string()),
+            _ => return Err(ParseOverrideError::Selector(selector.to_string())),
+        };
+
+        let (setting, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| ParseOverrideError::Assignment(assignment.to_string()))?;
+
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+
+        let mut object = serde_json::Map::new();
+        object.insert(snake_to_camel(setting), value);
+
+        let settings: data::GenericSettings =
+            serde_json::from_value(serde_json::Value::Object(object))
+                .map_err(|err| ParseOverrideError::Value(setting.to_string(), err))?;
+
+        // Unknown fields are ignored when deserializing, which leaves every setting unset
+        if settings == data::GenericSettings::default() {
+            return Err(ParseOverrideError::UnknownSetting(setting.to_string()));
+        }
+
+        Ok(TargetedOverride { selector, settings })
+    }
+
+    pub fn matches(&self, node_name: &str, profile_name: &str) -> bool {
+        match &self.selector {
+            OverrideSelector::Node(x) => x == node_name,
+            OverrideSelector::Profile(x) => x == profile_name,
+        }
+    }
+}
+
+/// The overrides in `targeted` which match none of the `(node, profile)` pairs in `targets`, like
+/// because of a typo in their selector
+pub fn unmatched_overrides<'a>(
+    targeted: &'a [TargetedOverride],
+    targets: &[(&str, &str)],
+) -> Vec<&'a TargetedOverride> {
+    targeted
+        .iter()
+        .filter(|x| {
+            !targets
+                .iter()
+                .any(|(node_name, profile_name)| x.matches(node_name, profile_name))
+        })
+        .collect()
+}
+
+#[test]
+fn test_unmatched_overrides() {
+    let targeted = vec![
+        TargetedOverride::parse("node:web01", "auto_rollback=false").unwrap(),
+        TargetedOverride::parse("node:web1", "auto_rollback=false").unwrap(),
+        TargetedOverride::parse("profile:system", "tempPath=/var/tmp").unwrap(),
+        TargetedOverride::parse("profile:sytem", "tempPath=/var/tmp").unwrap(),
+    ];
+    let targets = [("web01", "system"), ("web02", "app")];
+
+    let unmatched: Vec<String> = unmatched_overrides(&targeted, &targets)
+        .iter()
+        .map(|x| x.selector.to_string())
+        .collect();
+    assert_eq!(unmatched, vec!["node:web1", "profile:sytem"]);
+    assert_eq!(unmatched_overrides(&targeted, &[]).len(), 4);
+}
+
+#[test]
+fn test_parse_targeted_override() {
+    let o = TargetedOverride::parse("node:web01", "auto_rollback=false").unwrap();
+    assert_eq!(o.selector, OverrideSelector::Node("web01".to_string()));
+    assert_eq!(o.settings.auto_rollback, Some(false));
+    assert!(o.matches("web01", "system"));
+    assert!(!o.matches("web02", "system"));
+
+    let o = TargetedOverride::parse("profile:system", "tempPath=/var/tmp").unwrap();
+    assert_eq!(o.settings.temp_path, Some("/var/tmp".to_string()));
+    assert!(o.matches("web02", "system"));
+
+    let o = TargetedOverride::parse("node:web01", "confirm_timeout=60").unwrap();
+    assert_eq!(o.settings.confirm_timeout, Some(60));
+
+    assert!(matches!(
+        TargetedOverride::parse("host:web01", "auto_rollback=false"),
+        Err(ParseOverrideError::Selector(_))
+    ));
+    assert!(matches!(
+        TargetedOverride::parse("node:web01", "auto_rollback"),
+        Err(ParseOverrideError::Assignment(_))
+    ));
+    assert!(matches!(
+        TargetedOverride::parse("node:web01", "auto_rollback=maybe"),
+        Err(ParseOverrideError::Value(_, _))
+    ));
+    assert!(matches!(
+        TargetedOverride::parse("node:web01", "no_such_setting=1"),
+        Err(ParseOverrideError::UnknownSetting(_))
+    ));
 }
 
 #[derive(PartialEq, Debug)]
@@ -156,7 +489,7 @@ pub enum ParseFlakeError {
     #[error("Unrecognized node or token encountered")]
     Unrecognized,
 }
-pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
+pub fn parse_flake(flake: &str) -> Result<DeployFlake<'_>, ParseFlakeError> {
     let flake_fragment_start = flake.find('#');
     let (repo, maybe_fragment) = match flake_fragment_start {
         Some(s) => (&flake[..s], Some(&flake[s + 1..])),
@@ -287,6 +620,341 @@ fn test_parse_flake() {
     );
 }
 
+/// Where the closure of a profile is read from (`--closure-from`), instead of evaluating it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClosureSource {
+    File(std::path::PathBuf),
+    /// Given as `-`
+    Stdin,
+}
+
+impl std::str::FromStr for ClosureSource {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "-" => Ok(ClosureSource::Stdin),
+            x => Ok(ClosureSource::File(x.into())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReadClosureError {
+    #[error("Failed to read the closure from {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("`{0}` is not a store path like `/nix/store/<hash>-<name>`")]
+    NotStorePath(String),
+    #[error("A closure can only be given when deploying a single profile, like `.#node.profile`")]
+    NoProfile,
+}
+
+/// Whether `path` is an entry directly in the Nix store, like `/nix/store/<hash>-<name>`
+fn is_store_path(path: &str) -> bool {
+    let entry = match path.strip_prefix("/nix/store/") {
+        Some(x) => x,
+        None => return false,
+    };
+
+    // Hashes are in the base 32 of Nix, which leaves out `e`, `o`, `t` and `u`
+    let is_hash_char =
+        |c: char| c.is_ascii_digit() || (c.is_ascii_lowercase() && !"eotu".contains(c));
+
+    match entry.split_at_checked(32) {
+        Some((hash, name)) => {
+            hash.chars().all(is_hash_char)
+                && name.len() > 1
+                && name.starts_with('-')
+                && !name.contains('/')
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test_is_store_path() {
+    assert!(is_store_path(
+        "/nix/store/9hb3y1w5cmsphxq3cjmq4xcb0ylx6fbg-nixos-system-web01"
+    ));
+    assert!(!is_store_path(
+        "/nix/store/9hb3y1w5cmsphxq3cjmq4xcb0ylx6fbg-nixos-system-web01/activate"
+    ));
+    assert!(!is_store_path(
+        "/nix/store/9hb3y1w5cmsphxq3cjmq4xcb0ylx6fbg"
+    ));
+    assert!(!is_store_path(
+        "/nix/store/EHB3Y1W5CMSPHXQ3CJMQ4XCB0YLX6FBG-nixos-system-web01"
+    ));
+    assert!(!is_store_path("/nix/store/short-hash"));
+    assert!(!is_store_path("./result"));
+}
+
+/// Reads the closure from `source`, with `stdin` standing in for standard input
+pub fn read_closure(
+    source: &ClosureSource,
+    mut stdin: impl std::io::Read,
+) -> Result<String, ReadClosureError> {
+    let (name, read) = match source {
+        ClosureSource::File(path) => (path.display().to_string(), std::fs::read_to_string(path)),
+        ClosureSource::Stdin => {
+            let mut closure = String::new();
+            let read = stdin.read_to_string(&mut closure).map(|_| closure);
+            ("standard input".to_string(), read)
+        }
+    };
+
+    let closure = read.map_err(|err| ReadClosureError::Read(name, err))?;
+    let closure = closure.trim();
+
+    if !is_store_path(closure) {
+        return Err(ReadClosureError::NotStorePath(closure.to_string()));
+    }
+
+    Ok(closure.to_string())
+}
+
+#[test]
+fn test_read_closure() {
+    let closure = "/nix/store/9hb3y1w5cmsphxq3cjmq4xcb0ylx6fbg-nixos-system-web01";
+
+    let path = std::env::temp_dir().join(format!("deploy-rs-closure-{}", std::process::id()));
+    std::fs::write(&path, format!("{}\n", closure)).unwrap();
+    let from_file = read_closure(&ClosureSource::File(path.clone()), std::io::empty());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(from_file.unwrap(), closure);
+
+    assert_eq!(
+        read_closure(&ClosureSource::Stdin, format!("{}\n", closure).as_bytes()).unwrap(),
+        closure
+    );
+    assert_eq!("-".parse::<ClosureSource>().unwrap(), ClosureSource::Stdin);
+
+    assert!(matches!(
+        read_closure(&ClosureSource::Stdin, &b"./result\n"[..]),
+        Err(ReadClosureError::NotStorePath(x)) if x == "./result"
+    ));
+    assert!(matches!(
+        read_closure(&ClosureSource::File(path), std::io::empty()),
+        Err(ReadClosureError::Read(..))
+    ));
+}
+
+/// Replaces the path of the profile selected by `deploy_flake` with `closure`, which is deployed
+/// without being built. Unknown nodes and profiles are left for deploying to report.
+pub fn override_closure(
+    data: &mut data::Data,
+    deploy_flake: &DeployFlake<'_>,
+    closure: String,
+) -> Result<(), ReadClosureError> {
+    let (node_name, profile_name) = match (&deploy_flake.node, &deploy_flake.profile) {
+        (Some(node_name), Some(profile_name)) => (node_name, profile_name),
+        _ => return Err(ReadClosureError::NoProfile),
+    };
+
+    let settings = match data.nodes.get_mut(node_name) {
+        Some(node) => match node.node_settings.profiles.get_mut(profile_name) {
+            Some(profile) => &mut profile.profile_settings,
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    settings.path = closure;
+    settings.variant = None;
+    settings.prebuilt = true;
+
+    Ok(())
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SelectVariantError {
+    #[error("Profile `{1}` of node `{2}` has no variant `{0}`")]
+    NotFound(String, String, String),
+}
+
+/// Replaces the path of every profile with its selected variant, `attr` (from `--attr`) takes
+/// precedence over the `attribute` of a node. Profiles without any variants are left alone.
+pub fn select_variants(
+    data: &mut data::Data,
+    attr: Option<&str>,
+) -> Result<(), SelectVariantError> {
+    for (node_name, node) in data.nodes.iter_mut() {
+        let variant = match attr.or(node.node_settings.attribute.as_deref()) {
+            Some(x) => x.to_string(),
+            None => continue,
+        };
+
+        for (profile_name, profile) in node.node_settings.profiles.iter_mut() {
+            let settings = &mut profile.profile_settings;
+
+            if settings.variants.is_empty() {
+                continue;
+            }
+
+            settings.path = match settings.variants.get(&variant) {
+                Some(x) => x.clone(),
+                None => {
+                    return Err(SelectVariantError::NotFound(
+                        variant,
+                        profile_name.to_owned(),
+                        node_name.to_owned(),
+                    ))
+                }
+            };
+            settings.variant = Some(variant.clone());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_select_variants() {
+    let make_data = || -> data::Data {
+        serde_json::from_str(
+            r#"{
+                "nodes": {
+                    "web01": {
+                        "hostname": "web01",
+                        "attribute": "debug",
+                        "profiles": {
+                            "system": {
+                                "path": "/nix/store/release",
+                                "variants": { "debug": "/nix/store/debug", "tiny": "/nix/store/tiny" }
+                            },
+                            "plain": { "path": "/nix/store/plain" }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap()
+    };
+
+    let profile = |data: &data::Data, name: &str| {
+        data.nodes["web01"].node_settings.profiles[name]
+            .profile_settings
+            .clone()
+    };
+
+    let mut data = make_data();
+    select_variants(&mut data, None).unwrap();
+    assert_eq!(profile(&data, "system").path, "/nix/store/debug");
+    assert_eq!(profile(&data, "system").variant, Some("debug".to_string()));
+    assert_eq!(profile(&data, "plain").path, "/nix/store/plain");
+
+    let mut data = make_data();
+    select_variants(&mut data, Some("tiny")).unwrap();
+    assert_eq!(profile(&data, "system").path, "/nix/store/tiny");
+
+    let mut data = make_data();
+    assert_eq!(
+        select_variants(&mut data, Some("huge")),
+        Err(SelectVariantError::NotFound(
+            "huge".to_string(),
+            "system".to_string(),
+            "web01".to_string()
+        ))
+    );
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ResolveHostnameError {
+    #[error("Node `{0}` has both `hostname` and `hostnameTemplate`")]
+    Conflict(String),
+    #[error("Node `{0}` has neither `hostname` nor `hostnameTemplate`")]
+    Missing(String),
+    #[error("Variable `{0}` in the hostname template of node `{1}` is not defined in its `templateVars`")]
+    UndefinedVariable(String, String),
+    #[error("The hostname template of node `{0}` has an unclosed `{{`")]
+    Unclosed(String),
+}
+
+/// Replaces each `{variable}` in `template`, `{name}` with `node_name` and others from `vars`
+fn render_hostname_template(
+    template: &str,
+    node_name: &str,
+    vars: &HashMap<String, String>,
+) -> Result<String, ResolveHostnameError> {
+    let mut hostname = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        hostname.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| ResolveHostnameError::Unclosed(node_name.to_string()))?;
+        let var = &rest[start + 1..start + end];
+
+        match (var, vars.get(var)) {
+            (_, Some(value)) => hostname.push_str(value),
+            ("name", None) => hostname.push_str(node_name),
+            (_, None) => {
+                return Err(ResolveHostnameError::UndefinedVariable(
+                    var.to_string(),
+                    node_name.to_string(),
+                ))
+            }
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    hostname.push_str(rest);
+
+    Ok(hostname)
+}
+
+#[test]
+fn test_render_hostname_template() {
+    let vars: HashMap<String, String> = vec![
+        ("index".to_string(), "3".to_string()),
+        ("region".to_string(), "eu-west".to_string()),
+    ]
+    .into_iter()
+    .collect();
+
+    assert_eq!(
+        render_hostname_template("web-{index}.{region}.internal", "web03", &vars),
+        Ok("web-3.eu-west.internal".to_string())
+    );
+    assert_eq!(
+        render_hostname_template("{name}.example.com", "web03", &vars),
+        Ok("web03.example.com".to_string())
+    );
+    assert_eq!(
+        render_hostname_template("web-{idx}.internal", "web03", &vars),
+        Err(ResolveHostnameError::UndefinedVariable(
+            "idx".to_string(),
+            "web03".to_string()
+        ))
+    );
+    assert_eq!(
+        render_hostname_template("web-{index", "web03", &vars),
+        Err(ResolveHostnameError::Unclosed("web03".to_string()))
+    );
+}
+
+/// Sets the hostname of every node with a `hostnameTemplate` to the rendered template
+pub fn resolve_hostnames(data: &mut data::Data) -> Result<(), ResolveHostnameError> {
+    for (node_name, node) in data.nodes.iter_mut() {
+        let settings = &mut node.node_settings;
+
+        match (&settings.hostname_template, settings.hostname.is_empty()) {
+            (Some(_), false) => return Err(ResolveHostnameError::Conflict(node_name.to_owned())),
+            (None, true) => return Err(ResolveHostnameError::Missing(node_name.to_owned())),
+            (None, false) => (),
+            (Some(template), true) => {
+                settings.hostname =
+                    render_hostname_template(template, node_name, &settings.template_vars)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct DeployData<'a> {
     pub node_name: &'a str,
@@ -297,78 +965,1096 @@ pub struct DeployData<'a> {
     pub cmd_overrides: &'a CmdOverrides,
 
     pub merged_settings: data::GenericSettings,
+    /// The `sshOpts` as configured, before the options of deploy-rs settings were put in front
+    pub configured_ssh_opts: Vec<String>,
+    /// Socket of the shared SSH connection to the node, `None` without `sshMultiplexing`
+    pub ssh_control_path: Option<String>,
 
     pub debug_logs: bool,
+    /// Narrate every decision made while deploying (`--explain`)
+    pub explain: bool,
     pub log_dir: Option<&'a str>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 pub struct DeployDefs {
     pub ssh_user: String,
     pub profile_user: String,
     pub profile_path: String,
     pub sudo: Option<String>,
+    /// Given to the commands using `sudo` on their standard input, read from `sudoPassword`
+    #[serde(skip)]
+    pub sudo_password: Option<runner::Secret>,
 }
 
 #[derive(Error, Debug)]
-pub enum DeployDataDefsError {
-    #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
-    NoProfileUser(String, String),
+pub enum NodeProfilesError {
+    #[error("No profile named `{0}` was found")]
+    ProfileNotFound(String),
+    #[error("Invalid profile dependencies of node `{0}`: {1}")]
+    Dependencies(String, graph::DependencyError),
 }
 
-impl<'a> DeployData<'a> {
-    pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
-        let ssh_user = match self.merged_settings.ssh_user {
-            Some(ref u) => u.clone(),
-            None => whoami::username(),
-        };
-
-        let profile_user = match self.merged_settings.user {
-            Some(ref x) => x.clone(),
-            None => match self.merged_settings.ssh_user {
-                Some(ref x) => x.clone(),
-                None => {
-                    return Err(DeployDataDefsError::NoProfileUser(
-                        self.profile_name.to_owned(),
-                        self.node_name.to_owned(),
-                    ))
-                }
-            },
-        };
+/// The profiles of a node in the order they are deployed, which is `profilesOrder` and then the
+/// rest, with every profile moved after those it depends on
+pub fn node_profiles<'a>(
+    node_name: &str,
+    node: &'a data::Node,
+) -> Result<Vec<(&'a str, &'a data::Profile)>, NodeProfilesError> {
+    let mut profile_names: Vec<&str> = Vec::new();
 
-        let profile_path = match self.profile.profile_settings.profile_path {
-            None => match &profile_user[..] {
-                "root" => format!("/nix/var/nix/profiles/{}", self.profile_name),
-                _ => format!(
-                    "/nix/var/nix/profiles/per-user/{}/{}",
-                    profile_user, self.profile_name
-                ),
-            },
-            Some(ref x) => x.clone(),
-        };
+    for profile_name in [
+        node.node_settings.profiles_order.iter().collect(),
+        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+    ]
+    .concat()
+    {
+        if !node.node_settings.profiles.contains_key(profile_name) {
+            return Err(NodeProfilesError::ProfileNotFound(profile_name.to_owned()));
+        }
 
-        let sudo: Option<String> = match self.merged_settings.user {
-            Some(ref user) if user != &ssh_user => Some(format!("sudo -u {}", user)),
-            _ => None,
-        };
+        if !profile_names.contains(&profile_name.as_str()) {
+            profile_names.push(profile_name);
+        }
+    }
 
-        Ok(DeployDefs {
-            ssh_user,
-            profile_user,
-            profile_path,
-            sudo,
+    let deps = node
+        .node_settings
+        .profiles
+        .iter()
+        .map(|(name, profile)| {
+            (
+                name.as_str(),
+                profile
+                    .profile_settings
+                    .depends_on
+                    .as_deref()
+                    .unwrap_or(&[]),
+            )
         })
-    }
+        .collect();
+
+    let profile_names = graph::sort_by_dependencies(&profile_names, &deps)
+        .map_err(|err| NodeProfilesError::Dependencies(node_name.to_string(), err))?;
+
+    Ok(profile_names
+        .into_iter()
+        .map(|name| (name, &node.node_settings.profiles[name]))
+        .collect())
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
-    node: &'a data::Node,
+#[test]
+fn test_node_profiles() {
+    let node = |profiles: serde_json::Value| -> data::Node {
+        serde_json::from_value(serde_json::json!({
+            "hostname": "example.com",
+            "profilesOrder": ["app", "db"],
+            "profiles": profiles,
+        }))
+        .unwrap()
+    };
+    let names = |node: &data::Node| -> Vec<String> {
+        node_profiles("example", node)
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    };
+
+    let node_a = node(serde_json::json!({
+        "app": { "path": "/nix/store/blah-app" },
+        "db": { "path": "/nix/store/blah-db" },
+    }));
+    assert_eq!(names(&node_a), vec!["app", "db"]);
+
+    let node_a = node(serde_json::json!({
+        "app": { "path": "/nix/store/blah-app", "dependsOn": ["db"] },
+        "db": { "path": "/nix/store/blah-db" },
+    }));
+    assert_eq!(names(&node_a), vec!["db", "app"]);
+
+    let node_a = node(serde_json::json!({
+        "app": { "path": "/nix/store/blah-app", "dependsOn": ["db"] },
+        "db": { "path": "/nix/store/blah-db", "dependsOn": ["app"] },
+    }));
+    assert!(matches!(
+        node_profiles("example", &node_a),
+        Err(NodeProfilesError::Dependencies(
+            _,
+            graph::DependencyError::Cycle(_)
+        ))
+    ));
+
+    let node_a = node(serde_json::json!({
+        "app": { "path": "/nix/store/blah-app" },
+    }));
+    assert!(matches!(
+        node_profiles("example", &node_a),
+        Err(NodeProfilesError::ProfileNotFound(ref name)) if name == "db"
+    ));
+}
+
+#[derive(Error, Debug)]
+pub enum DeployDataDefsError {
+    #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
+    NoProfileUser(String, String),
+    #[error("`privilegeEscalationCommand` of profile {0} of node {1} is empty")]
+    EmptyPrivilegeEscalationCommand(String, String),
+    #[error(
+        "`activationEnv` of profile {1} of node {2} sets `{0}`, which is not a valid variable name"
+    )]
+    InvalidActivationEnv(String, String, String),
+    #[error("Failed to read `sudoPassword` of profile {0} of node {1}: {2}")]
+    SudoPassword(String, String, ReadSecretError),
+}
+
+#[derive(Error, Debug)]
+pub enum ReadSecretError {
+    #[error("`{0}` is not set in the environment")]
+    NoEnv(String),
+    #[error("Failed to run `{0}`: {1}")]
+    Command(String, std::io::Error),
+    #[error("`{0}` resulted in a bad exit code: {1:?}")]
+    CommandExit(String, Option<i32>),
+    #[error("Error converting the output of `{0}` to utf8: {1}")]
+    DecodeUtf8(String, std::string::FromUtf8Error),
+}
+
+/// Reads the secret from where `source` says. A command keeps the terminal, such as for unlocking a
+/// password manager.
+fn read_secret(source: &data::SecretSource) -> Result<runner::Secret, ReadSecretError> {
+    match source {
+        data::SecretSource::Env(name) => match std::env::var(name) {
+            Ok(x) => Ok(runner::Secret::new(x)),
+            Err(_) => Err(ReadSecretError::NoEnv(name.to_owned())),
+        },
+        data::SecretSource::Command(command) => {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .map_err(|err| ReadSecretError::Command(command.to_owned(), err))?;
+
+            match output.status.code() {
+                Some(0) => (),
+                a => return Err(ReadSecretError::CommandExit(command.to_owned(), a)),
+            };
+
+            let mut secret = String::from_utf8(output.stdout)
+                .map_err(|err| ReadSecretError::DecodeUtf8(command.to_owned(), err))?;
+            if secret.ends_with('\n') {
+                secret.pop();
+            }
+
+            Ok(runner::Secret::new(secret))
+        }
+    }
+}
+
+#[test]
+fn test_read_secret() {
+    use data::SecretSource;
+
+    // Only this test uses the variable, and it is removed before anything could look at it
+    std::env::set_var("DEPLOY_RS_TEST_READ_SECRET", "hunter2");
+    let secret = read_secret(&SecretSource::Env("DEPLOY_RS_TEST_READ_SECRET".to_string()));
+    std::env::remove_var("DEPLOY_RS_TEST_READ_SECRET");
+    assert_eq!(secret.unwrap().expose(), "hunter2");
+    assert!(matches!(
+        read_secret(&SecretSource::Env("DEPLOY_RS_TEST_UNSET".to_string())),
+        Err(ReadSecretError::NoEnv(_))
+    ));
+
+    assert_eq!(
+        read_secret(&SecretSource::Command("printf 'hunter 2\\n'".to_string()))
+            .unwrap()
+            .expose(),
+        "hunter 2"
+    );
+    assert!(matches!(
+        read_secret(&SecretSource::Command("exit 3".to_string())),
+        Err(ReadSecretError::CommandExit(_, Some(3)))
+    ));
+}
+
+/// Whether `name` can be set as an environment variable by a shell
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_env_name() {
+    assert!(is_env_name("DEPLOY_STAGE"));
+    assert!(is_env_name("_flag2"));
+    assert!(!is_env_name("2FA"));
+    assert!(!is_env_name("A=B"));
+    assert!(!is_env_name("-i"));
+    assert!(!is_env_name(""));
+}
+
+/// Switches from `sshUser` to `user`, when they differ
+const DEFAULT_PRIVILEGE_ESCALATION_COMMAND: &str = "sudo -u {user}";
+
+/// Like [`DEFAULT_PRIVILEGE_ESCALATION_COMMAND`] with `sudoPassword`, which is read from standard
+/// input without a prompt. Cached credentials are ignored (`-k`), so that the password is always
+/// read instead of being left for the command.
+const SUDO_PASSWORD_ESCALATION_COMMAND: &str = "sudo -k -S -p '' -u {user}";
+
+/// Fills `{user}` in `template` with the quoted `user`, or appends `-u <user>` without it. Returns
+/// `None` for an empty template, which would run the commands as `sshUser` instead.
+fn make_privilege_escalation_command(template: &str, user: &str) -> Option<String> {
+    if template.trim().is_empty() {
+        return None;
+    }
+
+    let user = deploy::shell_escape(user);
+
+    Some(match template.contains("{user}") {
+        true => template.replace("{user}", &user),
+        false => format!("{} -u {}", template.trim_end(), user),
+    })
+}
+
+#[test]
+fn test_make_privilege_escalation_command() {
+    assert_eq!(
+        make_privilege_escalation_command(DEFAULT_PRIVILEGE_ESCALATION_COMMAND, "test"),
+        Some("sudo -u 'test'".to_string())
+    );
+    assert_eq!(
+        make_privilege_escalation_command("doas -u {user}", "test"),
+        Some("doas -u 'test'".to_string())
+    );
+    assert_eq!(
+        make_privilege_escalation_command("doas ", "it's"),
+        Some("doas -u 'it'\\''s'".to_string())
+    );
+    assert_eq!(make_privilege_escalation_command(" ", "test"), None);
+}
+
+/// How a profile is rolled back when deploying it fails, from `magicRollback` and `autoRollback`
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RollbackStrategy {
+    /// Rolls back when activating fails, and unless deploy-rs confirms in time. This is the default
+    Magic,
+    /// Rolls back only when activating fails, with `magicRollback = false`
+    Auto,
+    /// Never rolls back, with both disabled
+    None,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("`autoRollback` is disabled but `magicRollback` is not, which it needs to roll back unconfirmed deployments; set `magicRollback = false` as well to never roll back")]
+pub struct IncoherentRollbackError;
+
+pub fn rollback_strategy(
+    settings: &data::GenericSettings,
+) -> Result<RollbackStrategy, IncoherentRollbackError> {
+    match (
+        settings.magic_rollback.unwrap_or(true),
+        settings.auto_rollback.unwrap_or(true),
+    ) {
+        (true, true) => Ok(RollbackStrategy::Magic),
+        (false, true) => Ok(RollbackStrategy::Auto),
+        (false, false) => Ok(RollbackStrategy::None),
+        (true, false) => Err(IncoherentRollbackError),
+    }
+}
+
+#[test]
+fn test_rollback_strategy() {
+    let strategy = |magic_rollback, auto_rollback| {
+        rollback_strategy(&data::GenericSettings {
+            magic_rollback,
+            auto_rollback,
+            ..Default::default()
+        })
+    };
+
+    assert_eq!(strategy(None, None), Ok(RollbackStrategy::Magic));
+    assert_eq!(
+        strategy(Some(true), Some(true)),
+        Ok(RollbackStrategy::Magic)
+    );
+    assert_eq!(strategy(Some(false), None), Ok(RollbackStrategy::Auto));
+    assert_eq!(
+        strategy(Some(false), Some(true)),
+        Ok(RollbackStrategy::Auto)
+    );
+    assert_eq!(
+        strategy(Some(false), Some(false)),
+        Ok(RollbackStrategy::None)
+    );
+    assert_eq!(strategy(None, Some(false)), Err(IncoherentRollbackError));
+    assert_eq!(
+        strategy(Some(true), Some(false)),
+        Err(IncoherentRollbackError)
+    );
+}
+
+/// A hostname like `localhost` is not enough to tell, as it may be forwarded to a VM (see `examples/system`)
+fn is_local(settings: &data::GenericSettings) -> bool {
+    settings.local == Some(true)
+}
+
+#[test]
+fn test_is_local() {
+    // Like `examples/system`, which reaches a VM through a port forwarded to `localhost`
+    let node = mock_node(serde_json::json!({
+        "sshPort": 2221,
+        "hostname": "localhost",
+        "fastConnection": true,
+        "profiles": {
+            "system": { "sshUser": "admin", "path": "/nix/store/blah-system", "user": "root" },
+        },
+    }));
+    let cmd_overrides = CmdOverrides::default();
+    let deploy_data = mock_deploy_data(&node, &cmd_overrides);
+
+    assert!(!deploy_data.is_local());
+    assert_eq!(
+        deploy_data.ssh_addr(&deploy_data.defs().unwrap()),
+        "admin@localhost"
+    );
+
+    let local = |local| {
+        is_local(&data::GenericSettings {
+            local,
+            ..Default::default()
+        })
+    };
+    assert!(!local(None));
+    assert!(local(Some(true)));
+    assert!(!local(Some(false)));
+}
+
+/// The SSH binary which runs commands on nodes, unless `sshCommand` is set
+pub const DEFAULT_SSH_COMMAND: &str = "ssh";
+
+/// Wraps IPv6 literals (with or without a zone) in brackets, so that their colons can't be taken for
+/// a port, like in the `ssh://` store URL of `nix copy`. OpenSSH removes them again. The port is
+/// always given with `-p` instead.
+fn ssh_host(hostname: &str) -> std::borrow::Cow<'_, str> {
+    let address = hostname.split('%').next().unwrap_or(hostname);
+
+    match address.parse::<std::net::Ipv6Addr>() {
+        Ok(_) => format!("[{}]", hostname).into(),
+        Err(_) => hostname.into(),
+    }
+}
+
+#[test]
+fn test_ssh_host() {
+    assert_eq!(ssh_host("192.0.2.1"), "192.0.2.1");
+    assert_eq!(ssh_host("example.com"), "example.com");
+    assert_eq!(ssh_host("::1"), "[::1]");
+    assert_eq!(ssh_host("2001:db8::1"), "[2001:db8::1]");
+    assert_eq!(ssh_host("fe80::1%eth0"), "[fe80::1%eth0]");
+    assert_eq!(ssh_host("[2001:db8::1]"), "[2001:db8::1]");
+}
+
+/// A profile as deploying it would resolve it, after all settings are merged
+#[derive(Debug, serde::Serialize)]
+pub struct DeploymentPlan<'a> {
+    pub node: &'a str,
+    pub profile: &'a str,
+    pub hostname: &'a str,
+    pub closure: &'a str,
+    pub temp_path: &'a str,
+    pub confirm_timeout: u16,
+    pub rollback: RollbackStrategy,
+    #[serde(flatten)]
+    pub defs: &'a DeployDefs,
+    pub settings: &'a data::GenericSettings,
+}
+
+impl<'a> DeployData<'a> {
+    /// The environment of every SSH command for the node, on top of the one deploy-rs runs in
+    pub fn ssh_env(&self) -> Vec<(String, String)> {
+        match self.merged_settings.ssh_agent_sock {
+            Some(ref sock) => vec![("SSH_AUTH_SOCK".to_string(), sock.clone())],
+            None => Vec::new(),
+        }
+    }
+
+    /// The SSH binary to run commands on the node with
+    pub fn ssh_command(&self) -> &str {
+        self.merged_settings
+            .ssh_command
+            .as_deref()
+            .unwrap_or(DEFAULT_SSH_COMMAND)
+    }
+
+    pub fn rollback_strategy(&self) -> Result<RollbackStrategy, IncoherentRollbackError> {
+        rollback_strategy(&self.merged_settings)
+    }
+
+    pub fn plan<'b>(
+        &'b self,
+        deploy_defs: &'b DeployDefs,
+    ) -> Result<DeploymentPlan<'b>, IncoherentRollbackError> {
+        Ok(DeploymentPlan {
+            node: self.node_name,
+            profile: self.profile_name,
+            hostname: match self.cmd_overrides.hostname {
+                Some(ref x) => x,
+                None => &self.node.node_settings.hostname,
+            },
+            closure: &self.profile.profile_settings.path,
+            temp_path: self.merged_settings.temp_path.as_deref().unwrap_or("/tmp"),
+            confirm_timeout: self.merged_settings.confirm_timeout.unwrap_or(30),
+            rollback: self.rollback_strategy()?,
+            defs: deploy_defs,
+            settings: &self.merged_settings,
+        })
+    }
+
+    /// Where SSH connects to for the node, both for copying the closure and for activating it
+    pub fn ssh_addr(&self, deploy_defs: &DeployDefs) -> String {
+        let hostname = match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        };
+
+        format!("{}@{}", deploy_defs.ssh_user, ssh_host(hostname))
+    }
+
+    /// If commands for the node run directly on this machine instead of over SSH
+    pub fn is_local(&self) -> bool {
+        is_local(&self.merged_settings)
+    }
+
+    pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
+        let ssh_user = match self.merged_settings.ssh_user {
+            Some(ref u) => u.clone(),
+            None => whoami::username(),
+        };
+
+        let profile_user = match self.merged_settings.user {
+            Some(ref x) => x.clone(),
+            None => match self.merged_settings.ssh_user {
+                Some(ref x) => x.clone(),
+                None => {
+                    return Err(DeployDataDefsError::NoProfileUser(
+                        self.profile_name.to_owned(),
+                        self.node_name.to_owned(),
+                    ))
+                }
+            },
+        };
+
+        let profile_path = match self.profile.profile_settings.profile_path {
+            None => match &profile_user[..] {
+                "root" => format!("/nix/var/nix/profiles/{}", self.profile_name),
+                _ => format!(
+                    "/nix/var/nix/profiles/per-user/{}/{}",
+                    profile_user, self.profile_name
+                ),
+            },
+            Some(ref x) => x.clone(),
+        };
+
+        // Activating switches to `activateUser` over `user`, whichever is set
+        let activate_user = self
+            .profile
+            .profile_settings
+            .activate_user
+            .as_ref()
+            .or(self.merged_settings.user.as_ref());
+
+        let sudo: Option<String> = match activate_user {
+            Some(user) if user != &ssh_user => {
+                let template = match (
+                    &self.merged_settings.privilege_escalation_command,
+                    &self.merged_settings.sudo_password,
+                ) {
+                    (Some(x), _) => x,
+                    (None, Some(_)) => SUDO_PASSWORD_ESCALATION_COMMAND,
+                    (None, None) => DEFAULT_PRIVILEGE_ESCALATION_COMMAND,
+                };
+
+                match make_privilege_escalation_command(template, user) {
+                    Some(x) => Some(x),
+                    None => {
+                        return Err(DeployDataDefsError::EmptyPrivilegeEscalationCommand(
+                            self.profile_name.to_owned(),
+                            self.node_name.to_owned(),
+                        ))
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(ref activation_env) = self.merged_settings.activation_env {
+            if let Some(name) = activation_env.keys().find(|x| !is_env_name(x)) {
+                return Err(DeployDataDefsError::InvalidActivationEnv(
+                    name.to_owned(),
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+        }
+
+        // Without switching users there is nothing to read it for
+        let sudo_password = match (&sudo, &self.merged_settings.sudo_password) {
+            (Some(_), Some(source)) => Some(read_secret(source).map_err(|err| {
+                DeployDataDefsError::SudoPassword(
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                    err,
+                )
+            })?),
+            _ => None,
+        };
+
+        Ok(DeployDefs {
+            ssh_user,
+            profile_user,
+            profile_path,
+            sudo,
+            sudo_password,
+        })
+    }
+}
+
+#[test]
+fn test_ssh_addr() {
+    let ssh_argv = |hostname: &str| {
+        let node = mock_node(serde_json::json!({
+            "hostname": hostname,
+            "sshPort": 2222,
+            "sshMultiplexing": false,
+        }));
+        let cmd_overrides = CmdOverrides::default();
+        let deploy_data = mock_deploy_data(&node, &cmd_overrides);
+        let deploy_defs = deploy_data.defs().unwrap();
+
+        let mut argv = deploy_data.merged_settings.ssh_opts.clone();
+        argv.push(deploy_data.ssh_addr(&deploy_defs));
+        argv
+    };
+
+    assert_eq!(ssh_argv("192.0.2.1"), ["-p", "2222", "admin@192.0.2.1"]);
+    assert_eq!(
+        ssh_argv("2001:db8::1"),
+        ["-p", "2222", "admin@[2001:db8::1]"]
+    );
+    assert_eq!(
+        ssh_argv("web01.example.com"),
+        ["-p", "2222", "admin@web01.example.com"]
+    );
+}
+
+#[test]
+fn test_deployment_plan() {
+    let node = mock_node(serde_json::json!({
+        "confirmTimeout": 60,
+        "magicRollback": false,
+        "sshMultiplexing": false,
+        "profiles": { "system": { "path": "/nix/store/blah-system", "tempPath": "/var/tmp" } },
+    }));
+    let cmd_overrides = CmdOverrides {
+        hostname: Some("10.0.0.1".to_string()),
+        ..Default::default()
+    };
+    let deploy_data = mock_deploy_data(&node, &cmd_overrides);
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let plan = serde_json::to_value(deploy_data.plan(&deploy_defs).unwrap()).unwrap();
+
+    assert_eq!(plan["node"], "example");
+    assert_eq!(plan["hostname"], "10.0.0.1");
+    assert_eq!(plan["closure"], "/nix/store/blah-system");
+    assert_eq!(plan["temp_path"], "/var/tmp");
+    assert_eq!(plan["confirm_timeout"], 60);
+    assert_eq!(plan["rollback"], "auto");
+    assert_eq!(plan["ssh_user"], "admin");
+    assert_eq!(plan["sudo"], "sudo -u 'root'");
+    assert_eq!(plan["profile_path"], "/nix/var/nix/profiles/system");
+    assert_eq!(plan["settings"]["magic_rollback"], false);
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SplitSshOptsError {
+    #[error("Unclosed quote in SSH options `{0}`")]
+    UnclosedQuote(String),
+    #[error("Trailing backslash in SSH options `{0}`")]
+    TrailingBackslash(String),
+}
+
+/// Splits SSH options given as one string (like `--ssh-opts`) into arguments like a POSIX shell
+/// would, so that `-o "ProxyCommand=ssh gateway nc %h %p"` stays a single option
+pub fn split_ssh_opts(s: &str) -> Result<Vec<String>, SplitSshOptsError> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(x) = arg.take() {
+                    args.push(x);
+                }
+            }
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(SplitSshOptsError::UnclosedQuote(s.to_string())),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Only these are special after a backslash in double quotes
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(SplitSshOptsError::UnclosedQuote(s.to_string())),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(SplitSshOptsError::UnclosedQuote(s.to_string())),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => return Err(SplitSshOptsError::TrailingBackslash(s.to_string())),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    args.extend(arg);
+
+    Ok(args)
+}
+
+#[test]
+fn test_split_ssh_opts() {
+    assert_eq!(
+        split_ssh_opts("-p 2121  -o Compression=yes"),
+        Ok(vec![
+            "-p".to_string(),
+            "2121".to_string(),
+            "-o".to_string(),
+            "Compression=yes".to_string()
+        ])
+    );
+    assert_eq!(
+        split_ssh_opts(r#"-o "ProxyCommand=ssh gateway nc %h %p" -o 'User=it'\''s' a\ b """#),
+        Ok(vec![
+            "-o".to_string(),
+            "ProxyCommand=ssh gateway nc %h %p".to_string(),
+            "-o".to_string(),
+            "User=it's".to_string(),
+            "a b".to_string(),
+            "".to_string(),
+        ])
+    );
+    assert!(split_ssh_opts("-o 'ProxyCommand=ssh gateway").is_err());
+    assert!(split_ssh_opts("-p 22 \\").is_err());
+}
+
+/// Options in `sshOpts` are passed as given, one argument each. An option which was written
+/// together with its value (like `-o ProxyCommand=ssh gateway nc %h %p`) is split into the flag
+/// and the value, the value is kept together however many spaces it contains.
+fn normalize_ssh_opts(ssh_opts: Vec<String>) -> Vec<String> {
+    let mut normalized = Vec::new();
+
+    for opt in ssh_opts {
+        match opt.split_once(' ') {
+            Some((flag, value))
+                if flag.len() == 2 && flag.starts_with('-') && !value.trim().is_empty() =>
+            {
+                normalized.push(flag.to_string());
+                normalized.push(value.trim_start().to_string());
+            }
+            _ => normalized.push(opt),
+        }
+    }
+
+    normalized
+}
+
+#[test]
+fn test_normalize_ssh_opts() {
+    let opts = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(
+        normalize_ssh_opts(opts(&["-o ProxyCommand=ssh gateway nc %h %p", "-p", "22"])),
+        opts(&["-o", "ProxyCommand=ssh gateway nc %h %p", "-p", "22"])
+    );
+    assert_eq!(
+        normalize_ssh_opts(opts(&["-o", "ProxyCommand=ssh gateway nc %h %p"])),
+        opts(&["-o", "ProxyCommand=ssh gateway nc %h %p"])
+    );
+    assert_eq!(normalize_ssh_opts(opts(&["-v"])), opts(&["-v"]));
+}
+
+/// SSH options for connecting through the SOCKS5 proxy at `proxy`
+fn socks_proxy_ssh_opts(proxy: &str) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        format!("ProxyCommand=nc -X 5 -x {} %h %p", proxy),
+    ]
+}
+
+#[test]
+fn test_socks_proxy_ssh_opts() {
+    assert_eq!(
+        socks_proxy_ssh_opts("proxy.example.com:1080"),
+        vec![
+            "-o".to_string(),
+            "ProxyCommand=nc -X 5 -x proxy.example.com:1080 %h %p".to_string()
+        ]
+    );
+}
+
+/// Passed as its own option, so that the hostname doesn't need a port (which IPv6 addresses would
+/// make ambiguous). SSH uses the first port it is given, so being in front this takes precedence
+/// over a `-p` (or `-o Port`) in the configured options.
+fn ssh_port_opts(port: u16) -> Vec<String> {
+    vec!["-p".to_string(), port.to_string()]
+}
+
+/// The port `ssh` would connect to with `ssh_opts`, as it reports without connecting
+#[cfg(test)]
+fn effective_ssh_port(ssh_opts: &[String]) -> String {
+    let output = std::process::Command::new("ssh")
+        .arg("-G")
+        .arg("-F")
+        .arg("/dev/null")
+        .args(ssh_opts)
+        .arg("example.com")
+        .output()
+        .unwrap();
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|x| x.strip_prefix("port "))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_ssh_port_opts() {
+    let node = mock_node(serde_json::json!({
+        "hostname": "2001:db8::1",
+        "sshOpts": ["-o", "Compression=yes", "-p", "2221"],
+        "sshMultiplexing": false,
+        "sshPort": 2222,
+    }));
+
+    let make_ssh_opts = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &Default::default(),
+            &node,
+            "example",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            false,
+            None,
+        )
+        .merged_settings
+        .ssh_opts
+    };
+
+    assert_eq!(
+        effective_ssh_port(&make_ssh_opts(&Default::default())),
+        "2222"
+    );
+    assert_eq!(
+        effective_ssh_port(&make_ssh_opts(&CmdOverrides {
+            ssh_port: Some(22),
+            ..Default::default()
+        })),
+        "22"
+    );
+    assert_eq!(node.node_settings.hostname, "2001:db8::1");
+}
+
+/// SSH options for reaching the node through the bastion `jump_host`
+fn ssh_jump_host_opts(jump_host: &str) -> Vec<String> {
+    vec!["-o".to_string(), format!("ProxyJump={}", jump_host)]
+}
+
+/// SSH options authenticating with only the key in `identity_file`, even if an agent has others
+fn identity_file_opts(identity_file: &str) -> Vec<String> {
+    vec![
+        "-i".to_string(),
+        identity_file.to_string(),
+        "-o".to_string(),
+        "IdentitiesOnly=yes".to_string(),
+    ]
+}
+
+/// SSH options for handling host keys of the node like `strict_host_key`
+fn strict_host_key_opts(strict_host_key: data::StrictHostKey) -> Vec<String> {
+    let value = match strict_host_key {
+        data::StrictHostKey::Yes => "yes",
+        data::StrictHostKey::No => "no",
+        data::StrictHostKey::AcceptNew => "accept-new",
+    };
+
+    vec!["-o".to_string(), format!("StrictHostKeyChecking={}", value)]
+}
+
+#[test]
+fn test_strict_host_key_opts() {
+    assert_eq!(
+        strict_host_key_opts(data::StrictHostKey::Yes),
+        ["-o", "StrictHostKeyChecking=yes"]
+    );
+    assert_eq!(
+        strict_host_key_opts(data::StrictHostKey::No),
+        ["-o", "StrictHostKeyChecking=no"]
+    );
+    assert_eq!(
+        strict_host_key_opts(data::StrictHostKey::AcceptNew),
+        ["-o", "StrictHostKeyChecking=accept-new"]
+    );
+
+    let node = mock_node(serde_json::json!({
+        "hostname": "10.0.0.5",
+        "sshOpts": ["-o", "StrictHostKeyChecking=no"],
+        "strictHostKeyChecking": "accept-new",
+    }));
+
+    let cmd_overrides = Default::default();
+
+    let deploy_data = mock_deploy_data(&node, &cmd_overrides);
+
+    // SSH uses the first value it is given for an option
+    assert_eq!(
+        deploy_data.merged_settings.ssh_opts[..2],
+        ["-o", "StrictHostKeyChecking=accept-new"]
+    );
+}
+
+#[test]
+fn test_ssh_jump_host_opts() {
+    let node = mock_node(serde_json::json!({
+        "hostname": "10.0.0.5",
+        "sshOpts": ["-o", "Compression=yes"],
+        "sshPort": 2222,
+        "sshJumpHost": "admin@bastion.example.com:2200",
+    }));
+
+    let cmd_overrides = CmdOverrides {
+        run_id: Some("42".to_string()),
+        ..Default::default()
+    };
+
+    let deploy_data = mock_deploy_data(&node, &cmd_overrides);
+
+    // The port of the jump host is its own, `-p` only applies to the node
+    assert_eq!(
+        deploy_data.merged_settings.ssh_opts[..4],
+        [
+            "-o",
+            "ProxyJump=admin@bastion.example.com:2200",
+            "-p",
+            "2222"
+        ]
+    );
+    // Every command goes through the shared connection, which makes the jump once
+    assert!(deploy_data
+        .ssh_control_path
+        .unwrap()
+        .ends_with("/example-42-%r"));
+    assert!(has_ssh_option(
+        &deploy_data.merged_settings.ssh_opts,
+        "ControlMaster"
+    ));
+}
+
+/// How long an idle shared SSH connection is kept open, in seconds
+const SSH_CONTROL_PERSIST: u16 = 60;
+
+/// Created by the first node sharing its SSH connection, with [`ssh_control_dir`]
+static SSH_CONTROL_DIR: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+fn ssh_control_dir() -> Option<&'static str> {
+    SSH_CONTROL_DIR.get_or_init(make_ssh_control_dir).as_deref()
+}
+
+/// Creates the directory for the sockets of shared SSH connections, which only the deploying user
+/// can access, as the sockets give access to the nodes. It is in `/tmp` regardless of `TMPDIR`, as
+/// socket paths must be short. As its name can be guessed, only a directory which didn't exist is
+/// used, like by `mkdtemp`.
+fn make_ssh_control_dir() -> Option<String> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.subsec_nanos())
+        .unwrap_or(0);
+
+    for attempt in 0..10 {
+        let path = format!(
+            "/tmp/deploy-rs-{}-{:x}",
+            std::process::id(),
+            nanos.wrapping_add(attempt)
+        );
+
+        match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+            Ok(()) => return Some(path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                log::warn!(
+                    "Not sharing SSH connections, as creating `{}` failed: {}",
+                    path,
+                    err
+                );
+                return None;
+            }
+        }
+    }
+
+    log::warn!("Not sharing SSH connections, as no directory for them could be created");
+    None
+}
+
+/// Removes the directory of the sockets of shared SSH connections, once they are closed
+pub fn remove_ssh_control_dir() {
+    // Not created just to be removed, if no connection was shared
+    if let Some(Some(dir)) = SSH_CONTROL_DIR.get() {
+        // Only empty directories are removed, connections still open keep theirs
+        std::fs::remove_dir(dir).ok();
+    }
+}
+
+/// Socket of the SSH connection shared by a run on a node in `dir`, `%r` keeps profiles with
+/// different SSH users apart
+fn make_ssh_control_path(dir: &str, node_name: &str, run_id: &str) -> String {
+    let node_name: String = node_name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("{}/{}-{}-%r", dir, node_name, run_id)
+}
+
+#[test]
+fn test_make_ssh_control_path() {
+    assert_eq!(
+        make_ssh_control_path("/tmp/deploy-rs-1", "web-1.example.com", "42"),
+        "/tmp/deploy-rs-1/web-1.example.com-42-%r"
+    );
+    assert_eq!(
+        make_ssh_control_path("/tmp/deploy-rs-1", "my node/%h", "42"),
+        "/tmp/deploy-rs-1/my_node__h-42-%r"
+    );
+
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = make_ssh_control_dir().unwrap();
+    assert_eq!(
+        std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+        0o700
+    );
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+fn ssh_multiplexing_opts(control_path: &str) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}", control_path),
+        "-o".to_string(),
+        format!("ControlPersist={}", SSH_CONTROL_PERSIST),
+    ]
+}
+
+/// Puts `opts` in front of `ssh_opts`, so that they take precedence, as SSH uses the first value
+/// it is given for an option
+fn prepend_ssh_opts(ssh_opts: &mut Vec<String>, mut opts: Vec<String>) {
+    opts.append(ssh_opts);
+    *ssh_opts = opts;
+}
+
+/// Whether `ssh_opts` set the `-o` option `name`, which is matched case insensitively
+fn has_ssh_option(ssh_opts: &[String], name: &str) -> bool {
+    let mut opts = ssh_opts.iter();
+
+    while let Some(opt) = opts.next() {
+        let option = match opt.strip_prefix("-o") {
+            Some("") => opts.next().map(|x| x.as_str()),
+            Some(x) => Some(x),
+            None => None,
+        };
+
+        if let Some(option) = option {
+            let key = option.split(['=', ' ']).next().unwrap_or(option);
+
+            if key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+#[test]
+fn test_has_ssh_option() {
+    let opts = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    assert!(has_ssh_option(
+        &opts(&["-p", "2121", "-o", "controlpath=/tmp/x"]),
+        "ControlPath"
+    ));
+    assert!(has_ssh_option(
+        &opts(&["-oControlPath /tmp/x"]),
+        "ControlPath"
+    ));
+    assert!(!has_ssh_option(
+        &opts(&["-o", "ControlPersist=10"]),
+        "ControlPath"
+    ));
+    assert!(!has_ssh_option(
+        &opts(&["ControlPath=/tmp/x"]),
+        "ControlPath"
+    ));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn make_deploy_data<'a>(
+    top_settings: &data::GenericSettings,
+    node: &'a data::Node,
     node_name: &'a str,
     profile: &'a data::Profile,
     profile_name: &'a str,
     cmd_overrides: &'a CmdOverrides,
     debug_logs: bool,
+    explain: bool,
     log_dir: Option<&'a str>,
 ) -> DeployData<'a> {
     let mut merged_settings = profile.generic_settings.clone();
@@ -382,7 +2068,10 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings.user = cmd_overrides.profile_user.clone();
     }
     if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
-        merged_settings.ssh_opts = ssh_opts.split(' ').map(|x| x.to_owned()).collect();
+        merged_settings.ssh_opts = ssh_opts.clone();
+    }
+    if let Some(ref ssh_command) = cmd_overrides.ssh_command {
+        merged_settings.ssh_command = Some(ssh_command.clone());
     }
     if let Some(fast_connection) = cmd_overrides.fast_connection {
         merged_settings.fast_connection = Some(fast_connection);
@@ -393,6 +2082,86 @@ pub fn make_deploy_data<'a, 's>(
     if let Some(magic_rollback) = cmd_overrides.magic_rollback {
         merged_settings.magic_rollback = Some(magic_rollback);
     }
+    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
+        merged_settings.confirm_timeout = Some(confirm_timeout);
+    }
+
+    for targeted in &cmd_overrides.targeted {
+        if targeted.matches(node_name, profile_name) {
+            let mut settings = targeted.settings.clone();
+            let replaces_ssh_opts = !settings.ssh_opts.is_empty();
+            settings.merge(merged_settings);
+
+            // Like `--ssh-opts`, given options replace the configured ones instead of adding to them
+            if replaces_ssh_opts {
+                settings.ssh_opts = targeted.settings.ssh_opts.clone();
+            }
+
+            merged_settings = settings;
+        }
+    }
+
+    merged_settings.ssh_opts = normalize_ssh_opts(merged_settings.ssh_opts);
+    let configured_ssh_opts = merged_settings.ssh_opts.clone();
+
+    let ssh_control_path = match merged_settings.ssh_multiplexing {
+        Some(false) => None,
+        _ if is_local(&merged_settings) => None,
+        // A connection shared through the configured options is managed by whoever configured it
+        _ if has_ssh_option(&merged_settings.ssh_opts, "ControlPath") => None,
+        _ => ssh_control_dir().map(|dir| {
+            let control_path = match cmd_overrides.run_id {
+                Some(ref run_id) => make_ssh_control_path(dir, node_name, run_id),
+                None => make_ssh_control_path(dir, node_name, &std::process::id().to_string()),
+            };
+
+            prepend_ssh_opts(
+                &mut merged_settings.ssh_opts,
+                ssh_multiplexing_opts(&control_path),
+            );
+
+            control_path
+        }),
+    };
+
+    if let Some(ssh_port) = cmd_overrides.ssh_port.or(node.node_settings.ssh_port) {
+        prepend_ssh_opts(&mut merged_settings.ssh_opts, ssh_port_opts(ssh_port));
+    }
+
+    // Both activation and confirmation use this, so they agree on the lock of this run. A
+    // `lockFileName` is used exactly as given.
+    if let (Some(ref run_id), None) = (&cmd_overrides.run_id, &merged_settings.lock_file_name) {
+        merged_settings.lock_file_name = Some(make_run_lock_file_name(
+            &profile.profile_settings.path,
+            run_id,
+        ));
+    }
+
+    if let Some(ref socks_proxy) = merged_settings.socks_proxy {
+        prepend_ssh_opts(
+            &mut merged_settings.ssh_opts,
+            socks_proxy_ssh_opts(socks_proxy),
+        );
+    }
+
+    if let Some(ref identity_file) = merged_settings.identity_file {
+        prepend_ssh_opts(
+            &mut merged_settings.ssh_opts,
+            identity_file_opts(identity_file),
+        );
+    }
+
+    if let Some(strict_host_key) = node.node_settings.strict_host_key_checking {
+        prepend_ssh_opts(
+            &mut merged_settings.ssh_opts,
+            strict_host_key_opts(strict_host_key),
+        );
+    }
+
+    if let Some(ref jump_host) = node.node_settings.ssh_jump_host {
+        // In front of the SOCKS proxy too, SSH uses whichever of `ProxyJump` and `ProxyCommand` comes first
+        prepend_ssh_opts(&mut merged_settings.ssh_opts, ssh_jump_host_opts(jump_host));
+    }
 
     DeployData {
         profile,
@@ -403,8 +2172,338 @@ pub fn make_deploy_data<'a, 's>(
         cmd_overrides,
 
         merged_settings,
+        configured_ssh_opts,
+        ssh_control_path,
 
         debug_logs,
+        explain,
         log_dir,
     }
 }
+
+/// A node with a `system` profile, with `settings` on top
+#[cfg(test)]
+pub(crate) fn mock_node(settings: serde_json::Value) -> crate::data::Node {
+    let mut node = serde_json::json!({
+        "hostname": "example.com",
+        "sshUser": "admin",
+        "user": "root",
+        "profiles": { "system": { "path": "/nix/store/blah-system" } },
+    });
+    node.as_object_mut()
+        .unwrap()
+        .extend(settings.as_object().unwrap().clone());
+
+    serde_json::from_value(node).unwrap()
+}
+
+/// The deploy data of the `system` profile of `node`, deployed as `example` and without top-level
+/// settings
+#[cfg(test)]
+pub(crate) fn mock_deploy_data<'a>(
+    node: &'a data::Node,
+    cmd_overrides: &'a CmdOverrides,
+) -> DeployData<'a> {
+    make_deploy_data(
+        &Default::default(),
+        node,
+        "example",
+        &node.node_settings.profiles["system"],
+        "system",
+        cmd_overrides,
+        false,
+        false,
+        None,
+    )
+}
+
+#[test]
+fn test_confirm_timeout_override() {
+    let node = mock_node(serde_json::json!({
+        "sshMultiplexing": false,
+        "profiles": {
+            "system": { "path": "/nix/store/blah-system", "confirmTimeout": 60 },
+            "app": { "path": "/nix/store/blah-app" },
+        },
+    }));
+
+    let confirm_timeout = |profile_name: &str, cmd_overrides: &CmdOverrides| {
+        let deploy_data = make_deploy_data(
+            &Default::default(),
+            &node,
+            "example",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            cmd_overrides,
+            false,
+            false,
+            None,
+        );
+        let deploy_defs = deploy_data.defs().unwrap();
+        let plan = deploy_data.plan(&deploy_defs).unwrap();
+
+        plan.confirm_timeout
+    };
+    let overridden = CmdOverrides {
+        confirm_timeout: Some(120),
+        ..Default::default()
+    };
+
+    assert_eq!(confirm_timeout("system", &Default::default()), 60);
+    assert_eq!(confirm_timeout("app", &Default::default()), 30);
+    assert_eq!(confirm_timeout("system", &overridden), 120);
+    assert_eq!(confirm_timeout("app", &overridden), 120);
+}
+
+#[derive(Error, Debug)]
+pub enum DeployDataBuilderError {
+    #[error("No hostname was given for node {0}")]
+    NoHostname(String),
+    #[error("No closure was given for profile {0} of node {1}")]
+    NoClosure(String, String),
+    #[error("{0}")]
+    DeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Describes a profile to deploy without evaluating a flake, for using deploy-rs as a library.
+/// Settings which have no setter of their own are given with [`DeployDataBuilder::settings`].
+#[derive(Debug, Default)]
+pub struct DeployDataBuilder {
+    node_name: String,
+    profile_name: String,
+    hostname: Option<String>,
+    closure: Option<String>,
+    profile_path: Option<String>,
+    settings: data::GenericSettings,
+    cmd_overrides: CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<String>,
+}
+
+impl DeployDataBuilder {
+    pub fn new(node_name: &str, profile_name: &str) -> Self {
+        DeployDataBuilder {
+            node_name: node_name.to_string(),
+            profile_name: profile_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_string());
+        self
+    }
+
+    /// The store path of the profile, which has to be on the node already (see [`push::push_profile`])
+    pub fn closure(mut self, closure: &str) -> Self {
+        self.closure = Some(closure.to_string());
+        self
+    }
+
+    pub fn profile_path(mut self, profile_path: &str) -> Self {
+        self.profile_path = Some(profile_path.to_string());
+        self
+    }
+
+    pub fn ssh_user(mut self, ssh_user: &str) -> Self {
+        self.settings.ssh_user = Some(ssh_user.to_string());
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> Self {
+        self.settings.user = Some(user.to_string());
+        self
+    }
+
+    pub fn ssh_opts(mut self, ssh_opts: Vec<String>) -> Self {
+        self.settings.ssh_opts = ssh_opts;
+        self
+    }
+
+    pub fn confirm_timeout(mut self, confirm_timeout: u16) -> Self {
+        self.settings.confirm_timeout = Some(confirm_timeout);
+        self
+    }
+
+    pub fn magic_rollback(mut self, magic_rollback: bool) -> Self {
+        self.settings.magic_rollback = Some(magic_rollback);
+        self
+    }
+
+    pub fn auto_rollback(mut self, auto_rollback: bool) -> Self {
+        self.settings.auto_rollback = Some(auto_rollback);
+        self
+    }
+
+    /// Replaces every setting given so far, like the generic settings of a profile in a flake
+    pub fn settings(mut self, settings: data::GenericSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Namespaces the lock files and SSH connection of this deployment, like `--run-id`
+    pub fn run_id(mut self, run_id: &str) -> Self {
+        self.cmd_overrides.run_id = Some(run_id.to_string());
+        self
+    }
+
+    pub fn debug_logs(mut self, debug_logs: bool) -> Self {
+        self.debug_logs = debug_logs;
+        self
+    }
+
+    /// Where the activation on the node logs to, like `--log-dir`
+    pub fn log_dir(mut self, log_dir: &str) -> Self {
+        self.log_dir = Some(log_dir.to_string());
+        self
+    }
+
+    /// Checks that everything required was given, and resolves the users and paths of the profile
+    pub fn build(self) -> Result<BuiltDeploy, DeployDataBuilderError> {
+        let hostname = match self.hostname {
+            Some(x) if !x.is_empty() => x,
+            _ => return Err(DeployDataBuilderError::NoHostname(self.node_name)),
+        };
+        let closure = match self.closure {
+            Some(x) if !x.is_empty() => x,
+            _ => {
+                return Err(DeployDataBuilderError::NoClosure(
+                    self.profile_name,
+                    self.node_name,
+                ))
+            }
+        };
+
+        let profile = data::Profile {
+            profile_settings: data::ProfileSettings {
+                path: closure,
+                profile_path: self.profile_path,
+                prebuilt: true,
+                ..Default::default()
+            },
+            generic_settings: Default::default(),
+        };
+
+        let node = data::Node {
+            generic_settings: self.settings,
+            node_settings: data::NodeSettings {
+                hostname,
+                profiles: vec![(self.profile_name.clone(), profile)]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        };
+
+        let deploy_defs = make_deploy_data(
+            &Default::default(),
+            &node,
+            &self.node_name,
+            &node.node_settings.profiles[&self.profile_name],
+            &self.profile_name,
+            &self.cmd_overrides,
+            self.debug_logs,
+            false,
+            self.log_dir.as_deref(),
+        )
+        .defs()?;
+
+        Ok(BuiltDeploy {
+            node_name: self.node_name,
+            profile_name: self.profile_name,
+            node,
+            cmd_overrides: self.cmd_overrides,
+            debug_logs: self.debug_logs,
+            log_dir: self.log_dir,
+            deploy_defs,
+        })
+    }
+}
+
+/// A profile described by a [`DeployDataBuilder`], which owns what its [`DeployData`] borrows
+#[derive(Debug)]
+pub struct BuiltDeploy {
+    node_name: String,
+    profile_name: String,
+    node: data::Node,
+    cmd_overrides: CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<String>,
+    deploy_defs: DeployDefs,
+}
+
+impl BuiltDeploy {
+    pub fn deploy_data(&self) -> DeployData<'_> {
+        make_deploy_data(
+            &Default::default(),
+            &self.node,
+            &self.node_name,
+            &self.node.node_settings.profiles[&self.profile_name],
+            &self.profile_name,
+            &self.cmd_overrides,
+            self.debug_logs,
+            false,
+            self.log_dir.as_deref(),
+        )
+    }
+
+    pub fn deploy_defs(&self) -> &DeployDefs {
+        &self.deploy_defs
+    }
+}
+
+#[tokio::test]
+async fn test_deploy_data_builder() {
+    use runner::{MockResponse, MockRunner};
+
+    assert!(matches!(
+        DeployDataBuilder::new("example", "system")
+            .closure("/nix/store/blah-system")
+            .build(),
+        Err(DeployDataBuilderError::NoHostname(_))
+    ));
+    assert!(matches!(
+        DeployDataBuilder::new("example", "system")
+            .hostname("example.com")
+            .build(),
+        Err(DeployDataBuilderError::NoClosure(_, _))
+    ));
+
+    let built = DeployDataBuilder::new("example", "system")
+        .hostname("example.com")
+        .closure("/nix/store/blah-system")
+        .ssh_user("admin")
+        .user("root")
+        .confirm_timeout(60)
+        .run_id("test")
+        .build()
+        .unwrap();
+
+    let deploy_data = built.deploy_data();
+    let deploy_defs = built.deploy_defs();
+    assert_eq!(deploy_defs.profile_path, "/nix/var/nix/profiles/system");
+    assert_eq!(deploy_defs.sudo, Some("sudo -u 'root'".to_string()));
+    assert_eq!(deploy_data.merged_settings.confirm_timeout, Some(60));
+
+    let runner = MockRunner::new(vec![(
+        "readlink",
+        MockResponse {
+            stdout: "system-42-link\n/nix/store/blah-system\n".to_string(),
+            ..MockResponse::exit(0)
+        },
+    )]);
+    let result = deploy::deploy_profile(
+        &deploy_data,
+        deploy_defs,
+        &runner,
+        &deploy::CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+
+    assert!(result.confirmed);
+    assert!(runner
+        .calls()
+        .iter()
+        .any(|x| x.contains(" activate /nix/store/blah-system ")));
+}
@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Retrieval of the logs `activate-rs` writes to `--log-dir` on a node.
+
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Error, Debug)]
+pub enum FetchLogsError {
+    #[error("Failed to run command for fetching logs over SSH: {0}")]
+    SSHFetchError(std::io::Error),
+    #[error("Fetching logs over SSH resulted in a bad exit code: {0:?}")]
+    SSHFetchExitError(Option<i32>),
+    #[error("Failed to create local log directory: {0}")]
+    CreateDirError(std::io::Error),
+    #[error("Failed to write fetched logs: {0}")]
+    WriteError(std::io::Error),
+    #[error("Failed to run tar for unpacking logs: {0}")]
+    TarError(std::io::Error),
+    #[error("Unpacking logs with tar resulted in a bad exit code: {0:?}")]
+    TarExitError(Option<i32>),
+}
+
+/// Packs the log directory on the node, compressed if `gzip` is available there
+pub(crate) fn build_fetch_logs_command(remote_dir: &str) -> String {
+    format!(
+        "cd '{}' && if command -v gzip > /dev/null 2>&1; then tar -cf - . | gzip -c; else tar -cf - .; fi",
+        remote_dir
+    )
+}
+
+#[test]
+fn test_fetch_logs_command_builder() {
+    assert_eq!(
+        build_fetch_logs_command("/var/log/deploy-rs"),
+        "cd '/var/log/deploy-rs' && if command -v gzip > /dev/null 2>&1; then tar -cf - . | gzip -c; else tar -cf - .; fi"
+            .to_string(),
+    );
+}
+
+fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+#[test]
+fn test_is_gzip() {
+    assert!(is_gzip(&[0x1f, 0x8b, 0x08, 0x00]));
+    assert!(!is_gzip(b"deploy-rs.log\0"));
+    assert!(!is_gzip(&[]));
+}
+
+pub struct FetchLogsData<'a> {
+    pub ssh_command: &'a str,
+    pub ssh_addr: &'a str,
+    pub ssh_opts: &'a [String],
+    pub ssh_env: &'a [(String, String)],
+    pub remote_dir: &'a str,
+    pub local_dir: &'a Path,
+    /// Store the archive as fetched instead of unpacking it into `local_dir`
+    pub keep_gzipped: bool,
+}
+
+/// Fetches the logs from the node, returning where they were stored. The logs are compressed
+/// on the node to save bandwidth, unless it lacks `gzip`.
+pub async fn fetch_logs(data: FetchLogsData<'_>) -> Result<PathBuf, FetchLogsError> {
+    let fetch_logs_command = build_fetch_logs_command(data.remote_dir);
+
+    debug!("Fetching logs from the node: {}", fetch_logs_command);
+
+    let mut ssh_fetch_command = Command::new(data.ssh_command);
+    ssh_fetch_command
+        .arg(data.ssh_addr)
+        .envs(data.ssh_env.iter().map(|(k, v)| (k, v)));
+
+    for ssh_opt in data.ssh_opts {
+        ssh_fetch_command.arg(ssh_opt);
+    }
+
+    let output = ssh_fetch_command
+        .arg(fetch_logs_command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output()
+        .await
+        .map_err(FetchLogsError::SSHFetchError)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(FetchLogsError::SSHFetchExitError(a)),
+    };
+
+    store_logs(&output.stdout, data.local_dir, data.keep_gzipped).await
+}
+
+/// Stores the output of `build_fetch_logs_command`, see [`fetch_logs`]
+pub(crate) async fn store_logs(
+    fetched: &[u8],
+    local_dir: &Path,
+    keep_gzipped: bool,
+) -> Result<PathBuf, FetchLogsError> {
+    let compressed = is_gzip(fetched);
+
+    debug!(
+        "Fetched {} bytes of {} logs",
+        fetched.len(),
+        if compressed {
+            "compressed"
+        } else {
+            "uncompressed"
+        }
+    );
+
+    if keep_gzipped {
+        let parent = local_dir.parent().unwrap_or_else(|| Path::new("."));
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(FetchLogsError::CreateDirError)?;
+
+        let archive = local_dir.with_extension(match compressed {
+            true => "tar.gz",
+            false => "tar",
+        });
+
+        tokio::fs::write(&archive, fetched)
+            .await
+            .map_err(FetchLogsError::WriteError)?;
+
+        info!("Stored logs of the node in {}", archive.display());
+
+        return Ok(archive);
+    }
+
+    tokio::fs::create_dir_all(local_dir)
+        .await
+        .map_err(FetchLogsError::CreateDirError)?;
+
+    let mut tar = Command::new("tar")
+        .arg(if compressed { "-xzf" } else { "-xf" })
+        .arg("-")
+        .arg("-C")
+        .arg(local_dir)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(FetchLogsError::TarError)?;
+
+    if let Some(mut stdin) = tar.stdin.take() {
+        stdin
+            .write_all(fetched)
+            .await
+            .map_err(FetchLogsError::WriteError)?;
+    }
+
+    match tar.wait().await.map_err(FetchLogsError::TarError)?.code() {
+        Some(0) => (),
+        a => return Err(FetchLogsError::TarExitError(a)),
+    };
+
+    info!("Stored logs of the node in {}", local_dir.display());
+
+    Ok(local_dir.to_path_buf())
+}
@@ -0,0 +1,407 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Read-only checks of a node, for validating the whole connection path without deploying.
+
+use log::{debug, info, warn};
+use std::borrow::Cow;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::process::Command;
+
+/// Below this much free space in the Nix store a warning is printed
+const MIN_FREE_STORE_KIB: u64 = 1024 * 1024;
+
+/// Clock differences above this many seconds are warned about, as they upset TLS and signatures
+const MAX_CLOCK_SKEW_SECS: u64 = 60;
+
+#[derive(Error, Debug)]
+pub enum PreflightError {
+    #[error("Failed to run `{0}` over SSH: {1}")]
+    SSHError(&'static str, std::io::Error),
+    #[error("Checking `{0}` over SSH resulted in a bad exit code: {1:?}")]
+    SSHExitError(&'static str, Option<i32>),
+    #[error("Error converting the output of `{0}` to utf8: {1}")]
+    DecodeUtf8(&'static str, std::string::FromUtf8Error),
+}
+
+/// Runs `command` on the node over `ssh` (the SSH binary, address and options) with `ssh_env`, returning its exit code and standard output
+async fn run_check(
+    ssh: &[String],
+    ssh_env: &[(String, String)],
+    name: &'static str,
+    command: &str,
+) -> Result<(Option<i32>, String), PreflightError> {
+    debug!("Running preflight check `{}`: {}", name, command);
+
+    let mut ssh_command = Command::new(&ssh[0]);
+    ssh_command
+        .args(&ssh[1..])
+        .envs(ssh_env.iter().map(|(k, v)| (k, v)));
+
+    let output = ssh_command
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output()
+        .await
+        .map_err(|err| PreflightError::SSHError(name, err))?;
+
+    let stdout =
+        String::from_utf8(output.stdout).map_err(|err| PreflightError::DecodeUtf8(name, err))?;
+
+    Ok((output.status.code(), stdout))
+}
+
+/// Runs the `sudo` check like [`run_check`], giving it `sudoPassword` on standard input
+async fn run_sudo_password_check(
+    ssh: &[String],
+    ssh_env: &[(String, String)],
+    command: &str,
+    password: &crate::runner::Secret,
+) -> Result<Option<i32>, PreflightError> {
+    use crate::runner::CommandRunner;
+
+    debug!("Running preflight check `sudo`: {}", command);
+
+    let mut argv = ssh.to_vec();
+    argv.push(command.to_string());
+
+    let status = crate::runner::SshRunner
+        .status(
+            &argv,
+            crate::runner::RunOptions {
+                env: ssh_env.to_vec(),
+                stdin_secret: Some(password.clone()),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|err| PreflightError::SSHError("sudo", err))?;
+
+    Ok(status.code())
+}
+
+/// Requires the check to exit successfully
+fn require_success(name: &'static str, code: Option<i32>) -> Result<(), PreflightError> {
+    match code {
+        Some(0) => Ok(()),
+        a => Err(PreflightError::SSHExitError(name, a)),
+    }
+}
+
+/// Parses the available KiB out of `df -Pk` output
+fn parse_df_available(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn test_parse_df_available() {
+    let output = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                  /dev/sda1        102400000 51200000  51200000      50% /\n";
+    assert_eq!(parse_df_available(output), Some(51200000));
+    assert_eq!(parse_df_available(""), None);
+    assert_eq!(parse_df_available("Filesystem\n/dev/sda1 nope"), None);
+}
+
+/// Returns how many seconds the clock of the node is off, given the output of `date +%s`
+fn clock_skew(output: &str, local: u64) -> Option<u64> {
+    let remote: u64 = output.trim().parse().ok()?;
+
+    Some(remote.max(local) - remote.min(local))
+}
+
+#[test]
+fn test_clock_skew() {
+    assert_eq!(clock_skew("1000\n", 1000), Some(0));
+    assert_eq!(clock_skew("1090\n", 1000), Some(90));
+    assert_eq!(clock_skew("910", 1000), Some(90));
+    assert_eq!(clock_skew("not a date", 1000), None);
+}
+
+/// Connects to the node and runs read-only checks of everything activation relies on: privileges,
+/// Nix, free space in the store, the clock, and whether the closure is already present. Nothing is
+/// copied and nothing is activated.
+pub async fn preflight_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), PreflightError> {
+    info!(
+        "Checking profile `{}` for node `{}` without deploying",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let ssh_addr = deploy_data.ssh_addr(deploy_defs);
+    let ssh = &crate::deploy::make_node_argv(
+        false,
+        deploy_data.ssh_command(),
+        &ssh_addr,
+        &deploy_data.merged_settings.ssh_opts,
+    );
+    let ssh_env = &deploy_data.ssh_env();
+
+    let (code, _) = run_check(ssh, ssh_env, "connection", "true").await?;
+    require_success("connection", code)?;
+
+    if let Some(sudo) = &deploy_defs.sudo {
+        let command = format!("{} true", sudo);
+
+        let code = match &deploy_defs.sudo_password {
+            Some(password) => run_sudo_password_check(ssh, ssh_env, &command, password).await?,
+            None => run_check(ssh, ssh_env, "sudo", &command).await?.0,
+        };
+        require_success("sudo", code)?;
+    }
+
+    let (code, version) = run_check(ssh, ssh_env, "nix", "nix --version").await?;
+    require_success("nix", code)?;
+    debug!("Node `{}` runs {}", deploy_data.node_name, version.trim());
+
+    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x.into(),
+        None => "/tmp".into(),
+    };
+
+    let (code, _) = run_check(
+        ssh,
+        ssh_env,
+        "temp path",
+        &format!("test -d '{0}' -a -w '{0}'", temp_path),
+    )
+    .await?;
+    if code != Some(0) {
+        warn!(
+            "Temporary path `{}` on node `{}` is not a writable directory for the SSH user",
+            temp_path, deploy_data.node_name
+        );
+    }
+
+    let (code, df) = run_check(ssh, ssh_env, "disk", "df -Pk /nix/store").await?;
+    require_success("disk", code)?;
+    match parse_df_available(&df) {
+        Some(available) if available < MIN_FREE_STORE_KIB => warn!(
+            "Only {} MiB are free in the Nix store of node `{}`",
+            available / 1024,
+            deploy_data.node_name
+        ),
+        Some(available) => debug!("{} MiB are free in the Nix store", available / 1024),
+        None => warn!(
+            "Could not parse free space of node `{}`",
+            deploy_data.node_name
+        ),
+    }
+
+    let (code, date) = run_check(ssh, ssh_env, "clock", "date +%s").await?;
+    require_success("clock", code)?;
+    let local = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    match clock_skew(&date, local) {
+        Some(skew) if skew > MAX_CLOCK_SKEW_SECS => warn!(
+            "The clock of node `{}` is off by {}s",
+            deploy_data.node_name, skew
+        ),
+        Some(_) => (),
+        None => warn!(
+            "Could not parse the clock of node `{}`",
+            deploy_data.node_name
+        ),
+    }
+
+    let closure = &deploy_data.profile.profile_settings.path;
+    let (code, _) = run_check(
+        ssh,
+        ssh_env,
+        "closure",
+        &format!("nix path-info '{}' > /dev/null 2>&1", closure),
+    )
+    .await?;
+    match code {
+        Some(0) => info!(
+            "The closure is already present on node `{}`",
+            deploy_data.node_name
+        ),
+        _ => info!(
+            "The closure is not present on node `{}`, deploying would copy it",
+            deploy_data.node_name
+        ),
+    }
+
+    info!(
+        "Preflight checks of profile `{}` for node `{}` passed",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ConnectivityError {
+    #[error("Failed to run SSH to check if node `{0}` can be reached: {1}")]
+    SSHError(String, std::io::Error),
+    #[error("Node `{0}` can't be reached over SSH")]
+    Unreachable(String),
+    #[error("Checking if node `{0}` can be reached resulted in a bad exit code: {1:?}")]
+    SSHExitError(String, Option<i32>),
+}
+
+/// How long SSH tries to connect for checking connectivity, unless `sshOpts` says otherwise
+const CONNECTIVITY_TIMEOUT_SECS: u16 = 10;
+
+/// The command line running `true` on the node, with the same options (like the port or jump
+/// host) as deploying
+fn connectivity_argv(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Vec<String> {
+    let mut argv = crate::deploy::node_argv(deploy_data, &deploy_data.ssh_addr(deploy_defs));
+
+    if !deploy_data.is_local() {
+        // SSH uses the first value it is given, so this comes after the configured options
+        argv.push("-o".to_string());
+        argv.push(format!("ConnectTimeout={}", CONNECTIVITY_TIMEOUT_SECS));
+    }
+
+    argv.push("true".to_string());
+    argv
+}
+
+/// Maps how running `connectivity_argv` went to whether the node can be reached
+fn connectivity_result(
+    node_name: &str,
+    status: std::io::Result<std::process::ExitStatus>,
+) -> Result<(), ConnectivityError> {
+    match status {
+        Err(err) => Err(ConnectivityError::SSHError(node_name.to_string(), err)),
+        Ok(status) => match status.code() {
+            Some(0) => Ok(()),
+            // The exit code of SSH when it fails by itself, as `true` can't
+            Some(255) => Err(ConnectivityError::Unreachable(node_name.to_string())),
+            a => Err(ConnectivityError::SSHExitError(node_name.to_string(), a)),
+        },
+    }
+}
+
+#[test]
+fn test_connectivity_check() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let node = crate::mock_node(serde_json::json!({
+        "hostname": "web01.example.com",
+        "sshPort": 2222,
+        "sshJumpHost": "bastion.example.com",
+        "sshOpts": ["-o", "Compression=yes"],
+        "sshMultiplexing": false,
+    }));
+    let cmd_overrides = Default::default();
+    let deploy_data = crate::make_deploy_data(
+        &Default::default(),
+        &node,
+        "web01",
+        &node.node_settings.profiles["system"],
+        "system",
+        &cmd_overrides,
+        false,
+        false,
+        None,
+    );
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert_eq!(
+        connectivity_argv(&deploy_data, &deploy_defs),
+        [
+            "ssh",
+            "admin@web01.example.com",
+            "-o",
+            "ProxyJump=bastion.example.com",
+            "-p",
+            "2222",
+            "-o",
+            "Compression=yes",
+            "-o",
+            "ConnectTimeout=10",
+            "true",
+        ]
+    );
+
+    let exit = |code: i32| Ok(std::process::ExitStatus::from_raw(code << 8));
+    assert!(connectivity_result("web01", exit(0)).is_ok());
+    assert!(matches!(
+        connectivity_result("web01", exit(255)),
+        Err(ConnectivityError::Unreachable(x)) if x == "web01"
+    ));
+    assert!(matches!(
+        connectivity_result("web01", exit(1)),
+        Err(ConnectivityError::SSHExitError(_, Some(1)))
+    ));
+    assert!(matches!(
+        connectivity_result(
+            "web01",
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        ),
+        Err(ConnectivityError::SSHError(_, _))
+    ));
+}
+
+/// Checks that the node can be reached over SSH, without running anything but `true` on it
+pub async fn check_connectivity(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), ConnectivityError> {
+    let argv = connectivity_argv(deploy_data, deploy_defs);
+
+    debug!(
+        "Checking if node `{}` can be reached: {:?}",
+        deploy_data.node_name, argv
+    );
+
+    let status = Command::new(&argv[0])
+        .args(&argv[1..])
+        .envs(deploy_data.ssh_env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .await;
+
+    connectivity_result(deploy_data.node_name, status)
+}
+
+/// Checks every node of `targets` concurrently with [`check_connectivity`], so that a fleet can
+/// be left alone entirely if any of its nodes can't be reached. Returns the error of each node
+/// which can't.
+pub async fn check_fleet_connectivity<'a>(
+    targets: &[(&'a super::DeployData<'a>, &'a super::DeployDefs)],
+) -> Result<(), Vec<ConnectivityError>> {
+    let mut addrs: Vec<(&str, String)> = Vec::new();
+    let mut checks = Vec::new();
+
+    // Profiles of a node usually connect the same way, which only needs checking once
+    for (deploy_data, deploy_defs) in targets {
+        let addr = (deploy_data.node_name, deploy_data.ssh_addr(deploy_defs));
+
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+            checks.push(check_connectivity(deploy_data, deploy_defs));
+        }
+    }
+
+    let errors: Vec<ConnectivityError> = futures_util::future::join_all(checks)
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
@@ -2,11 +2,17 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, info, warn};
+
+use crate::telemetry::{Span, SpanStatus};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
 
 #[derive(Error, Debug)]
 pub enum PushProfileError {
@@ -32,6 +38,182 @@ pub enum PushProfileError {
     CopyExitError(Option<i32>),
 }
 
+/// Exponential backoff between attempts (of `nix copy`, or connecting), starting at one second and capped at a minute
+pub(crate) fn retry_delay(attempt: u8) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempt as u32).min(60))
+}
+
+#[test]
+fn test_retry_delay() {
+    assert_eq!(retry_delay(0), Duration::from_secs(1));
+    assert_eq!(retry_delay(1), Duration::from_secs(2));
+    assert_eq!(retry_delay(3), Duration::from_secs(8));
+    assert_eq!(retry_delay(6), Duration::from_secs(60));
+    assert_eq!(retry_delay(255), Duration::from_secs(60));
+}
+
+// Activity and result types of Nix's `internal-json` log format
+const NIX_ACT_COPY_PATH: u64 = 100;
+const NIX_ACT_COPY_PATHS: u64 = 103;
+const NIX_RES_PROGRESS: u64 = 105;
+
+#[derive(Deserialize, Debug)]
+struct NixLogLine {
+    action: String,
+    id: Option<u64>,
+    #[serde(rename = "type")]
+    kind: Option<u64>,
+    level: Option<u64>,
+    msg: Option<String>,
+    #[serde(default)]
+    fields: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq)]
+enum CopyEvent {
+    /// Sent whenever another path is done
+    Progress {
+        paths_done: u64,
+        paths_expected: u64,
+        bytes_done: u64,
+    },
+    Message(u64, String),
+}
+
+/// Follows the `internal-json` log of `nix copy`, accumulating how much was copied already
+#[derive(Default)]
+struct CopyProgress {
+    activities: HashMap<u64, u64>,
+    path_bytes: HashMap<u64, u64>,
+    paths_done: u64,
+    paths_expected: u64,
+}
+
+impl CopyProgress {
+    fn update(&mut self, line: &str) -> Option<CopyEvent> {
+        let line: NixLogLine = match line.strip_prefix("@nix ") {
+            Some(json) => serde_json::from_str(json).ok()?,
+            // Anything else was not printed by the logger, so pass it on as is
+            None => return Some(CopyEvent::Message(0, line.to_string())),
+        };
+
+        let field = |i: usize| line.fields.get(i).and_then(|x| x.as_u64()).unwrap_or(0);
+
+        match line.action.as_str() {
+            "msg" => Some(CopyEvent::Message(line.level.unwrap_or(0), line.msg?)),
+            "start" => {
+                self.activities.insert(line.id?, line.kind?);
+                None
+            }
+            "result" if line.kind == Some(NIX_RES_PROGRESS) => {
+                match self.activities.get(&line.id?) {
+                    Some(&NIX_ACT_COPY_PATH) => {
+                        self.path_bytes.insert(line.id?, field(0));
+                        None
+                    }
+                    Some(&NIX_ACT_COPY_PATHS) => {
+                        let (paths_done, paths_expected) = (field(0), field(1));
+                        if (paths_done, paths_expected) == (self.paths_done, self.paths_expected) {
+                            return None;
+                        }
+
+                        self.paths_done = paths_done;
+                        self.paths_expected = paths_expected;
+
+                        Some(CopyEvent::Progress {
+                            paths_done,
+                            paths_expected,
+                            bytes_done: self.path_bytes.values().sum(),
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_copy_progress() {
+    let mut progress = CopyProgress::default();
+
+    assert_eq!(
+        progress.update(r#"@nix {"action":"start","id":1,"level":3,"parent":0,"text":"copying 2 paths","type":103,"fields":[]}"#),
+        None
+    );
+    assert_eq!(
+        progress.update(r#"@nix {"action":"start","id":2,"level":3,"parent":1,"text":"copying path","type":100,"fields":["/nix/store/a"]}"#),
+        None
+    );
+    assert_eq!(
+        progress.update(r#"@nix {"action":"result","id":2,"type":105,"fields":[4096,8192,0,0]}"#),
+        None
+    );
+    assert_eq!(
+        progress.update(r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,1,0]}"#),
+        Some(CopyEvent::Progress {
+            paths_done: 1,
+            paths_expected: 2,
+            bytes_done: 4096
+        })
+    );
+    // Unchanged path counts are not reported again
+    assert_eq!(
+        progress.update(r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,1,0]}"#),
+        None
+    );
+    assert_eq!(
+        progress.update(r#"@nix {"action":"msg","level":0,"msg":"error: unable to connect"}"#),
+        Some(CopyEvent::Message(
+            0,
+            "error: unable to connect".to_string()
+        ))
+    );
+    assert_eq!(
+        progress.update("Warning: Permanently added 'host' to the list of known hosts."),
+        Some(CopyEvent::Message(
+            0,
+            "Warning: Permanently added 'host' to the list of known hosts.".to_string()
+        ))
+    );
+}
+
+/// Waits for `nix copy` started with `--log-format internal-json`, logging its progress on the given node
+async fn wait_copy_with_progress(
+    mut child: Child,
+    node_name: &str,
+) -> Result<ExitStatus, std::io::Error> {
+    if let Some(stderr) = child.stderr.take() {
+        let mut lines = BufReader::new(stderr).lines();
+        let mut progress = CopyProgress::default();
+
+        while let Some(line) = lines.next_line().await? {
+            match progress.update(&line) {
+                Some(CopyEvent::Progress {
+                    paths_done,
+                    paths_expected,
+                    bytes_done,
+                }) => info!(
+                    "[{}] Copied {}/{} paths ({} MiB)",
+                    node_name,
+                    paths_done,
+                    paths_expected,
+                    bytes_done / 1024 / 1024
+                ),
+                // Error level messages from Nix, and anything printed by ssh
+                Some(CopyEvent::Message(0, msg)) => warn!("[{}] {}", node_name, msg),
+                Some(CopyEvent::Message(level, msg)) if level <= 3 => {
+                    debug!("[{}] {}", node_name, msg)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    child.wait().await
+}
+
 pub struct PushProfileData<'a> {
     pub supports_flakes: bool,
     pub check_sigs: bool,
@@ -43,12 +225,121 @@ pub struct PushProfileData<'a> {
     pub extra_build_args: &'a [String],
 }
 
-pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+/// Joins SSH options for `NIX_SSHOPTS`, which Nix splits like a shell. Only options which need
+/// it are quoted, quoting all of them breaks with older versions of Nix, which split on whitespace.
+fn make_nix_sshopts(ssh_opts: &[String]) -> String {
+    ssh_opts
+        .iter()
+        .map(|x| {
+            match x.is_empty() || x.contains(|c: char| c.is_whitespace() || "'\"\\$`".contains(c)) {
+                true => crate::deploy::shell_escape(x),
+                false => x.to_string(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[test]
+fn test_make_nix_sshopts() {
+    let opts = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(make_nix_sshopts(&opts(&["-p", "2121"])), "-p 2121");
+    assert_eq!(
+        make_nix_sshopts(&opts(&["-o", "ProxyCommand=ssh gateway nc %h %p"])),
+        "-o 'ProxyCommand=ssh gateway nc %h %p'"
+    );
+}
+
+/// Arguments of `nix copy` for copying `closure` to the node at `ssh_addr`, with `copyOpts` after
+/// the flags deploy-rs sets itself
+fn make_copy_args(
+    settings: &crate::data::GenericSettings,
+    check_sigs: bool,
+    ssh_addr: &str,
+    closure: &str,
+) -> Vec<String> {
+    let mut args = vec!["copy".to_string()];
+
+    if settings.fast_connection != Some(true) {
+        args.push("--substitute-on-destination".to_string());
+    }
+
+    if !check_sigs {
+        args.push("--no-check-sigs".to_string());
+    }
+
+    if let Some(ref copy_opts) = settings.copy_opts {
+        args.extend(copy_opts.iter().cloned());
+    }
+
+    args.push("--to".to_string());
+    args.push(format!("ssh://{}", ssh_addr));
+    args.push(closure.to_string());
+
+    args
+}
+
+#[test]
+fn test_make_copy_args() {
+    let settings = crate::data::GenericSettings {
+        copy_opts: Some(vec![
+            "--option".to_string(),
+            "substituters".to_string(),
+            "http://cache.internal".to_string(),
+        ]),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        make_copy_args(
+            &settings,
+            false,
+            "admin@example.com",
+            "/nix/store/blah-system"
+        ),
+        vec![
+            "copy",
+            "--substitute-on-destination",
+            "--no-check-sigs",
+            "--option",
+            "substituters",
+            "http://cache.internal",
+            "--to",
+            "ssh://admin@example.com",
+            "/nix/store/blah-system"
+        ]
+    );
+    assert_eq!(
+        make_copy_args(
+            &crate::data::GenericSettings {
+                fast_connection: Some(true),
+                ..Default::default()
+            },
+            true,
+            "admin@example.com",
+            "/nix/store/blah-system"
+        ),
+        vec![
+            "copy",
+            "--to",
+            "ssh://admin@example.com",
+            "/nix/store/blah-system"
+        ]
+    );
+}
+
+async fn build_profile(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
     info!(
         "Building profile `{}` for node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
+    let path_attr = match data.deploy_data.profile.profile_settings.variant {
+        Some(ref variant) => format!("variants.\"{}\"", variant),
+        None => "path".to_string(),
+    };
+
     let mut build_command = if data.supports_flakes {
         Command::new("nix")
     } else {
@@ -57,13 +348,13 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
 
     if data.supports_flakes {
         build_command.arg("build").arg(format!(
-            "{}#deploy.nodes.\"{}\".profiles.\"{}\".path",
-            data.repo, data.deploy_data.node_name, data.deploy_data.profile_name
+            "{}#deploy.nodes.\"{}\".profiles.\"{}\".{}",
+            data.repo, data.deploy_data.node_name, data.deploy_data.profile_name, path_attr
         ))
     } else {
-        build_command.arg(&data.repo).arg("-A").arg(format!(
-            "deploy.nodes.\"{}\".profiles.\"{}\".path",
-            data.deploy_data.node_name, data.deploy_data.profile_name
+        build_command.arg(data.repo).arg("-A").arg(format!(
+            "deploy.nodes.\"{}\".profiles.\"{}\".{}",
+            data.deploy_data.node_name, data.deploy_data.profile_name, path_attr
         ))
     };
 
@@ -96,6 +387,19 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         a => return Err(PushProfileError::BuildExitError(a)),
     };
 
+    Ok(())
+}
+
+pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+    if data.deploy_data.profile.profile_settings.prebuilt {
+        info!(
+            "Using the given closure for profile `{}` of node `{}`, without building it",
+            data.deploy_data.profile_name, data.deploy_data.node_name
+        );
+    } else {
+        build_profile(&data).await?;
+    }
+
     if !Path::new(
         format!(
             "{}/deploy-rs-activate",
@@ -132,6 +436,7 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
             .arg("-k")
             .arg(local_key)
             .arg(&data.deploy_data.profile.profile_settings.path)
+            .stdout(data.deploy_data.cmd_overrides.log_format.child_stdout())
             .status()
             .await
             .map_err(PushProfileError::SignError)?;
@@ -142,50 +447,86 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         };
     }
 
+    if data.deploy_data.is_local() {
+        debug!(
+            "Node `{}` is local, so profile `{}` is already in its store",
+            data.deploy_data.node_name, data.deploy_data.profile_name
+        );
+
+        return Ok(());
+    }
+
     debug!(
         "Copying profile `{}` to node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
+    let mut copy_span = Span::start_in_profile(
+        "copy",
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+    );
+    copy_span.set_attribute("deploy.node", data.deploy_data.node_name);
+    copy_span.set_attribute("deploy.profile", data.deploy_data.profile_name);
+
+    let ssh_opts_str = make_nix_sshopts(&data.deploy_data.merged_settings.ssh_opts);
+
     let mut copy_command = Command::new("nix");
-    copy_command.arg("copy");
+    copy_command
+        .args(make_copy_args(
+            &data.deploy_data.merged_settings,
+            data.check_sigs,
+            &data.deploy_data.ssh_addr(data.deploy_defs),
+            &data.deploy_data.profile.profile_settings.path,
+        ))
+        .env("NIX_SSHOPTS", ssh_opts_str)
+        .envs(data.deploy_data.ssh_env())
+        .stdout(data.deploy_data.cmd_overrides.log_format.child_stdout());
 
-    if data.deploy_data.merged_settings.fast_connection != Some(true) {
-        copy_command.arg("--substitute-on-destination");
+    // Older Nix versions without flakes don't support `--log-format`
+    if data.supports_flakes {
+        copy_command
+            .arg("--log-format")
+            .arg("internal-json")
+            .arg("-v")
+            .stderr(Stdio::piped());
     }
 
-    if !data.check_sigs {
-        copy_command.arg("--no-check-sigs");
-    }
+    let copy_retries = data.deploy_data.merged_settings.copy_retries.unwrap_or(0);
 
-    let ssh_opts_str = data
-        .deploy_data
-        .merged_settings
-        .ssh_opts
-        // This should provide some extra safety, but it also breaks for some reason, oh well
-        // .iter()
-        // .map(|x| format!("'{}'", x))
-        // .collect::<Vec<String>>()
-        .join(" ");
-
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
-    };
+    // `nix copy` only transfers what is missing on the node, so retrying mostly resumes the previous attempt
+    let mut attempt = 0;
+    loop {
+        let mut copy_child = copy_command.spawn().map_err(PushProfileError::CopyError)?;
 
-    let copy_exit_status = copy_command
-        .arg("--to")
-        .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
-        .arg(&data.deploy_data.profile.profile_settings.path)
-        .env("NIX_SSHOPTS", ssh_opts_str)
-        .status()
-        .await
+        let copy_exit_status = if data.supports_flakes {
+            wait_copy_with_progress(copy_child, data.deploy_data.node_name).await
+        } else {
+            copy_child.wait().await
+        }
         .map_err(PushProfileError::CopyError)?;
 
-    match copy_exit_status.code() {
-        Some(0) => (),
-        a => return Err(PushProfileError::CopyExitError(a)),
-    };
+        match copy_exit_status.code() {
+            Some(0) => break,
+            a if attempt >= copy_retries => return Err(PushProfileError::CopyExitError(a)),
+            a => {
+                let delay = retry_delay(attempt);
+                warn!(
+                    "Copying profile `{}` to node `{}` resulted in a bad exit code: {:?}, retrying in {}s ({}/{})",
+                    data.deploy_data.profile_name,
+                    data.deploy_data.node_name,
+                    a,
+                    delay.as_secs(),
+                    attempt + 1,
+                    copy_retries
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+
+    copy_span.end(SpanStatus::Ok);
 
     Ok(())
 }
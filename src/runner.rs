@@ -0,0 +1,495 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Running commands on nodes, behind a trait so that deploying can be tested without SSH.
+
+use futures_util::future::{BoxFuture, FutureExt};
+use log::info;
+use std::collections::VecDeque;
+use std::io;
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncBufReadExt;
+use tokio::process::Command;
+
+/// A value which is only ever given to a command on its standard input, like a password. It is
+/// left out of `Debug`, so that it can't end up in the log.
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(<hidden>)")
+    }
+}
+
+/// How a command is run, besides its arguments
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunOptions {
+    /// Closes standard input, instead of inheriting it
+    pub null_stdin: bool,
+    /// Kills the command once it is dropped, like when waiting for it is abandoned
+    pub kill_on_drop: bool,
+    /// Reads standard input from this file, instead of inheriting it
+    pub stdin: Option<std::path::PathBuf>,
+    /// Written to standard input as its first line, followed by the contents of `stdin` if that
+    /// is set too
+    pub stdin_secret: Option<Secret>,
+    /// Forwards each line the command outputs to the log with this prefix, instead of inheriting
+    /// standard output and error
+    pub stream_prefix: Option<String>,
+    /// Variables set in the environment of the command, on top of the inherited ones
+    pub env: Vec<(String, String)>,
+    /// Keeps the last lines of standard error for [`RunningCommand::stderr`], which are still
+    /// passed on as well
+    pub capture_stderr: bool,
+    /// Writes standard output to standard error, instead of inheriting it
+    pub stdout_to_stderr: bool,
+}
+
+/// A command which has been started by a [`CommandRunner`]
+pub trait RunningCommand: Send {
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>>;
+    fn kill(&mut self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// The end of what the command wrote to standard error so far, with `capture_stderr`
+    fn stderr(&self) -> String {
+        String::new()
+    }
+}
+
+/// Runs the command lines made by `deploy::node_argv`, which run something on a node
+pub trait CommandRunner: Sync {
+    fn spawn(&self, argv: &[String], options: RunOptions) -> io::Result<Box<dyn RunningCommand>>;
+
+    /// Runs the command with its standard output captured
+    fn output<'a>(
+        &'a self,
+        argv: &'a [String],
+        options: RunOptions,
+    ) -> BoxFuture<'a, io::Result<Output>>;
+
+    fn status<'a>(
+        &'a self,
+        argv: &'a [String],
+        options: RunOptions,
+    ) -> BoxFuture<'a, io::Result<ExitStatus>> {
+        async move { self.spawn(argv, options)?.wait().await }.boxed()
+    }
+}
+
+/// Passes each line `reader` outputs to `emit` as it arrives, without its line ending
+async fn forward_lines(reader: impl tokio::io::AsyncRead + Unpin, mut emit: impl FnMut(&str)) {
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => emit(String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r'])),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_forward_lines() {
+    let mut lines = Vec::new();
+    forward_lines(&b"starting foo.service\r\n\nbar \xff\ndone"[..], |x| {
+        lines.push(x.to_string())
+    })
+    .await;
+
+    assert_eq!(
+        lines,
+        vec!["starting foo.service", "", "bar \u{fffd}", "done"]
+    );
+}
+
+/// How many lines of standard error `capture_stderr` keeps
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Adds `line` to the end of `tail`, dropping the oldest one if it is full
+fn push_tail(tail: &mut VecDeque<String>, line: &str) {
+    if tail.len() == STDERR_TAIL_LINES {
+        tail.pop_front();
+    }
+
+    tail.push_back(line.to_string());
+}
+
+#[test]
+fn test_push_tail() {
+    let mut tail = VecDeque::new();
+    for i in 0..25 {
+        push_tail(&mut tail, &i.to_string());
+    }
+
+    assert_eq!(tail.len(), STDERR_TAIL_LINES);
+    assert_eq!(tail.front().map(|x| x.as_str()), Some("5"));
+    assert_eq!(tail.back().map(|x| x.as_str()), Some("24"));
+}
+
+/// Runs commands as processes on this machine, which is `ssh` for nodes that aren't local
+pub struct SshRunner;
+
+struct SshCommand {
+    child: tokio::process::Child,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    // Forwards standard error while capturing it, until the command closes it
+    stderr_forward: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl RunningCommand for SshCommand {
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        async move {
+            let status = self.child.wait().await?;
+
+            // Something the command left running may keep standard error open
+            if let Some(stderr_forward) = self.stderr_forward.take() {
+                tokio::time::timeout(std::time::Duration::from_secs(1), stderr_forward)
+                    .await
+                    .ok();
+            }
+
+            Ok(status)
+        }
+        .boxed()
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.child.kill().boxed()
+    }
+
+    fn stderr(&self) -> String {
+        let tail = self.stderr_tail.lock().unwrap();
+        tail.iter().cloned().collect::<Vec<_>>().join("\n")
+    }
+}
+
+fn make_command(argv: &[String], options: &RunOptions) -> io::Result<Command> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command to run"))?;
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .envs(options.env.iter().map(|(k, v)| (k, v)))
+        .kill_on_drop(options.kill_on_drop);
+
+    if options.stdin_secret.is_some() {
+        command.stdin(Stdio::piped());
+    } else if let Some(stdin) = &options.stdin {
+        command.stdin(std::fs::File::open(stdin)?);
+    } else if options.null_stdin {
+        command.stdin(Stdio::null());
+    }
+
+    Ok(command)
+}
+
+/// Starts `command`, writing `stdin_secret` of `options` to it in the background
+fn spawn_child(command: &mut Command, options: &RunOptions) -> io::Result<tokio::process::Child> {
+    let secret = match &options.stdin_secret {
+        Some(secret) => format!("{}\n", secret.expose()),
+        None => return command.spawn(),
+    };
+    // Opened before starting, so that a missing file fails like without a secret
+    let file = match &options.stdin {
+        Some(stdin) => Some(tokio::fs::File::from_std(std::fs::File::open(stdin)?)),
+        None => None,
+    };
+
+    let mut child = command.spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            // The command may exit without reading all of it, which its exit status tells about
+            if stdin.write_all(secret.as_bytes()).await.is_ok() {
+                if let Some(mut file) = file {
+                    tokio::io::copy(&mut file, &mut stdin).await.ok();
+                }
+            }
+        });
+    }
+
+    Ok(child)
+}
+
+#[tokio::test]
+async fn test_spawn_child_writes_secret() {
+    let options = RunOptions {
+        stdin_secret: Some(Secret::new("hunter2".to_string())),
+        ..Default::default()
+    };
+    assert!(!format!("{:?}", options).contains("hunter2"));
+
+    let argv = ["sh".to_string(), "-c".to_string(), "cat".to_string()];
+    let output = SshRunner.output(&argv, options.clone()).await.unwrap();
+    assert_eq!(output.stdout, b"hunter2\n");
+
+    // Like uploading a file with `sudo`, the file follows the secret
+    let file = std::env::temp_dir().join(format!(
+        "deploy-rs-test-stdin-secret-{}",
+        std::process::id()
+    ));
+    std::fs::write(&file, "contents").unwrap();
+    let output = SshRunner
+        .output(
+            &argv,
+            RunOptions {
+                stdin: Some(file.clone()),
+                ..options
+            },
+        )
+        .await
+        .unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(output.stdout, b"hunter2\ncontents");
+}
+
+impl CommandRunner for SshRunner {
+    fn spawn(&self, argv: &[String], options: RunOptions) -> io::Result<Box<dyn RunningCommand>> {
+        let mut command = make_command(argv, &options)?;
+
+        if options.stream_prefix.is_some() {
+            command.stdout(Stdio::piped());
+        } else if options.stdout_to_stderr {
+            command.stdout(io::stderr());
+        }
+        if options.stream_prefix.is_some() || options.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+
+        let mut child = spawn_child(&mut command, &options)?;
+
+        if let Some(prefix) = &options.stream_prefix {
+            if let Some(stdout) = child.stdout.take() {
+                let prefix = prefix.clone();
+                tokio::spawn(forward_lines(stdout, move |x| info!("{} {}", prefix, x)));
+            }
+        }
+
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let mut stderr_forward = None;
+
+        if let Some(stderr) = child.stderr.take() {
+            let prefix = options.stream_prefix;
+            let capture_stderr = options.capture_stderr;
+            let stderr_tail = stderr_tail.clone();
+
+            stderr_forward = Some(tokio::spawn(forward_lines(stderr, move |x| {
+                match &prefix {
+                    Some(prefix) => info!("{} {}", prefix, x),
+                    None => eprintln!("{}", x),
+                }
+
+                if capture_stderr {
+                    push_tail(&mut stderr_tail.lock().unwrap(), x);
+                }
+            })));
+        }
+
+        Ok(Box::new(SshCommand {
+            child,
+            stderr_tail,
+            stderr_forward,
+        }))
+    }
+
+    fn output<'a>(
+        &'a self,
+        argv: &'a [String],
+        options: RunOptions,
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        async move {
+            let mut command = make_command(argv, &options)?;
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+            // Like `Command::output`, which doesn't inherit standard input either
+            if options.stdin_secret.is_none() && options.stdin.is_none() {
+                command.stdin(Stdio::null());
+            }
+
+            spawn_child(&mut command, &options)?
+                .wait_with_output()
+                .await
+        }
+        .boxed()
+    }
+}
+
+/// What a [`MockRunner`] does for a command
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MockResponse {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// How long the command takes, `None` runs until it is killed
+    pub duration: Option<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl MockResponse {
+    pub fn exit(code: i32) -> Self {
+        MockResponse {
+            code,
+            duration: Some(std::time::Duration::from_millis(0)),
+            ..Default::default()
+        }
+    }
+}
+
+/// Answers commands by the first of `responses` whose pattern is part of the command run on the
+/// node (the last argument), recording every command it was given. Commands which match none
+/// succeed without output.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockRunner {
+    pub responses: Vec<(String, MockResponse)>,
+    pub calls: std::sync::Mutex<Vec<String>>,
+    /// The whole command lines of `calls`, with how they were run
+    pub invocations: std::sync::Mutex<Vec<(Vec<String>, RunOptions)>>,
+    running: Arc<std::sync::atomic::AtomicUsize>,
+    max_running: std::sync::atomic::AtomicUsize,
+}
+
+/// Counts as running in the [`MockRunner`] which started it until it is dropped
+#[cfg(test)]
+struct MockCommand(MockResponse, Arc<std::sync::atomic::AtomicUsize>);
+
+#[cfg(test)]
+impl Drop for MockCommand {
+    fn drop(&mut self) {
+        self.1.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl RunningCommand for MockCommand {
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        use std::os::unix::process::ExitStatusExt;
+
+        async move {
+            match self.0.duration {
+                Some(x) => tokio::time::sleep(x).await,
+                None => futures_util::future::pending().await,
+            }
+
+            Ok(ExitStatus::from_raw(self.0.code << 8))
+        }
+        .boxed()
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.0.duration = Some(std::time::Duration::from_millis(0));
+        self.0.code = 137;
+
+        async { Ok(()) }.boxed()
+    }
+
+    fn stderr(&self) -> String {
+        self.0.stderr.clone()
+    }
+}
+
+#[cfg(test)]
+impl MockRunner {
+    pub fn new(responses: Vec<(&str, MockResponse)>) -> Self {
+        MockRunner {
+            responses: responses
+                .into_iter()
+                .map(|(pattern, response)| (pattern.to_string(), response))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn respond(&self, argv: &[String], options: RunOptions) -> MockResponse {
+        let command = argv.last().cloned().unwrap_or_default();
+        self.calls.lock().unwrap().push(command.clone());
+        self.invocations
+            .lock()
+            .unwrap()
+            .push((argv.to_vec(), options));
+
+        self.responses
+            .iter()
+            .find(|(pattern, _)| command.contains(pattern.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| MockResponse::exit(0))
+    }
+
+    fn start(&self, argv: &[String], options: RunOptions) -> MockCommand {
+        use std::sync::atomic::Ordering;
+
+        let response = self.respond(argv, options);
+
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_running.fetch_max(running, Ordering::SeqCst);
+
+        MockCommand(response, self.running.clone())
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn invocations(&self) -> Vec<(Vec<String>, RunOptions)> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// How many commands are running now
+    pub fn running(&self) -> usize {
+        self.running.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// The most commands which were running at the same time
+    pub fn max_running(&self) -> usize {
+        self.max_running.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for MockRunner {
+    fn spawn(&self, argv: &[String], options: RunOptions) -> io::Result<Box<dyn RunningCommand>> {
+        Ok(Box::new(self.start(argv, options)))
+    }
+
+    fn output<'a>(
+        &'a self,
+        argv: &'a [String],
+        options: RunOptions,
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        let mut command = self.start(argv, options);
+
+        async move {
+            let stdout = command.0.stdout.clone().into_bytes();
+            let stderr = command.0.stderr.clone().into_bytes();
+            let status = command.wait().await?;
+
+            Ok(Output {
+                status,
+                stdout,
+                stderr,
+            })
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,422 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Minimal OpenTelemetry tracing, exported as OTLP/HTTP JSON through `curl`.
+//!
+//! Like the logger, the tracer is global, when [`init`] was not called with an endpoint
+//! every span is a no-op.
+
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+struct Tracer {
+    endpoint: String,
+    trace_id: String,
+    root_span_id: String,
+    root_start: u64,
+    spans: Vec<OtlpSpan>,
+    /// The id and start of the span of deploying each profile, by node and profile name
+    profile_spans: HashMap<(String, String), (String, u64)>,
+}
+
+impl Tracer {
+    /// The id and start of the span of deploying `profile_name` of `node_name`, which begins with
+    /// whatever span of it comes first, as copying a profile comes before deploying it
+    fn profile_span(&mut self, node_name: &str, profile_name: &str) -> (String, u64) {
+        self.profile_spans
+            .entry((node_name.to_string(), profile_name.to_string()))
+            .or_insert_with(|| (make_span_id(), now_nanos()))
+            .clone()
+    }
+}
+
+static TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now_nanos());
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn make_trace_id() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+fn make_span_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+/// Starts tracing a rollout, spans will be sent to `endpoint` by [`finish`]
+pub fn init(endpoint: Option<&str>) {
+    if let Some(endpoint) = endpoint {
+        *TRACER.lock().unwrap() = Some(Tracer {
+            endpoint: endpoint.to_string(),
+            trace_id: make_trace_id(),
+            root_span_id: make_span_id(),
+            root_start: now_nanos(),
+            spans: Vec::new(),
+            profile_spans: HashMap::new(),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanStatus {
+    Ok,
+    Error,
+}
+
+struct SpanData {
+    span_id: String,
+    parent_span_id: String,
+    name: String,
+    start: u64,
+    attributes: Vec<(String, String)>,
+}
+
+/// A span in the current rollout, which is recorded when ended (or dropped, which counts as an error)
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+impl Span {
+    /// Starts a span, as a child of `parent` or of the rollout itself
+    pub fn start(name: &str, parent: Option<&Span>) -> Span {
+        let tracer = TRACER.lock().unwrap();
+
+        let tracer = match &*tracer {
+            Some(x) => x,
+            None => return Span { data: None },
+        };
+
+        let parent_span_id = match parent.and_then(|x| x.data.as_ref()) {
+            Some(x) => x.span_id.clone(),
+            None => tracer.root_span_id.clone(),
+        };
+
+        Span {
+            data: Some(SpanData {
+                span_id: make_span_id(),
+                parent_span_id,
+                name: name.to_string(),
+                start: now_nanos(),
+                attributes: Vec::new(),
+            }),
+        }
+    }
+
+    /// Starts the span of deploying a profile, as a child of the rollout, which the spans of
+    /// [`Span::start_in_profile`] belong to
+    pub fn start_profile(node_name: &str, profile_name: &str) -> Span {
+        let mut tracer = TRACER.lock().unwrap();
+
+        let tracer = match &mut *tracer {
+            Some(x) => x,
+            None => return Span { data: None },
+        };
+
+        let (span_id, start) = tracer.profile_span(node_name, profile_name);
+
+        Span {
+            data: Some(SpanData {
+                span_id,
+                parent_span_id: tracer.root_span_id.clone(),
+                name: "deploy".to_string(),
+                start,
+                attributes: vec![
+                    ("deploy.node".to_string(), node_name.to_string()),
+                    ("deploy.profile".to_string(), profile_name.to_string()),
+                ],
+            }),
+        }
+    }
+
+    /// Starts a span as a child of the span of deploying a profile, even if that has not been
+    /// started yet
+    pub fn start_in_profile(name: &str, node_name: &str, profile_name: &str) -> Span {
+        let mut tracer = TRACER.lock().unwrap();
+
+        let tracer = match &mut *tracer {
+            Some(x) => x,
+            None => return Span { data: None },
+        };
+
+        Span {
+            data: Some(SpanData {
+                span_id: make_span_id(),
+                parent_span_id: tracer.profile_span(node_name, profile_name).0,
+                name: name.to_string(),
+                start: now_nanos(),
+                attributes: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: &str) {
+        if let Some(data) = &mut self.data {
+            match data.attributes.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value.to_string(),
+                None => data.attributes.push((key.to_string(), value.to_string())),
+            }
+        }
+    }
+
+    pub fn end(mut self, status: SpanStatus) {
+        self.record(status);
+    }
+
+    fn record(&mut self, status: SpanStatus) {
+        if let Some(data) = self.data.take() {
+            if let Some(tracer) = &mut *TRACER.lock().unwrap() {
+                let span = make_otlp_span(&tracer.trace_id, data, now_nanos(), status);
+                tracer.spans.push(span);
+            }
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.record(SpanStatus::Error);
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct OtlpValue {
+    string_value: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct OtlpAttribute {
+    key: String,
+    value: OtlpValue,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct OtlpStatus {
+    code: u8,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<OtlpAttribute>,
+    status: OtlpStatus,
+}
+
+fn make_otlp_span(trace_id: &str, data: SpanData, end: u64, status: SpanStatus) -> OtlpSpan {
+    OtlpSpan {
+        trace_id: trace_id.to_string(),
+        span_id: data.span_id,
+        parent_span_id: data.parent_span_id,
+        name: data.name,
+        // SPAN_KIND_INTERNAL
+        kind: 1,
+        start_time_unix_nano: data.start.to_string(),
+        end_time_unix_nano: end.to_string(),
+        attributes: data
+            .attributes
+            .into_iter()
+            .map(|(key, value)| OtlpAttribute {
+                key,
+                value: OtlpValue {
+                    string_value: value,
+                },
+            })
+            .collect(),
+        status: OtlpStatus {
+            code: match status {
+                SpanStatus::Ok => 1,
+                SpanStatus::Error => 2,
+            },
+        },
+    }
+}
+
+fn make_export_request(spans: &[OtlpSpan]) -> serde_json::Value {
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "deploy-rs" } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "deploy-rs" },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+fn make_traces_url(endpoint: &str) -> String {
+    match endpoint.trim_end_matches('/') {
+        x if x.ends_with("/v1/traces") => x.to_string(),
+        x => format!("{}/v1/traces", x),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Failed to serialize spans: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to run curl to export spans: {0}")]
+    Curl(std::io::Error),
+    #[error("Exporting spans with curl resulted in a bad exit code: {0:?}")]
+    CurlExit(Option<i32>),
+}
+
+/// Ends the rollout span with `outcome`, and sends every recorded span to the endpoint
+pub async fn finish(outcome: &str, status: SpanStatus) -> Result<(), ExportError> {
+    let (url, body) = {
+        let mut tracer = TRACER.lock().unwrap();
+
+        let mut tracer = match tracer.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let root = SpanData {
+            span_id: tracer.root_span_id.clone(),
+            parent_span_id: String::new(),
+            name: "rollout".to_string(),
+            start: tracer.root_start,
+            attributes: vec![("deploy.outcome".to_string(), outcome.to_string())],
+        };
+        let root = make_otlp_span(&tracer.trace_id, root, now_nanos(), status);
+        tracer.spans.push(root);
+
+        (
+            make_traces_url(&tracer.endpoint),
+            serde_json::to_vec(&make_export_request(&tracer.spans))?,
+        )
+    };
+
+    debug!("Exporting spans to {}", url);
+
+    let mut curl = Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("-X")
+        .arg("POST")
+        .arg("-H")
+        .arg("Content-Type: application/json")
+        .arg("--data-binary")
+        .arg("@-")
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(ExportError::Curl)?;
+
+    if let Some(mut stdin) = curl.stdin.take() {
+        if let Err(err) = stdin.write_all(&body).await {
+            warn!("Failed to write spans to curl: {}", err);
+        }
+    }
+
+    match curl.wait().await.map_err(ExportError::Curl)?.code() {
+        Some(0) => (),
+        a => return Err(ExportError::CurlExit(a)),
+    };
+
+    Ok(())
+}
+
+#[test]
+fn test_disabled_span_is_noop() {
+    let mut span = Span::start("deploy", None);
+    span.set_attribute("deploy.node", "example");
+
+    assert!(span.data.is_none());
+}
+
+#[test]
+fn test_profile_span() {
+    let mut tracer = Tracer {
+        endpoint: "http://localhost:4318".to_string(),
+        trace_id: make_trace_id(),
+        root_span_id: make_span_id(),
+        root_start: now_nanos(),
+        spans: Vec::new(),
+        profile_spans: HashMap::new(),
+    };
+
+    let (copy_parent, start) = tracer.profile_span("example", "system");
+    assert_eq!(
+        tracer.profile_span("example", "system"),
+        (copy_parent.clone(), start)
+    );
+    assert_ne!(tracer.profile_span("example", "app").0, copy_parent);
+}
+
+#[test]
+fn test_make_traces_url() {
+    assert_eq!(
+        make_traces_url("http://localhost:4318"),
+        "http://localhost:4318/v1/traces"
+    );
+    assert_eq!(
+        make_traces_url("http://localhost:4318/v1/traces/"),
+        "http://localhost:4318/v1/traces"
+    );
+}
+
+#[test]
+fn test_export_request() {
+    let span = make_otlp_span(
+        "0123456789abcdef0123456789abcdef",
+        SpanData {
+            span_id: "0123456789abcdef".to_string(),
+            parent_span_id: "fedcba9876543210".to_string(),
+            name: "activate".to_string(),
+            start: 1,
+            attributes: vec![("deploy.node".to_string(), "example".to_string())],
+        },
+        2,
+        SpanStatus::Error,
+    );
+
+    assert_eq!(
+        make_export_request(&[span])["resourceSpans"][0]["scopeSpans"][0]["spans"][0],
+        serde_json::json!({
+            "traceId": "0123456789abcdef0123456789abcdef",
+            "spanId": "0123456789abcdef",
+            "parentSpanId": "fedcba9876543210",
+            "name": "activate",
+            "kind": 1,
+            "startTimeUnixNano": "1",
+            "endTimeUnixNano": "2",
+            "attributes": [{ "key": "deploy.node", "value": { "stringValue": "example" } }],
+            "status": { "code": 2 },
+        })
+    );
+}